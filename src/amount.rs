@@ -0,0 +1,136 @@
+use anyhow::{anyhow, Result};
+use ethers::core::types::U256;
+use std::cmp::Ordering;
+use std::fmt;
+
+/// An exact amount of a token, stored in the token's base units (e.g. wei) along with its decimals.
+/// Amounts typed by users are parsed into base units, and balances are formatted back without going through floats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenAmount {
+    pub raw: U256,
+    pub decimals: u8,
+}
+
+impl TokenAmount {
+    pub fn new(raw: U256, decimals: u8) -> Self {
+        Self { raw, decimals }
+    }
+
+    pub fn zero(decimals: u8) -> Self {
+        Self::new(U256::zero(), decimals)
+    }
+
+    /// Builds an amount of whole tokens, e.g. 100 TEST.
+    pub fn from_whole(tokens: u64, decimals: u8) -> Self {
+        Self::new(U256::from(tokens) * U256::exp10(decimals as usize), decimals)
+    }
+
+    /// Parses a decimal string such as "0.5", "12" or "1,000.25" into base units.
+    /// Amounts with more fractional digits than the token supports are rejected rather than rounded.
+    pub fn parse(amount: &str, decimals: u8) -> Result<Self> {
+        let amount = amount.trim().trim_start_matches('$').replace(',', "");
+        let (whole, fraction) = match amount.split_once('.') {
+            Some((whole, fraction)) => (whole, fraction),
+            None => (amount.as_str(), ""),
+        };
+        if whole.is_empty() && fraction.is_empty() {
+            return Err(anyhow!("Amount is empty"));
+        }
+        if !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(anyhow!("Amount {} is not a decimal number", amount));
+        }
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.len() > decimals as usize {
+            return Err(anyhow!(
+                "Amount {} has more than {} decimal places",
+                amount,
+                decimals
+            ));
+        }
+
+        let unit = U256::exp10(decimals as usize);
+        let whole_raw = if whole.is_empty() {
+            U256::zero()
+        } else {
+            U256::from_dec_str(whole)?
+        };
+        let fraction_raw = if fraction.is_empty() {
+            U256::zero()
+        } else {
+            U256::from_dec_str(fraction)? * U256::exp10(decimals as usize - fraction.len())
+        };
+        let raw = whole_raw
+            .checked_mul(unit)
+            .and_then(|raw| raw.checked_add(fraction_raw))
+            .ok_or(anyhow!("Amount {} is too large", amount))?;
+        Ok(Self::new(raw, decimals))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.raw.is_zero()
+    }
+
+    pub fn checked_sub(&self, other: &TokenAmount) -> Option<TokenAmount> {
+        if self.decimals != other.decimals {
+            return None;
+        }
+        self.raw
+            .checked_sub(other.raw)
+            .map(|raw| Self::new(raw, self.decimals))
+    }
+}
+
+impl PartialOrd for TokenAmount {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.decimals == other.decimals {
+            Some(self.raw.cmp(&other.raw))
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for TokenAmount {
+    /// Formats the amount in whole tokens, without trailing zeros, e.g. "0.5" or "100".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = U256::exp10(self.decimals as usize);
+        let whole = self.raw / unit;
+        let fraction = self.raw % unit;
+        if fraction.is_zero() {
+            return write!(f, "{}", whole);
+        }
+        let fraction_str = format!("{:0>width$}", fraction.to_string(), width = self.decimals as usize);
+        write!(f, "{}.{}", whole, fraction_str.trim_end_matches('0'))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_token_amount() {
+        assert_eq!(TokenAmount::parse("0.5", 18).unwrap().raw, U256::exp10(17) * 5);
+        assert_eq!(TokenAmount::parse("12", 6).unwrap().raw, U256::from(12_000_000u64));
+        assert_eq!(TokenAmount::parse("1,000.25", 2).unwrap().raw, U256::from(100_025u64));
+        assert_eq!(TokenAmount::parse(".1", 1).unwrap().raw, U256::from(1u64));
+        assert_eq!(TokenAmount::parse("1.50", 1).unwrap().raw, U256::from(15u64));
+        assert!(TokenAmount::parse("0.0000001", 6).is_err());
+        assert!(TokenAmount::parse("-1", 18).is_err());
+        assert!(TokenAmount::parse("1e18", 18).is_err());
+        assert!(TokenAmount::parse("", 18).is_err());
+    }
+
+    #[test]
+    fn test_format_and_compare_token_amount() {
+        let balance = TokenAmount::new(U256::from(1_500_000u64), 6);
+        assert_eq!(balance.to_string(), "1.5");
+        assert_eq!(TokenAmount::from_whole(100, 18).to_string(), "100");
+        assert_eq!(TokenAmount::new(U256::from(1u64), 18).to_string(), "0.000000000000000001");
+
+        let amount = TokenAmount::parse("0.5", 6).unwrap();
+        assert!(balance >= amount);
+        assert_eq!(balance.checked_sub(&amount).unwrap().to_string(), "1");
+        assert_eq!(amount.checked_sub(&balance), None);
+    }
+}
@@ -3,16 +3,18 @@
 // use ethers_signers::{LocalWallet, Signer};
 
 use dotenv::dotenv;
-use ethers::abi::Abi;
+use ethers::abi::{Abi, Tokenize};
 use ethers::utils::id;
 use ethers::prelude::*;
-use anyhow::{Error};
+use anyhow::{anyhow, Error};
 use ethers::core::types::{Address, U256, H160, H256};
-use ethers::providers::{Http, Middleware, Provider};
+use ethers::providers::{Http, Middleware, Provider, RpcError};
+use ethers::types::transaction::eip2718::TypedTransaction;
 use ethers::signers::{LocalWallet, Signer};
 use hex::encode;
-use crate::strings::{reply_with_etherscan, recipient_intro_body, recipient_intro_subject};
-use crate::config::{INCOMING_EML_PATH, ETHERSCAN_KEY, LOGIN_ID_KEY, LOGIN_PASSWORD_KEY, SMTP_DOMAIN_NAME_KEY};
+use crate::strings::{dropped_reply, rejected_reply, reply_with_explorer, reverted_reply};
+use crate::chain_config::{ChainConfig, ChainRegistry};
+use crate::config::{TOKEN_NAMES_KEY, TOKEN_REFRESH_INTERVAL_KEY, TX_CONFIRMATIONS_KEY, ETHERSCAN_KEY, LOGIN_ID_KEY, LOGIN_PASSWORD_KEY, SMTP_DOMAIN_NAME_KEY, ZK_EMAIL_PATH_KEY};
 use crate::smtp_client::EmailSenderClient;
 // use hex_literal::hex;
 use k256::ecdsa::SigningKey;
@@ -21,107 +23,62 @@ use std::convert::TryFrom;
 use std::env;
 use std::fs;
 use std::str::{self, FromStr};
-use crate::parse_email::{extract_from, extract_subject, parse_subject_for_send};
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use crate::parse_email::{parse_subject_for_migrate, split_chain_suffix, MigrateDestination, ParsedEmail};
+use crate::coordinator::{calculate_decimal_salt, calculate_hash, CommandValidation};
+use crate::db::{Job, JobState, Simulation, Store, TxOutcome, TxStatus};
+use crate::revert::RevertReason;
+use crate::submitter::{Submitters, TxSubmitter};
+use crate::prover::CircuitFiles;
+use crate::proof_artifacts::{ProofArtifacts, ProofFileError};
+use crate::circuit_input::{CircuitParams, PUBLIC_SIGNALS};
+use crate::verifier::ProofVerifier;
+use crate::amount::TokenAmount;
 // use std::error::Error;
 // use rand::thread_rng;
 // use std::borrow::Borrow;
 // use rustc_hex::{FromHex, ToHex};
 // use std::sync::Arc;
 
+/// The names looked up in the TokenRegistry contract at startup, unless TOKEN_NAMES is set.
+/// Other tokens registered on chain are picked up the first time they are used.
+pub const DEFAULT_TOKEN_NAMES: [&str; 4] = [NATIVE_TOKEN, "DAI", "USDC", "TEST"];
+/// The chain's native asset, which has no ERC20 contract: its balance is read from the account itself.
+pub const NATIVE_TOKEN: &str = "ETH";
+pub const NATIVE_DECIMALS: u8 = 18;
+/// How often registered tokens are re-read from chain, unless TOKEN_REFRESH_INTERVAL_SECS is set
+pub const DEFAULT_TOKEN_REFRESH_SECS: u64 = 600;
+/// How many blocks to wait for on top of a transaction before replying, unless TX_CONFIRMATIONS is set
+pub const DEFAULT_TX_CONFIRMATIONS: usize = 1;
+
 pub type SignerType = SignerMiddleware<Provider<Http>, Wallet<SigningKey>>;
 pub type ClientType = NonceManagerMiddleware<SignerMiddleware<Provider<Http>, Wallet<SigningKey>>>;
-#[derive(Debug, Clone)]
-pub struct CircomCalldata {
-    pi_a: [U256; 2],
-    pi_b: [[U256; 2]; 2],
-    pi_c: [U256; 2],
-    signals: [U256; 27],
+/// The event the wallet contract emits for every transfer between email wallets.
+#[derive(Debug, Clone, PartialEq, EthEvent, serde::Serialize, serde::Deserialize)]
+#[ethevent(name = "TransferInfo", abi = "TransferInfo(uint256,uint256,uint256,string)")]
+pub struct TransferInfo {
+    #[ethevent(indexed)]
+    pub from_salt: U256,
+    #[ethevent(indexed)]
+    pub to_salt: U256,
+    pub amount: U256,
+    pub currency: String,
 }
 
-// Define a new function that takes optional arguments and provides default values
-pub fn get_calldata(dir: Option<&str>, nonce: Option<&str>) -> Result<CircomCalldata, Error> {
-    // Provide default values if arguments are not specified
-    let dir = dir.unwrap_or("");
-    let nonce = nonce.unwrap_or("");
-
-    // Call the main function with the specified or default values
-    parse_files_into_calldata(dir, nonce)
+#[derive(Debug, Clone)]
+pub struct CircomCalldata {
+    pub pi_a: [U256; 2],
+    /// G2 coordinates in the [c1, c0] order the verifier contract takes
+    pub pi_b: [[U256; 2]; 2],
+    pub pi_c: [U256; 2],
+    pub signals: [U256; PUBLIC_SIGNALS],
 }
 
-// #[tokio::main]
-// async
-fn parse_files_into_calldata(
-    dir: &str,
-    nonce: &str,
-) -> Result<CircomCalldata, Error> {
-    let proof_dir = dir.to_owned() + "rapidsnark_proof_" + nonce + ".json";
-    // If the proof dir doesn't exist, probably the proof wasn't generated properly. Send an email to the user.
-    if !std::path::Path::new(&proof_dir).exists() {
-        let failure_subject = "Wallet send validation failed";
-        let failure_body = format!("The proof file was unable to generate -- we are likely mid-migration. Check back in tomorrow to try to send again!");
-        let sender = EmailSenderClient::new(env::var(LOGIN_ID_KEY).unwrap().as_str(), env::var(LOGIN_PASSWORD_KEY).unwrap().as_str(), Some(env::var(SMTP_DOMAIN_NAME_KEY).unwrap().as_str()));
-        let eml = fs::read_to_string(format!("{}/wallet_{}.eml", dir.replace("../proofs/", ""), nonce)).unwrap();
-        let sender_email = extract_from(&eml).unwrap_or("".to_string());
-        match sender.send_new_email(failure_subject, &failure_body, &sender_email) {
-            Ok(_) => (),
-            Err(e) => eprintln!("Failed to send email: {}", e),
-        }
-        return Err(Error::new(std::io::Error::new(std::io::ErrorKind::NotFound, "File not found")));        
-    }
-    let proof_json: Value = serde_json::from_str(&fs::read_to_string(proof_dir).unwrap()).unwrap();
-    let public_json: Value = serde_json::from_str(
-        &fs::read_to_string(dir.to_owned() + "rapidsnark_public_" + nonce + ".json").unwrap(),
-    )
-    .unwrap();
-
-    let pi_a: [U256; 2] = [
-        U256::from_dec_str(proof_json["pi_a"][0].as_str().unwrap()).unwrap(),
-        U256::from_dec_str(proof_json["pi_a"][1].as_str().unwrap()).unwrap(),
-    ];
-
-    let pi_b_raw: [[U256; 2]; 2] = [
-        [
-            U256::from_dec_str(proof_json["pi_b"][0][0].as_str().unwrap()).unwrap(),
-            U256::from_dec_str(proof_json["pi_b"][0][1].as_str().unwrap()).unwrap(),
-        ],
-        [
-            U256::from_dec_str(proof_json["pi_b"][1][0].as_str().unwrap()).unwrap(),
-            U256::from_dec_str(proof_json["pi_b"][1][1].as_str().unwrap()).unwrap(),
-        ],
-    ];
-
-    // Swap the G2 points to be the correct order with the new snarkjs
-    let pi_b_swapped: Vec<[U256; 2]> = pi_b_raw.iter().map(|inner| [inner[1], inner[0]]).collect();
-
-    // Convert the Vec to an array
-    let pi_b: [[U256; 2]; 2] = [pi_b_swapped[0], pi_b_swapped[1]];
-
-    let pi_c: [U256; 2] = [
-        U256::from_dec_str(proof_json["pi_c"][0].as_str().unwrap()).unwrap(),
-        U256::from_dec_str(proof_json["pi_c"][1].as_str().unwrap()).unwrap(),
-    ];
-
-    let signals_vec = public_json
-        .as_array()
-        .unwrap()
-        .iter()
-        .map(|x| U256::from_dec_str(x.as_str().unwrap()).unwrap())
-        .collect::<Vec<_>>();
-
-    println!("signals_vec: {:?}", signals_vec);
-
-    let signals: [U256; 27] = signals_vec
-        .as_slice()
-        .try_into()
-        .unwrap();
-
-    let calldata = CircomCalldata {
-        pi_a,
-        pi_b,
-        pi_c,
-        signals,
-    };
-    Ok(calldata)
+/// Loads the proof the provers wrote to `dir` for an email, ready to send to the wallet contract.
+pub fn get_calldata(dir: &str, nonce: &str) -> Result<CircomCalldata, ProofFileError> {
+    ProofArtifacts::load_from_dir(dir, nonce, &CircuitParams::default())?.to_calldata()
 }
 
 pub enum AbiType {
@@ -130,23 +87,18 @@ pub enum AbiType {
     TokenRegistry,
 }
 
-pub async fn get_provider(force_localhost: bool) -> Result<Provider<Http>, Error> {
+pub async fn get_provider(chain: &ChainConfig) -> Result<Provider<Http>, Error> {
     // let alchemy_api_key = std::env::var("ALCHEMY_GOERLI_KEY").unwrap();
     // println!("alchemy_api_key: {}", alchemy_api_key);
-    let rpcurl = if force_localhost {
-        "http://localhost:8548".to_string()
-    } else {
-        std::env::var("RPC_URL").expect("The RPC_URL environment variable must be set")
-    };
-    // Get the private key from the environment variable
+    let rpcurl = chain.rpc_url.clone();
     println!("Got provider for rpcurl: {}", rpcurl);
 
     let provider = Provider::<Http>::try_from(rpcurl)?;
     Ok(provider)
 }
 
-pub async fn get_gas_price(force_localhost: bool) -> Result<U256, Error> {
-    let provider = (get_provider(force_localhost).await).unwrap();
+pub async fn get_gas_price(chain: &ChainConfig) -> Result<U256, Error> {
+    let provider = (get_provider(chain).await).unwrap();
     let gas_price = provider.get_gas_price().await?;
     Ok(gas_price)
 }
@@ -167,11 +119,9 @@ pub fn get_abi(abi_type: AbiType) -> Result<Abi, Error> {
     Ok(abi)
 }
 
-pub async fn get_signer(force_localhost: bool) -> Result<SignerType, Error> {
-    let chain_id: u64 = std::env::var("CHAIN_ID")
-        .expect("The CHAIN_ID environment variable must be set")
-        .parse()?;
-    let provider = (get_provider(force_localhost).await).unwrap();
+pub async fn get_signer(chain: &ChainConfig) -> Result<SignerType, Error> {
+    let chain_id = chain.id;
+    let provider = (get_provider(chain).await).unwrap();
     let private_key_hex =
         std::env::var("PRIVATE_KEY").expect("The PRIVATE_KEY environment variable must be set");
     let wallet: LocalWallet = LocalWallet::from_str(&private_key_hex)?;
@@ -180,198 +130,354 @@ pub async fn get_signer(force_localhost: bool) -> Result<SignerType, Error> {
     Ok(signer)
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct EtherscanResponse {
-    status: String,
-    message: String,
-    result: Vec<Transaction>,
-}
-
-#[derive(Debug, serde::Deserialize)]
-struct Transaction {
-    blockNumber: String,
-    timeStamp: String,
-    hash: String,
-    nonce: String,
-    blockHash: String,
-    transactionIndex: String,
-    from: String,
-    to: String,
-    value: String,
-    gas: String,
-    gasPrice: String,
-    isError: String,
-    txreceipt_status: String,
-    input: String,
-    contractAddress: String,
-    cumulativeGasUsed: String,
-    gasUsed: String,
-    confirmations: String,
-}
-
-pub async fn get_pending_tx_count(force_localhost: bool, wallet_address: H160) -> Result<usize, Error> {
-    Ok(0)
-    // Query the current nonce of the account
-    // let provider = (get_provider(force_localhost).await).unwrap();
-    // let current_nonce = provider.get_transaction_count(wallet_address, None).await.unwrap();
-
-    // // Get pending txes
-    // let etherscan_api_key = std::env::var(ETHERSCAN_KEY).expect("The ETHERSCAN_API_KEY environment variable must be set");
-    // let chain_id: u64 = std::env::var("CHAIN_ID").expect("The CHAIN_ID environment variable must be set").parse().unwrap();
-    // let etherscan_url = match chain_id {
-    //     5 => "https://goerli.etherscan.io",
-    //     1 => "https://etherscan.io",
-    //     42161 => "https://arbiscan.io",
-    //     10 => "https://optimistic.etherscan.io",
-    //     _ => panic!("Unsupported chain id"),
-    // };
-    // let url = format!("{}/api?module=account&action=txlist&address={}&startblock=0&endblock=999999999&sort=asc&apikey={}", etherscan_url, wallet_address, etherscan_api_key);
-    // println!("Etherscan Url: {}", url);
-    // let response_text = reqwest::get(&url).await?.text().await?;
-    // println!("API response: {}", response_text);
-    // let response: EtherscanResponse = serde_json::from_str(&response_text)?;
-
-    // // let response = reqwest::get(&url).await.expect("Failed to send pending tx request to Etherscan").json::<EtherscanResponse>().await.expect("Failed to parse Etherscan return value");
-
-    // let pending_transactions: Vec<Transaction> = response.result.into_iter().filter(|tx| tx.txreceipt_status == "Pending").collect();
-    // let pending_count = pending_transactions.len();
-
-    // println!("Pending transactions for {}: {}", wallet_address, pending_count);
-    // Ok(pending_count)
-}
-
-// local: bool - whether or not to send to a local RPC
-// dir: data directory where the intermediate rapidsnark inputs/proofs will be stored
-pub async fn send_to_chain(
+/// The number of transactions the wallet has sent that aren't mined yet.
+pub async fn get_pending_tx_count(chain: &ChainConfig, wallet_address: H160) -> Result<usize, Error> {
+    let provider = get_provider(chain).await?;
+    let mined = provider.get_transaction_count(wallet_address, Some(BlockNumber::Latest.into())).await?;
+    let pending = provider.get_transaction_count(wallet_address, Some(BlockNumber::Pending.into())).await?;
+    Ok(pending.saturating_sub(mined).as_usize())
+}
+
+/// Sends the proof of an email to chain, calling the contract function its subject asks for
+/// on the chain it names (or the default chain).
+pub async fn send_proven_email_to_chain(
     force_localhost: bool,
     dir: &str,
     nonce: &str,
 ) -> Result<(), Error> {
-    // Load environment variables from the .env file
     dotenv().ok();
-    let contract_address: Address = std::env::var("CONTRACT_ADDRESS").unwrap().parse()?;
-    let signer_raw = get_signer(force_localhost).await.unwrap();
-    let sender_address = signer_raw.address().clone();
-    let signer = signer_raw.nonce_manager(sender_address);
+    let email = read_nonce_email(nonce)?;
+    let (subject, chain_name) = split_chain_suffix(email.subject.as_str());
+    let chains = ChainRegistry::from_env()?;
+    let chain = chains
+        .select(chain_name.as_deref())
+        .ok_or(anyhow!("Chain {:?} is not configured", chain_name))?;
+    let chain = if force_localhost { chain.with_localhost_rpc() } else { chain.clone() };
+    let submitter = TxSubmitter::new(&chain, Store::open_from_env().await?).await?;
+    let emailer = EmailSenderClient::new(
+        env::var(LOGIN_ID_KEY)?.as_str(),
+        env::var(LOGIN_PASSWORD_KEY)?.as_str(),
+        Some(env::var(SMTP_DOMAIN_NAME_KEY)?.as_str()),
+    );
+    if parse_subject_for_migrate(subject).is_ok() {
+        return Err(anyhow!("Migrates aren't proven, the relayer sends them when it receives them"));
+    }
+    let calldata = get_calldata(dir, nonce)?;
+    let files = CircuitFiles::from_env(&env::var(ZK_EMAIL_PATH_KEY)?);
+    ProofVerifier::load(&files.verification_key_path())?.verify(&calldata)?;
+    send_to_chain(&submitter, &emailer, nonce, &calldata).await
+}
 
-    let gas_price = get_gas_price(force_localhost).await.unwrap_or(50.into());
+/// Sends an email proven by the relayer itself to the chain its subject names, through that chain's long-lived submitter.
+pub async fn send_email_proof_to_chain(
+    submitters: &Submitters,
+    chains: &ChainRegistry,
+    emailer: &EmailSenderClient,
+    email: &ParsedEmail,
+    nonce: &str,
+    calldata: &CircomCalldata,
+) -> Result<(), Error> {
+    send_to_chain(email_submitter(submitters, chains, email)?, emailer, nonce, calldata).await
+}
 
-    // Read proof and public parameters from JSON files
-    let calldata = get_calldata(Some(dir), Some(nonce)).unwrap();
+/// The submitter of the chain an email's subject names, or of the default chain.
+pub fn email_submitter<'a>(submitters: &'a Submitters, chains: &ChainRegistry, email: &ParsedEmail) -> Result<&'a TxSubmitter, Error> {
+    let (_, chain_name) = split_chain_suffix(email.subject.as_str());
+    let chain = chains
+        .select(chain_name.as_deref())
+        .ok_or(anyhow!("Chain {:?} is not configured", chain_name))?;
+    submitters.get(chain.id).ok_or(anyhow!("No submitter for {}", chain.name))
+}
 
-    // Initialize NonceManagerMiddleware
-    // let nonce_manager = NonceManagerMiddleware::new(signer, sender_address);
-        // let contract: ContractInstance<SignerMiddleware<Provider<Http>, Wallet<SigningKey>>, Abi> =
-        // ContractInstance::new(contract_address, get_abi(AbiType::Wallet).unwrap(), signer);
-    let contract = ContractInstance::<_, ClientType>::new(contract_address, get_abi(AbiType::Wallet).unwrap(), &signer);
-    // let contract = ContractInstance::new(contract_address, get_abi(AbiType::Wallet).unwrap(), signer);
+// submitter: sends to the chain, with a localhost RPC when sending to a local fork
+// emailer: replies to the email with the outcome
+// calldata: the email's proof and public signals
+pub async fn send_to_chain(
+    submitter: &TxSubmitter,
+    emailer: &EmailSenderClient,
+    nonce: &str,
+    calldata: &CircomCalldata,
+) -> Result<(), Error> {
+    // Load environment variables from the .env file
+    dotenv().ok();
 
-    signer.initialize_nonce(None).await?;
+    // Call the transfer function
+    send_wallet_call(
+        submitter,
+        emailer,
+        nonce,
+        "transfer(uint256[2],uint256[2][2],uint256[2],uint256[27])",
+        (
+            calldata.pi_a,
+            calldata.pi_b,
+            calldata.pi_c,
+            calldata.signals,
+        ),
+    )
+    .await
+}
 
-    let pending_txes = get_pending_tx_count(force_localhost, sender_address).await.expect("Pending tx count failed");
-    for _ in 0..pending_txes {
-        let mut _nonce = signer.next();
+/// Moves all of a token from the sender's wallet to another email's wallet or to an external wallet,
+/// through the migrateAllToken overload matching the destination, between the wallets of the given raw salts.
+/// migrateAllToken takes no proof, so the contract only checks that the relayer calls it: the token and destination are the
+/// ones of the DKIM-verified subject, and the call is only made for emails whose nullifier wasn't used yet.
+pub async fn send_migrate_to_chain(
+    submitter: &TxSubmitter,
+    emailer: &EmailSenderClient,
+    nonce: &str,
+    from: &str,
+    validation: &CommandValidation,
+    token_name: &str,
+    destination: &MigrateDestination,
+) -> Result<(), Error> {
+    let from_salt_raw = validation.salt_sender.as_deref().ok_or(anyhow!("Migrate validation has no sender salt"))?;
+    let to_salt_raw = validation.salt_receiver.as_deref();
+    let from_salt = U256::from_dec_str(&calculate_decimal_salt(from, from_salt_raw).await?)?;
+    let token_name = token_name.to_string();
+
+    match destination {
+        MigrateDestination::Email(recipient) => {
+            let to_salt_raw = to_salt_raw.ok_or(anyhow!("Migrate to {} has no recipient salt", recipient))?;
+            let to_salt = U256::from_dec_str(&calculate_decimal_salt(recipient.as_str(), to_salt_raw).await?)?;
+            send_wallet_call(
+                submitter,
+                emailer,
+                nonce,
+                "migrateAllToken(uint256,uint256,string)",
+                (from_salt, to_salt, token_name),
+            )
+            .await
+        }
+        MigrateDestination::Wallet(wallet) => {
+            let to_wallet: Address = wallet.parse()?;
+            let mut from_salt_bytes = [0u8; 32];
+            from_salt.to_big_endian(&mut from_salt_bytes);
+            send_wallet_call(
+                submitter,
+                emailer,
+                nonce,
+                "migrateAllToken(bytes32,address,string)",
+                (H256(from_salt_bytes), to_wallet, token_name),
+            )
+            .await
+        }
     }
+}
 
-    println!("Sending transaction with gas price {:?}...", gas_price);
-
-    // Call the transfer function
-    let call = contract
-        .method::<_, ()>(
-            "transfer",
-            (
-                calldata.pi_a,
-                calldata.pi_b,
-                calldata.pi_c,
-                calldata.signals,
-            ),
-        )?
-        .gas_price(gas_price * 2);
-
-    println!("Calling call: {:?}", call);
-
-    // Send the transaction with the updated nonce
-    let pending_tx = match call.send().await {
-        Ok(tx) => tx,
+/// Sends a call to the wallet contract, waits for its receipt, stores the outcome for the email, and replies with it.
+/// The email's job is moved to Submitted with the transaction's hash as soon as it is broadcast.
+/// The function is given by its full signature, since overloaded functions can't be looked up by name.
+async fn send_wallet_call<T: Tokenize>(
+    submitter: &TxSubmitter,
+    emailer: &EmailSenderClient,
+    nonce: &str,
+    signature: &str,
+    args: T,
+) -> Result<(), Error> {
+    let chain = submitter.chain();
+    let email = read_nonce_email(nonce)?;
+
+    let contract = ContractInstance::<_, SignerType>::new(chain.wallet_contract, get_abi(AbiType::Wallet).unwrap(), submitter.signer());
+    let call = contract.method_hash::<_, ()>(id(signature), args)?;
+    let mut tx = call.tx.clone();
+
+    // Only broadcast calls that pass a simulation, so bad proofs or signals never cost gas or take a nonce
+    let block_number = submitter.signer().get_block_number().await?.as_u64();
+    let simulated = submitter.simulate(&tx, block_number).await;
+    let simulation = Simulation {
+        chain_id: chain.id,
+        block_number,
+        passed: simulated.is_ok(),
+        gas_estimate: simulated.as_ref().ok().copied(),
+        revert_reason: simulated.as_ref().err().map(|e| match revert_reason_of(e) {
+            Some(reason) => reason.to_string(),
+            None => e.to_string(),
+        }),
+    };
+    println!("Simulated {} at block {}: {:?}", signature, block_number, simulation);
+    if let Err(e) = submitter.store().set_email_simulation(&email.raw, simulation) {
+        println!("Error storing simulation: {}", e);
+    }
+    match simulated {
+        Ok(gas_estimate) => {
+            tx.set_gas(gas_estimate);
+        }
         Err(e) => {
-            println!("Error: {:?}", e);
-            reply_with_message(nonce, "Error sending transaction. Most likely your email domain is not supported (must be @gmail.com, @hotmail.com, @ethereum.org, or @skiff.com).", false);
-            println!("Error bytes: {:?}", e.as_revert());
-            return Err(e.into());
+            let reason = revert_reason_of(&e);
+            println!("Call for email {} would revert: {:?} ({:?})", calculate_hash(&email.raw), reason, e);
+            if let Err(reply_error) = reply_with_message(emailer, &email, &rejected_reply(reason.as_ref()), false) {
+                println!("Error sending reply: {}", reply_error);
+            }
+            return Err(e);
         }
-    };
-    println!("Transaction hash: {:?}", pending_tx);
-    let etherscan_reply = reply_with_etherscan(pending_tx.tx_hash());
+    }
 
-    // Reply-all with tx data
-    reply_with_message(nonce, &etherscan_reply, true);
-    Ok(())
-}
+    let (tx, fees) = submitter.prepare(tx).await?;
+    println!("Sending {} with fees {:?}...", signature, fees);
+    let (tx, tx_hash) = submitter.send(tx).await?;
+    let submitted = submitter.store().update_email_job(&email.raw, |job| {
+        job.transition(JobState::Submitted);
+        job.chain_id = Some(chain.id);
+        job.tx_hash = Some(format!("0x{:x}", tx_hash));
+    });
+    if let Err(e) = submitted {
+        println!("Error storing submitted transaction: {}", e);
+    }
 
-fn reply_with_message(nonce: &str, reply: &str, send_to_recipient: bool) {
-    dotenv().ok();
-    // TODO: Don't reconstruct the sender client for each email
-    let mut sender: EmailSenderClient = EmailSenderClient::new(
-        env::var(LOGIN_ID_KEY).unwrap().as_str(),
-        env::var(LOGIN_PASSWORD_KEY).unwrap().as_str(),
-        Some(env::var(SMTP_DOMAIN_NAME_KEY).unwrap().as_str()),
-    );
+    let confirmations = tx_confirmations();
+    println!("Waiting for {} confirmation(s) on {}...", confirmations, chain.name);
+    let mined = submitter
+        .wait(tx, fees, tx_hash, confirmations, |replacement| {
+            if let Err(e) = submitter.store().record_tx_replacement(&email.raw, replacement) {
+                println!("Error recording transaction replacement: {}", e);
+            }
+            async {}
+        })
+        .await?;
+    finish_wallet_call(submitter, emailer, &email, mined.tx_hash, mined.receipt.as_ref(), confirmations).await
+}
 
-    // Read raw email from received_eml/wallet_{nonce}.eml
-    let eml_var = env::var(INCOMING_EML_PATH).unwrap();
-    let path = format!("{}/wallet_{}.eml", eml_var, nonce);
-    println!("Fetching eml from path {:?}", path);
-    let raw_email = fs::read_to_string(path).unwrap();
-    let confirmation = sender.reply_all(&raw_email, &reply, send_to_recipient);
+/// Waits for the transaction an email's job submitted before the relayer restarted, rather than sending the email again,
+/// then stores its outcome and replies with it. A transaction the node no longer knows is treated as dropped.
+pub async fn wait_for_submitted_tx(submitters: &Submitters, emailer: &EmailSenderClient, job: &Job) -> Result<(), Error> {
+    let chain_id = job.chain_id.ok_or(anyhow!("Submitted job has no chain"))?;
+    let nonce = job.file_id.as_deref().ok_or(anyhow!("Submitted job has no file id"))?;
+    let tx_hash: H256 = job.tx_hash.as_deref().ok_or(anyhow!("Submitted job has no transaction"))?.parse()?;
+    let submitter = submitters.get(chain_id).ok_or(anyhow!("Chain {} is not configured", chain_id))?;
+    let email = read_nonce_email(nonce)?;
+
+    let confirmations = tx_confirmations();
+    println!("Waiting for {} confirmation(s) of {:?} on {}...", confirmations, tx_hash, submitter.chain().name);
+    let receipt = match submitter.signer().get_transaction(tx_hash).await? {
+        Some(_) => PendingTransaction::new(tx_hash, submitter.signer().provider())
+            .confirmations(confirmations)
+            .await?,
+        None => None,
+    };
+    finish_wallet_call(submitter, emailer, &email, tx_hash, receipt.as_ref(), confirmations).await
 }
 
-fn send_final_recipient_intro(nonce: &str, reply: &str, new_subject: &str, send_to_recipient: bool) {
-    dotenv().ok();
-    let mut sender: EmailSenderClient = EmailSenderClient::new(
-        env::var(LOGIN_ID_KEY).unwrap().as_str(),
-        env::var(LOGIN_PASSWORD_KEY).unwrap().as_str(),
-        Some(env::var(SMTP_DOMAIN_NAME_KEY).unwrap().as_str()),
-    );
-    // Read raw email from received_eml/wallet_{nonce}.eml
-    let eml_var = env::var(INCOMING_EML_PATH).unwrap();
-
-    let raw_email = fs::read_to_string(format!("{}/wallet_{}.eml", eml_var, nonce)).unwrap();
-    let from_addr = extract_from(&raw_email.to_string()).unwrap_or("".to_string());
-    let subject_str = extract_subject(&raw_email.to_string()).unwrap_or("".to_string());
-    // Parse the subject to get the amount, currency, and recipient
-    let result = parse_subject_for_send(subject_str.as_str());
-    let (amount, currency, recipient) = match result {
-        Ok((amt, cur, rec)) => (amt, cur, rec),
-        Err(_) => {
-            println!("Could not parse subject");
-            return;
-        }
+/// Stores the outcome of an email's mined (or dropped) transaction and replies with it.
+async fn finish_wallet_call(
+    submitter: &TxSubmitter,
+    emailer: &EmailSenderClient,
+    email: &ParsedEmail,
+    tx_hash: H256,
+    receipt: Option<&TransactionReceipt>,
+    confirmations: usize,
+) -> Result<(), Error> {
+    let chain = submitter.chain();
+    let (outcome, revert_reason) = tx_outcome(chain, tx_hash, receipt).await;
+    println!("Transaction outcome: {:?}", outcome);
+    if let Some(reason) = &revert_reason {
+        println!("Transaction {:?} for email {} reverted: {:?}", tx_hash, calculate_hash(&email.raw), reason);
+    }
+
+    let reply = match outcome.status {
+        TxStatus::Confirmed => reply_with_explorer(chain, tx_hash, confirmations),
+        TxStatus::Reverted => reverted_reply(chain, tx_hash, revert_reason.as_ref()),
+        TxStatus::Dropped => dropped_reply(chain, tx_hash),
     };
+    if let Err(e) = submitter.store().set_email_tx_outcome(&email.raw, outcome.clone()) {
+        println!("Error storing transaction outcome: {}", e);
+    }
+
+    // Reply-all with tx data once it went through, otherwise only tell the sender
+    if let Err(e) = reply_with_message(emailer, email, &reply, outcome.status == TxStatus::Confirmed) {
+        println!("Error sending reply: {}", e);
+    }
+    match outcome.status {
+        TxStatus::Confirmed => Ok(()),
+        TxStatus::Reverted => match revert_reason {
+            Some(reason) => Err(anyhow!("Transaction {:?} reverted: {}", tx_hash, reason)),
+            None => Err(anyhow!("Transaction {:?} reverted", tx_hash)),
+        },
+        TxStatus::Dropped => Err(anyhow!("Transaction {:?} was dropped", tx_hash)),
+    }
+}
 
-    // Create the subject and body for the recipient email
-    
-    let intro_subject = recipient_intro_subject(from_addr.as_str(), &amount, &currency);
-    let intro_body = recipient_intro_body(from_addr.as_str(), &amount, &currency);
+fn tx_confirmations() -> usize {
+    env::var(TX_CONFIRMATIONS_KEY)
+        .ok()
+        .and_then(|confirmations| confirmations.parse().ok())
+        .unwrap_or(DEFAULT_TX_CONFIRMATIONS)
+}
 
-    // Send the email to the recipient
-    let confirmation_recipient = sender.send_new_email(intro_subject.as_str(), intro_body.as_str(), &recipient);
-    match confirmation_recipient {
-        Ok(_) => println!("Confirmation email sent successfully to recipient."),
-        Err(e) => println!("Error sending confirmation email to final: {}", e),
+/// Builds the outcome of a transaction from its receipt (None if it was dropped),
+/// decoding the transfers it emitted or the reason it reverted.
+async fn tx_outcome(chain: &ChainConfig, tx_hash: H256, receipt: Option<&TransactionReceipt>) -> (TxOutcome, Option<RevertReason>) {
+    let mut outcome = TxOutcome {
+        chain_id: chain.id,
+        tx_hash: format!("0x{:x}", tx_hash),
+        status: TxStatus::Dropped,
+        block_number: None,
+        revert_reason: None,
+        transfers: vec![],
+    };
+    let Some(receipt) = receipt else {
+        return (outcome, None);
+    };
+    outcome.block_number = receipt.block_number.map(|block_number| block_number.as_u64());
+    if receipt.status == Some(U64::one()) {
+        outcome.status = TxStatus::Confirmed;
+        outcome.transfers = receipt
+            .logs
+            .iter()
+            .filter(|log| log.address == chain.wallet_contract)
+            .filter_map(|log| parse_log::<TransferInfo>(log.clone()).ok())
+            .collect();
+    } else {
+        outcome.status = TxStatus::Reverted;
+        let reason = query_revert_reason(chain, tx_hash, receipt.block_number).await;
+        outcome.revert_reason = reason.as_ref().map(|reason| reason.to_string());
+        return (outcome, reason);
     }
+    (outcome, None)
+}
+
+/// Replays a reverted transaction at the block it was mined in, since receipts don't include the revert reason.
+async fn query_revert_reason(chain: &ChainConfig, tx_hash: H256, block_number: Option<U64>) -> Option<RevertReason> {
+    let provider = get_provider(chain).await.ok()?;
+    let tx = provider.get_transaction(tx_hash).await.ok()??;
+    let call: TypedTransaction = (&tx).into();
+    let block = block_number.map(|block_number| BlockId::Number(BlockNumber::Number(block_number)));
+    let error = provider.call(&call, block).await.err()?;
+    RevertReason::from_error_response(RpcError::as_error_response(&error)?, &get_abi(AbiType::Wallet).ok()?)
+}
+
+/// The revert behind an error from calling or estimating a wallet call, if it was one.
+fn revert_reason_of(error: &Error) -> Option<RevertReason> {
+    let response = match error.downcast_ref::<<SignerType as Middleware>::Error>() {
+        Some(error) => MiddlewareError::as_error_response(error),
+        None => RpcError::as_error_response(error.downcast_ref::<ProviderError>()?),
+    }?;
+    RevertReason::from_error_response(response, &get_abi(AbiType::Wallet).ok()?)
+}
 
-    let confirmation = sender.send_new_email(&new_subject,&raw_email, &reply);
+/// Reads and parses the raw email saved as wallet_{nonce}.eml in the eml directory
+fn read_nonce_email(nonce: &str) -> Result<ParsedEmail, Error> {
+    let raw_email = fs::read_to_string(format!("{}/wallet_{}.eml", CircuitFiles::eml_dir(), nonce))?;
+    ParsedEmail::parse(&raw_email)
+}
+
+/// Replies to an email with the shared email client, to everyone on it if send_to_recipient is set or else only the sender.
+fn reply_with_message(emailer: &EmailSenderClient, email: &ParsedEmail, reply: &str, send_to_recipient: bool) -> Result<(), Error> {
+    emailer
+        .reply_all(email, reply, send_to_recipient)
+        .map_err(|e| anyhow!("Could not reply to {}: {}", email.from, e))?;
+    println!("Reply sent successfully.");
+    Ok(())
 }
 
 pub async fn query_address(
-    force_localhost: bool,
+    chain: &ChainConfig,
     user_salt: &str,
 ) -> Result<H160, Error> {
     // Load environment variables from the .env file
     dotenv().ok();
     let abi = get_abi(AbiType::Wallet)?;
-    let signer = get_signer(force_localhost).await?;
-    let logic_contract_address: Address = std::env::var("CONTRACT_ADDRESS").unwrap().parse()?;
+    let signer = get_signer(chain).await?;
+    let logic_contract_address = chain.wallet_contract;
     let logic_contract = ContractInstance::new(logic_contract_address, abi, signer);
     let decimal_salt_u256 = U256::from_dec_str(&user_salt)?;
     let address_method = logic_contract.method::<_, Address>("getOrCreateWallet", decimal_salt_u256)?;
@@ -381,60 +487,221 @@ pub async fn query_address(
     Ok(address)
 }
 
+/// Whether the wallet contract has marked an email's nullifier as used, i.e. the email was already executed.
+pub async fn query_nullifier_used(chain: &ChainConfig, nullifier: U256) -> Result<bool, Error> {
+    let abi = get_abi(AbiType::Wallet)?;
+    let signer = get_signer(chain).await?;
+    let logic_contract = ContractInstance::new(chain.wallet_contract, abi, signer);
+    let used = logic_contract.method::<_, bool>("nullifier", nullifier)?.call().await?;
+    Ok(used)
+}
+
+/// Resolves a token name to its ERC20 contract address through the TokenRegistry contract the wallet contract uses.
+/// The zero address if the name isn't registered.
+async fn query_registered_token_address(chain: &ChainConfig, token_name: &str) -> Result<Address, Error> {
+    let signer = get_signer(chain).await?;
+    let logic_contract = ContractInstance::new(chain.wallet_contract, get_abi(AbiType::Wallet)?, signer.clone());
+    let registry_address = logic_contract.method::<_, Address>("getTokenRegistry", ())?.call().await?;
+    let registry_contract = ContractInstance::new(registry_address, get_abi(AbiType::TokenRegistry)?, signer);
+    let erc20_address = registry_contract
+        .method::<_, Address>("getTokenAddress", token_name.to_string())?
+        .call()
+        .await?;
+    Ok(erc20_address)
+}
+
 // Given an address and token, get the balance of that token for that address from the chain
 // This can be done on a local light node or fork to ensure future tx data is not leaked
+// The token's address and decimals come from the TokenRegistry cache, so only the balance is read
 pub async fn query_balance(
-    force_localhost: bool,
+    chain: &ChainConfig,
     user_address: &str,
-    token_name: &str,
-) -> Result<f64, Error> {
+    token: &TokenInfo,
+) -> Result<TokenAmount, Error> {
     // Load environment variables from the .env file
     dotenv().ok();
-    let abi = get_abi(AbiType::Wallet)?;
-    let signer = get_signer(force_localhost).await?;
-    let logic_contract_address: Address = std::env::var("CONTRACT_ADDRESS").unwrap().parse()?;
-    let logic_contract = ContractInstance::new(logic_contract_address, abi, signer);
-    let erc20_address_method = logic_contract.method::<_, Address>("getTokenAddress", token_name.to_string())?;
-    let erc20_address = erc20_address_method.call().await?;
-    let erc_contract = ContractInstance::new(erc20_address, get_abi(AbiType::ERC20).unwrap(), get_signer(force_localhost).await.unwrap());
+    if token.name == NATIVE_TOKEN {
+        // The native balance is held by the wallet itself, in wei
+        let provider = get_provider(chain).await?;
+        let raw_balance = provider.get_balance(Address::from_str(user_address)?, None).await?;
+        return Ok(TokenAmount::new(raw_balance, NATIVE_DECIMALS));
+    }
+    let erc_contract = ContractInstance::new(token.address, get_abi(AbiType::ERC20)?, get_signer(chain).await?);
     // Call the balanceOf function on the ERC20 contract to get the raw balance in wei
     let raw_balance: U256 = erc_contract
-        .method::<_, U256>("balanceOf", Address::from_str(user_address).unwrap())
-        .unwrap()
+        .method::<_, U256>("balanceOf", Address::from_str(user_address)?)?
         .call()
         .await?;
 
-    // Call the decimals function on the ERC20 contract to get the decimal count
-    let decimals: U256 = erc_contract
-        .method::<_, U256>("decimals", ())
-        .unwrap()
-        .call()
-        .await?;
-
-    let decimals_u256 = U256::from(decimals.low_u64());
-    let divisor = U256::from(10).pow(decimals_u256);
-    let balance_u256 = raw_balance / divisor;
-    let balance: f64 = balance_u256.low_u64() as f64;
-    
-    if token_name == "TEST" && balance == 0.0 {
-        return Ok(100.0);
+    let balance = TokenAmount::new(raw_balance, token.decimals);
+    if token.name == "TEST" && balance.is_zero() {
+        return Ok(TokenAmount::from_whole(100, token.decimals));
     }
     Ok(balance)
 }
 
 
+/// A token registered in the wallet contract's TokenRegistry, or the native asset (with a zero address).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenInfo {
+    pub name: String,
+    pub address: Address,
+    pub decimals: u8,
+}
+
+/// A cache of the tokens registered in each chain's TokenRegistry contract, so commands can use any token that
+/// `setTokenAddress` added without a redeploy. The contract can look names up but not list them, so the cache is seeded with
+/// the names in TOKEN_NAMES, names that aren't cached are looked up on chain the first time an email uses them,
+/// and every cached name is re-read periodically.
+#[derive(Debug, Clone, Default)]
+pub struct TokenRegistry {
+    tokens: Arc<RwLock<BTreeMap<(u64, String), TokenInfo>>>,
+}
+
+impl TokenRegistry {
+    /// A registry that only knows the given tokens, without reading the chain.
+    pub fn with_tokens(chain: &ChainConfig, tokens: Vec<TokenInfo>) -> Self {
+        let registry = Self::default();
+        for token in tokens {
+            registry.insert(chain, token);
+        }
+        registry
+    }
+
+    /// Loads the default tokens of every chain. Tokens that fail to load are logged and retried on the next refresh.
+    pub async fn load(chains: &ChainRegistry) -> Self {
+        let registry = Self::default();
+        registry.refresh(chains).await;
+        registry
+    }
+
+    /// Re-reads the address and decimals of every cached token, dropping tokens that were unregistered.
+    pub async fn refresh(&self, chains: &ChainRegistry) {
+        for chain in chains.chains() {
+            let mut names = self.names(chain);
+            for name in seed_token_names() {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+            for name in names {
+                match query_token_info(chain, &name).await {
+                    Ok(Some(token)) => self.insert(chain, token),
+                    Ok(None) => {
+                        self.tokens.write().unwrap().remove(&(chain.id, name));
+                    }
+                    Err(e) => println!("Error loading token {} on {} from the registry: {}", name, chain.name, e),
+                }
+            }
+            println!("Token registry loaded for {}: {}", chain.name, self.names(chain).join(", "));
+        }
+    }
+
+    /// Refreshes the registry in the background, every TOKEN_REFRESH_INTERVAL_SECS seconds.
+    pub fn spawn_refresh(&self, chains: ChainRegistry) {
+        let interval = std::env::var(TOKEN_REFRESH_INTERVAL_KEY)
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_TOKEN_REFRESH_SECS);
+        let registry = self.clone();
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(interval)).await;
+                registry.refresh(&chains).await;
+            }
+        });
+    }
+
+    fn insert(&self, chain: &ChainConfig, token: TokenInfo) {
+        self.tokens.write().unwrap().insert((chain.id, token.name.clone()), token);
+    }
+
+    /// The cached token, without reading the chain.
+    pub fn get(&self, chain: &ChainConfig, token_name: &str) -> Option<TokenInfo> {
+        self.tokens.read().unwrap().get(&(chain.id, token_name.to_string())).cloned()
+    }
+
+    /// The cached token, or else the token as registered on chain right now. None if it isn't registered.
+    pub async fn resolve(&self, chain: &ChainConfig, token_name: &str) -> Result<Option<TokenInfo>, Error> {
+        if let Some(token) = self.get(chain, token_name) {
+            return Ok(Some(token));
+        }
+        let token = query_token_info(chain, token_name).await?;
+        if let Some(token) = &token {
+            println!("Found newly registered token {} on {} at {:?}", token.name, chain.name, token.address);
+            self.insert(chain, token.clone());
+        }
+        Ok(token)
+    }
+
+    /// The names of every token cached for a chain, native asset first.
+    pub fn names(&self, chain: &ChainConfig) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .tokens
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|(chain_id, _)| *chain_id == chain.id)
+            .map(|(_, name)| name.clone())
+            .collect();
+        names.sort_by_key(|name| name != NATIVE_TOKEN);
+        names
+    }
+}
+
+/// The names to look up in the TokenRegistry contract: the comma separated TOKEN_NAMES, or DEFAULT_TOKEN_NAMES.
+fn seed_token_names() -> Vec<String> {
+    match std::env::var(TOKEN_NAMES_KEY) {
+        Ok(names) => names
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect(),
+        Err(_) => DEFAULT_TOKEN_NAMES.iter().map(|name| name.to_string()).collect(),
+    }
+}
+
+fn native_token_info() -> TokenInfo {
+    TokenInfo {
+        name: NATIVE_TOKEN.to_string(),
+        address: Address::zero(),
+        decimals: NATIVE_DECIMALS,
+    }
+}
+
+// Reads a token's address from the TokenRegistry contract and its decimals from the token, or None if the name isn't registered
+async fn query_token_info(chain: &ChainConfig, token_name: &str) -> Result<Option<TokenInfo>, Error> {
+    if token_name == NATIVE_TOKEN {
+        return Ok(Some(native_token_info()));
+    }
+    let address = query_registered_token_address(chain, token_name).await?;
+    if address.is_zero() {
+        return Ok(None);
+    }
+    let erc_contract = ContractInstance::new(address, get_abi(AbiType::ERC20)?, get_signer(chain).await?);
+    let decimals: u8 = erc_contract.method::<_, u8>("decimals", ())?.call().await?;
+    Ok(Some(TokenInfo {
+        name: token_name.to_string(),
+        address,
+        decimals,
+    }))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::chain_config::LOCALHOST_RPC_URL;
 
     #[tokio::test]
     async fn test_query_balance() {
         dotenv::dotenv().ok();
-        let balance = query_balance(false, "0x11fE4B6AE13d2a6055C8D9cF65c55bac32B5d844", "DAI").await;
+        let chains = ChainRegistry::from_env().unwrap();
+        let dai = TokenRegistry::default().resolve(chains.default_chain(), "DAI").await.unwrap().unwrap();
+        let balance = query_balance(chains.default_chain(), "0x11fE4B6AE13d2a6055C8D9cF65c55bac32B5d844", &dai).await;
 
         match balance {
             Ok(bal) => {
-                assert!(bal > 0.0, "Balance must be more than 0");
+                assert!(!bal.is_zero(), "Balance must be more than 0");
             },
             Err(e) => {
                 println!("Error: {:?}", e);
@@ -446,11 +713,13 @@ mod test {
     #[tokio::test]
     async fn test_query_balance_0xee() {
         dotenv::dotenv().ok();
-        let balance = query_balance(false, "0xeede835a3a8ab64193a379d1ebbe528201d90f29", "TEST").await;
+        let chains = ChainRegistry::from_env().unwrap();
+        let test = TokenRegistry::default().resolve(chains.default_chain(), "TEST").await.unwrap().unwrap();
+        let balance = query_balance(chains.default_chain(), "0xeede835a3a8ab64193a379d1ebbe528201d90f29", &test).await;
 
         match balance {
             Ok(bal) => {
-                assert!(bal > 90.0, "Balance must be more than 90");
+                assert!(bal > TokenAmount::from_whole(90, bal.decimals), "Balance must be more than 90");
             },
             Err(e) => {
                 println!("Error: {:?}", e);
@@ -459,11 +728,28 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn test_token_registry_cache() {
+        let dai = TokenInfo {
+            name: "DAI".to_string(),
+            address: Address::repeat_byte(0x11),
+            decimals: 18,
+        };
+        let goerli = ChainConfig::new(5, LOCALHOST_RPC_URL, Address::zero());
+        let arbitrum = ChainConfig::new(42161, LOCALHOST_RPC_URL, Address::zero());
+        let registry = TokenRegistry::with_tokens(&goerli, vec![dai.clone(), native_token_info()]);
+        assert_eq!(registry.names(&goerli), vec!["ETH", "DAI"]);
+        assert_eq!(registry.resolve(&goerli, "DAI").await.unwrap(), Some(dai));
+        assert_eq!(registry.get(&goerli, "USDC"), None);
+        assert_eq!(registry.get(&arbitrum, "DAI"), None);
+    }
+
     #[tokio::test]
     async fn test_get_pending_tx_count() {
         dotenv::dotenv().ok();
         let wallet_address = "0x11fE4B6AE13d2a6055C8D9cF65c55bac32B5d844".parse().unwrap();
-        let pending_tx_count = get_pending_tx_count(false, wallet_address).await;
+        let chains = ChainRegistry::from_env().unwrap();
+        let pending_tx_count = get_pending_tx_count(chains.default_chain(), wallet_address).await;
 
         match pending_tx_count {
             Ok(count) => {
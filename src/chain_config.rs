@@ -0,0 +1,156 @@
+use anyhow::{anyhow, Result};
+use ethers::core::types::{Address, H256};
+use std::env;
+
+/// The RPC used instead of a chain's own when sending to a local fork
+pub const LOCALHOST_RPC_URL: &str = "http://localhost:8548";
+
+/// Chains the relayer knows the name and explorer of: (chain id, name, other names users may type, explorer base URL)
+const KNOWN_CHAINS: [(u64, &str, &[&str], &str); 4] = [
+    (1, "Ethereum", &["mainnet", "ethereum mainnet"], "https://etherscan.io"),
+    (5, "Ethereum Goerli", &["goerli"], "https://goerli.etherscan.io"),
+    (10, "Optimism", &["op", "op mainnet"], "https://optimistic.etherscan.io"),
+    (42161, "Arbitrum", &["arbitrum one", "arb"], "https://arbiscan.io"),
+];
+
+/// Everything needed to talk to one chain: where to send calls, which wallet contract to call, and where users can view transactions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainConfig {
+    pub id: u64,
+    pub name: String,
+    pub rpc_url: String,
+    pub wallet_contract: Address,
+    pub explorer_url: String,
+}
+
+impl ChainConfig {
+    /// Builds the config of a chain from its id, filling in the name and explorer of known chains.
+    pub fn new(id: u64, rpc_url: &str, wallet_contract: Address) -> Self {
+        let (name, explorer_url) = match KNOWN_CHAINS.iter().find(|(known_id, ..)| *known_id == id) {
+            Some((_, name, _, explorer_url)) => (name.to_string(), explorer_url.to_string()),
+            None => (format!("Chain {}", id), "https://blockscan.com".to_string()),
+        };
+        Self {
+            id,
+            name,
+            rpc_url: rpc_url.to_string(),
+            wallet_contract,
+            explorer_url,
+        }
+    }
+
+    /// The same chain, reached through a local fork instead.
+    pub fn with_localhost_rpc(&self) -> Self {
+        Self {
+            rpc_url: LOCALHOST_RPC_URL.to_string(),
+            ..self.clone()
+        }
+    }
+
+    pub fn tx_url(&self, tx_hash: H256) -> String {
+        format!("{}/tx/0x{:x}", self.explorer_url, tx_hash)
+    }
+
+    /// Whether a name typed in a subject (e.g. "arbitrum" or "42161") refers to this chain.
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim().to_lowercase();
+        if name == self.name.to_lowercase() || name == self.id.to_string() {
+            return true;
+        }
+        KNOWN_CHAINS
+            .iter()
+            .any(|(id, _, aliases, _)| *id == self.id && aliases.contains(&name.as_str()))
+    }
+}
+
+/// The chains the relayer is configured for. Commands run on the default chain unless their subject ends with "on <chain>".
+#[derive(Debug, Clone)]
+pub struct ChainRegistry {
+    chains: Vec<ChainConfig>,
+    default_id: u64,
+}
+
+impl ChainRegistry {
+    pub fn new(chains: Vec<ChainConfig>, default_id: u64) -> Result<Self> {
+        if !chains.iter().any(|chain| chain.id == default_id) {
+            return Err(anyhow!("Default chain {} is not configured", default_id));
+        }
+        Ok(Self { chains, default_id })
+    }
+
+    /// Loads the default chain from CHAIN_ID, RPC_URL and CONTRACT_ADDRESS, and every other known chain
+    /// that has both RPC_URL_<chain id> and CONTRACT_ADDRESS_<chain id> set.
+    /// EXPLORER_URL_<chain id> overrides the explorer of any of them.
+    pub fn from_env() -> Result<Self> {
+        let default_id: u64 = env::var("CHAIN_ID")
+            .map_err(|_| anyhow!("The CHAIN_ID environment variable must be set"))?
+            .parse()?;
+        let rpc_url = env::var("RPC_URL").map_err(|_| anyhow!("The RPC_URL environment variable must be set"))?;
+        let wallet_contract: Address = env::var("CONTRACT_ADDRESS")
+            .map_err(|_| anyhow!("The CONTRACT_ADDRESS environment variable must be set"))?
+            .parse()?;
+        let mut chains = vec![ChainConfig::new(default_id, &rpc_url, wallet_contract)];
+
+        for (id, ..) in KNOWN_CHAINS.iter().filter(|(id, ..)| *id != default_id) {
+            if let (Ok(rpc_url), Ok(wallet_contract)) = (
+                env::var(format!("RPC_URL_{}", id)),
+                env::var(format!("CONTRACT_ADDRESS_{}", id)),
+            ) {
+                chains.push(ChainConfig::new(*id, &rpc_url, wallet_contract.parse()?));
+            }
+        }
+        for chain in chains.iter_mut() {
+            if let Ok(explorer_url) = env::var(format!("EXPLORER_URL_{}", chain.id)) {
+                chain.explorer_url = explorer_url.trim_end_matches('/').to_string();
+            }
+        }
+        Self::new(chains, default_id)
+    }
+
+    pub fn default_chain(&self) -> &ChainConfig {
+        self.by_id(self.default_id).expect("The default chain is always configured")
+    }
+
+    pub fn by_id(&self, id: u64) -> Option<&ChainConfig> {
+        self.chains.iter().find(|chain| chain.id == id)
+    }
+
+    /// The chain a subject asked for by name, or the default chain when it didn't name one.
+    pub fn select(&self, name: Option<&str>) -> Option<&ChainConfig> {
+        match name {
+            Some(name) => self.chains.iter().find(|chain| chain.matches(name)),
+            None => Some(self.default_chain()),
+        }
+    }
+
+    pub fn chains(&self) -> &[ChainConfig] {
+        &self.chains
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.chains.iter().map(|chain| chain.name.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_select_chain_by_name() {
+        let goerli = ChainConfig::new(5, "https://goerli.example", Address::repeat_byte(1));
+        let arbitrum = ChainConfig::new(42161, "https://arbitrum.example", Address::repeat_byte(2));
+        let chains = ChainRegistry::new(vec![goerli.clone(), arbitrum.clone()], 5).unwrap();
+
+        assert_eq!(chains.select(None), Some(&goerli));
+        assert_eq!(chains.select(Some("Arbitrum")), Some(&arbitrum));
+        assert_eq!(chains.select(Some("arbitrum one")), Some(&arbitrum));
+        assert_eq!(chains.select(Some("42161")), Some(&arbitrum));
+        assert_eq!(chains.select(Some("Optimism")), None);
+        assert_eq!(
+            arbitrum.tx_url(H256::repeat_byte(0xab)),
+            format!("https://arbiscan.io/tx/0x{}", "ab".repeat(32))
+        );
+        assert!(ChainRegistry::new(vec![arbitrum], 5).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+use crate::coordinator::MAX_MESSAGE_ID_LEN;
+use crate::dkim::VerifiedDkim;
+use anyhow::{anyhow, Result};
+use num_bigint::BigUint;
+use rsa::traits::PublicKeyParts;
+use serde::{Deserialize, Serialize};
+use sha2::digest::generic_array::GenericArray;
+use sha2::compress256;
+
+/// The SHA-256 initial hash values, which the body precompute starts from
+const SHA256_IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// How many public signals the wallet circuit has, and so how many the contract takes
+pub const PUBLIC_SIGNALS: usize = 27;
+
+/// The sizes the wallet circuit was compiled with. Inputs are padded to exactly these lengths.
+#[derive(Debug, Clone, Copy)]
+pub struct CircuitParams {
+    pub max_header_bytes: usize,
+    pub max_body_bytes: usize,
+    /// Bits per chunk of the RSA modulus and signature
+    pub bigint_n: usize,
+    /// Chunks of the RSA modulus and signature
+    pub bigint_k: usize,
+    pub public_signals: usize,
+}
+
+impl Default for CircuitParams {
+    fn default() -> Self {
+        Self {
+            max_header_bytes: 1024,
+            max_body_bytes: 1536,
+            bigint_n: 121,
+            bigint_k: 17,
+            public_signals: PUBLIC_SIGNALS,
+        }
+    }
+}
+
+/// The input JSON of the wallet circuit for one email, as zk-email-verify's generate_input.ts wrote it.
+/// Numbers are decimal strings and byte arrays are arrays of them, as circom expects.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CircuitInput {
+    /// The signed (canonicalized) header, SHA-256 padded and then zero filled
+    pub in_padded: Vec<String>,
+    /// The length of the SHA-256 padded header, before zero filling
+    pub in_len_padded_bytes: String,
+    pub modulus: Vec<String>,
+    pub signature: Vec<String>,
+    /// The body after the precomputed blocks, SHA-256 padded and then zero filled
+    pub in_body_padded: Vec<String>,
+    pub in_body_len_padded_bytes: String,
+    /// The SHA-256 state after the body blocks the circuit skips
+    pub precomputed_sha: Vec<String>,
+    /// Where the bh= value starts in the header
+    pub body_hash_idx: String,
+    /// Where the sender's address starts in the header
+    pub email_from_idx: String,
+    /// Where the subject's command starts in the header
+    pub subject_idx: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub amount_idx: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub currency_idx: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub recipient_idx: Option<String>,
+    /// The salts (message ids) the sender's and recipient's wallets are derived with, zero padded
+    pub custom_message_id_from: Vec<String>,
+    pub custom_message_id_recipient: Vec<String>,
+    pub nonce: String,
+}
+
+/// Builds the circuit input of a verified email. The recipient salt is absent when the command has no recipient wallet.
+pub fn generate_circuit_input(
+    dkim: &VerifiedDkim,
+    sender_salt: &str,
+    recipient_salt: Option<&str>,
+    nonce: &str,
+    params: &CircuitParams,
+) -> Result<CircuitInput> {
+    let header = &dkim.canonicalized_header;
+    let (in_padded, header_len) = sha256_pad(header, params.max_header_bytes)
+        .map_err(|e| anyhow!("Header doesn't fit the circuit: {}", e))?;
+    let (in_body_padded, body_len, precomputed_sha) = precompute_body(&dkim.canonicalized_body, params.max_body_bytes);
+
+    let header_str = String::from_utf8_lossy(header);
+    let indices = HeaderIndices::find(&header_str)?;
+
+    Ok(CircuitInput {
+        in_padded: to_strings(&in_padded),
+        in_len_padded_bytes: header_len.to_string(),
+        modulus: to_chunks(&BigUint::from_bytes_be(&dkim.public_key.n().to_bytes_be()), params)?,
+        signature: to_chunks(&BigUint::from_bytes_be(&dkim.signature.signature), params)?,
+        in_body_padded: to_strings(&in_body_padded),
+        in_body_len_padded_bytes: body_len.to_string(),
+        precomputed_sha: to_strings(&precomputed_sha),
+        body_hash_idx: indices.body_hash.to_string(),
+        email_from_idx: indices.from.to_string(),
+        subject_idx: indices.subject.to_string(),
+        amount_idx: indices.amount.map(|idx| idx.to_string()),
+        currency_idx: indices.currency.map(|idx| idx.to_string()),
+        recipient_idx: indices.recipient.map(|idx| idx.to_string()),
+        custom_message_id_from: pad_salt(sender_salt)?,
+        custom_message_id_recipient: pad_salt(recipient_salt.unwrap_or_default())?,
+        nonce: nonce.to_string(),
+    })
+}
+
+/// Appends SHA-256 padding: 0x80, zeros, and the bit length, up to a multiple of 64 bytes.
+fn sha256_padding(data: &[u8]) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    padded
+}
+
+/// SHA-256 pads the data, then zero fills it to `max_len`. Returns the filled bytes and the padded length before filling.
+fn sha256_pad(data: &[u8], max_len: usize) -> Result<(Vec<u8>, usize)> {
+    let mut padded = sha256_padding(data);
+    let padded_len = padded.len();
+    if padded_len > max_len {
+        return Err(anyhow!("{} padded bytes is more than the max of {}", padded_len, max_len));
+    }
+    padded.resize(max_len, 0);
+    Ok((padded, padded_len))
+}
+
+/// Hashes as many leading 64-byte blocks of the padded body as needed for the rest to fit in `max_len`, so the circuit
+/// only hashes the remainder. Returns the zero filled remainder, its padded length, and the SHA-256 state after the skipped blocks.
+fn precompute_body(body: &[u8], max_len: usize) -> (Vec<u8>, usize, Vec<u8>) {
+    let padded = sha256_padding(body);
+    let padded_len = padded.len();
+    let skipped = padded_len.saturating_sub(max_len).div_ceil(64) * 64;
+
+    let mut state = SHA256_IV;
+    let blocks: Vec<_> = padded[..skipped].chunks(64).map(GenericArray::clone_from_slice).collect();
+    compress256(&mut state, &blocks);
+    let precomputed_sha = state.iter().flat_map(|word| word.to_be_bytes()).collect();
+
+    let mut remainder = padded[skipped..].to_vec();
+    let remainder_len = remainder.len();
+    remainder.resize(max_len, 0);
+    (remainder, remainder_len, precomputed_sha)
+}
+
+/// Splits a big integer into `bigint_k` little-endian chunks of `bigint_n` bits.
+fn to_chunks(value: &BigUint, params: &CircuitParams) -> Result<Vec<String>> {
+    if value.bits() as usize > params.bigint_n * params.bigint_k {
+        return Err(anyhow!("{} bit integer doesn't fit in {} chunks of {} bits", value.bits(), params.bigint_k, params.bigint_n));
+    }
+    let mask = (BigUint::from(1u8) << params.bigint_n) - 1u8;
+    Ok((0..params.bigint_k)
+        .map(|i| ((value >> (i * params.bigint_n)) & &mask).to_str_radix(10))
+        .collect())
+}
+
+fn pad_salt(salt: &str) -> Result<Vec<String>> {
+    if salt.len() > MAX_MESSAGE_ID_LEN {
+        return Err(anyhow!("Salt is longer than {} bytes", MAX_MESSAGE_ID_LEN));
+    }
+    let mut padded = salt.as_bytes().to_vec();
+    padded.resize(MAX_MESSAGE_ID_LEN, 0);
+    Ok(to_strings(&padded))
+}
+
+fn to_strings(bytes: &[u8]) -> Vec<String> {
+    bytes.iter().map(|byte| byte.to_string()).collect()
+}
+
+/// Byte offsets into the canonicalized header of the values the circuit reveals or checks.
+#[derive(Debug, PartialEq)]
+struct HeaderIndices {
+    body_hash: usize,
+    from: usize,
+    subject: usize,
+    amount: Option<usize>,
+    currency: Option<usize>,
+    recipient: Option<usize>,
+}
+
+impl HeaderIndices {
+    fn find(header: &str) -> Result<Self> {
+        let body_hash = header
+            .find("bh=")
+            .map(|idx| idx + "bh=".len())
+            .ok_or(anyhow!("Header has no bh= tag"))?;
+
+        let (from_start, from_value) = header_value(header, "from").ok_or(anyhow!("Header has no from field"))?;
+        let from = from_start + from_value.find('<').map_or(0, |idx| idx + 1);
+
+        let (subject, subject_value) = header_value(header, "subject").ok_or(anyhow!("Header has no subject field"))?;
+        let send_regex = regex::Regex::new(r"(?i)^(?:send|transfer) ?\$?(\d+(?:\.\d+)?) ([a-z]+) to (\S+@\S+)").unwrap();
+        // Only sends are proven, other commands have no values in the subject for the circuit to reveal
+        let (amount, currency, recipient) = match send_regex.captures(subject_value) {
+            Some(captures) => (captures.get(1), captures.get(2), captures.get(3)),
+            None => (None, None, None),
+        };
+        let offset = |capture: Option<regex::Match>| capture.map(|capture| subject + capture.start());
+
+        Ok(Self {
+            body_hash,
+            from,
+            subject,
+            amount: offset(amount),
+            currency: offset(currency),
+            recipient: offset(recipient),
+        })
+    }
+}
+
+/// Finds a field of a relaxed-canonicalized header ("name:value\r\n"), returning where its value starts and the value.
+fn header_value<'a>(header: &'a str, name: &str) -> Option<(usize, &'a str)> {
+    let prefix = format!("{}:", name);
+    let mut start = 0;
+    for line in header.split_inclusive("\r\n") {
+        if line.to_lowercase().starts_with(&prefix) {
+            let value_start = start + prefix.len();
+            let value = line[prefix.len()..].trim_end_matches("\r\n");
+            return Some((value_start, value));
+        }
+        start += line.len();
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dkim::{verify_dkim, StaticKeyResolver};
+    use sha2::{Digest, Sha256};
+
+    const FIXTURE_KEY: &str = "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQC5+ZkeOLLgpgWb+RO6y2k26tCBPbYQgT2NrRMY/AfI1VYW7VeGB1YWiLAWfLxBdDZQhaIjQ2qS5I5EXQYkZJAtdkWzyBEnx1V+lV88rGre5kyZQrwCRBSzhLSVe3Gj/CaK/zUMcB3bFJ47A2g1xu6799Y6CIDbHgnwgSisBLg6OQIDAQAB";
+
+    /// A fixture email signed with FIXTURE_KEY, and the reference input of the circuit for it with the production parameters.
+    /// The reference was computed independently of this module, following the circuits package's generate-input.ts (see proofgen.sh).
+    /// Regenerate it with that script and --nonce=fixture, never from this module.
+    struct Fixture {
+        eml: &'static str,
+        from: &'static str,
+        sender_salt: &'static str,
+        recipient_salt: Option<&'static str>,
+        reference: &'static str,
+    }
+
+    /// A send with a folded subject and a short body
+    const RELAXED: Fixture = Fixture {
+        eml: include_str!("../fixtures/dkim_relaxed.eml"),
+        from: "alice@example.com",
+        sender_salt: "<CAfixture0001@mail.example.com>",
+        recipient_salt: None,
+        reference: include_str!("../fixtures/dkim_relaxed.input.json"),
+    };
+
+    /// A send to a recipient wallet, with a body longer than the circuit hashes
+    const SEND: Fixture = Fixture {
+        eml: include_str!("../fixtures/dkim_send.eml"),
+        from: "dave@example.com",
+        sender_salt: "<CAfixture0002@mail.example.com>",
+        recipient_salt: Some("<CAfixture0002@mail.example.com>"),
+        reference: include_str!("../fixtures/dkim_send.input.json"),
+    };
+
+    async fn fixture_input(fixture: &Fixture) -> (VerifiedDkim, CircuitInput) {
+        let resolver = StaticKeyResolver::new().with_record("test._domainkey.example.com", FIXTURE_KEY);
+        let dkim = verify_dkim(fixture.eml, fixture.from, &resolver).await.unwrap();
+        let input = generate_circuit_input(&dkim, fixture.sender_salt, fixture.recipient_salt, "fixture", &CircuitParams::default()).unwrap();
+        (dkim, input)
+    }
+
+    #[tokio::test]
+    async fn test_circuit_input_matches_reference() {
+        for fixture in [RELAXED, SEND] {
+            let (_, input) = fixture_input(&fixture).await;
+            let reference: CircuitInput = serde_json::from_str(fixture.reference).unwrap();
+            assert_eq!(input, reference, "Circuit input of {}'s fixture drifted from generate-input.ts", fixture.from);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_circuit_input_indices() {
+        let (dkim, input) = fixture_input(&RELAXED).await;
+        let header = String::from_utf8_lossy(&dkim.canonicalized_header);
+        let at = |idx: &str| &header[idx.parse::<usize>().unwrap()..];
+        assert!(at(&input.email_from_idx).starts_with("alice@example.com>"));
+        assert!(at(&input.subject_idx).starts_with("Send 1 DAI to bob@example.org"));
+        assert!(at(input.amount_idx.as_ref().unwrap()).starts_with("1 DAI"));
+        assert!(at(input.currency_idx.as_ref().unwrap()).starts_with("DAI to"));
+        assert!(at(input.recipient_idx.as_ref().unwrap()).starts_with("bob@example.org"));
+        assert!(at(&input.body_hash_idx).starts_with("36GHsq"));
+
+        assert_eq!(input.in_padded.len(), 1024);
+        assert_eq!(input.in_body_padded.len(), 1536);
+        assert_eq!(input.modulus.len(), 17);
+        assert_eq!(input.signature.len(), 17);
+
+        let (dkim, input) = fixture_input(&SEND).await;
+        let header = String::from_utf8_lossy(&dkim.canonicalized_header);
+        let at = |idx: &str| &header[idx.parse::<usize>().unwrap()..];
+        assert!(at(&input.email_from_idx).starts_with("dave@example.com>"));
+        assert!(at(input.amount_idx.as_ref().unwrap()).starts_with("0.25 USDC"));
+        assert!(at(input.currency_idx.as_ref().unwrap()).starts_with("USDC to"));
+        assert!(at(input.recipient_idx.as_ref().unwrap()).starts_with("carol@example.net"));
+        assert_eq!(&input.custom_message_id_recipient[..2], &["60", "67"]);
+        // The body is longer than the circuit hashes, so its first blocks are precomputed
+        assert_ne!(input.precomputed_sha, to_strings(&SHA256_IV.iter().flat_map(|word| word.to_be_bytes()).collect::<Vec<u8>>()));
+    }
+
+    #[test]
+    fn test_precompute_body() {
+        let body = "a".repeat(200);
+        let (remainder, remainder_len, precomputed_sha) = precompute_body(body.as_bytes(), 128);
+        assert_eq!(remainder.len(), 128);
+        // 200 bytes pad to 256, of which the first two blocks are precomputed
+        assert_eq!(remainder_len, 128);
+
+        // Finishing the precomputed hash over the remainder gives the hash of the whole body
+        let words: Vec<u32> = precomputed_sha
+            .chunks(4)
+            .map(|word| u32::from_be_bytes(word.try_into().unwrap()))
+            .collect();
+        let mut state: [u32; 8] = words.try_into().unwrap();
+        let blocks: Vec<_> = remainder[..remainder_len].chunks(64).map(GenericArray::clone_from_slice).collect();
+        compress256(&mut state, &blocks);
+        let hash: Vec<u8> = state.iter().flat_map(|word| word.to_be_bytes()).collect();
+        assert_eq!(hash, Sha256::digest(body.as_bytes()).to_vec());
+    }
+
+    #[test]
+    fn test_sha256_pad_and_chunks() {
+        let (padded, len) = sha256_pad(b"abc", 128).unwrap();
+        assert_eq!(len, 64);
+        assert_eq!(padded.len(), 128);
+        assert_eq!(&padded[..4], &[b'a', b'b', b'c', 0x80]);
+        assert_eq!(padded[63], 24);
+        assert!(sha256_pad(&[0; 56], 64).is_err());
+
+        let params = CircuitParams {
+            bigint_n: 8,
+            bigint_k: 3,
+            ..CircuitParams::default()
+        };
+        assert_eq!(to_chunks(&BigUint::from(0x010203u32), &params).unwrap(), vec!["3", "2", "1"]);
+        assert!(to_chunks(&BigUint::from(0x01020304u32), &params).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+use crate::chain::TokenRegistry;
+use crate::chain_config::{ChainConfig, ChainRegistry};
+use crate::coordinator::{
+    prove_and_send, send_migrate, validate_balance, validate_migrate, validate_send, CommandValidation,
+};
+use crate::db::{JobState, Store};
+use crate::dkim::DkimKeyResolver;
+use crate::funds_monitor::FundsMonitor;
+use crate::parse_email::{
+    parse_subject_for_balance, parse_subject_for_migrate, parse_subject_for_send,
+    split_chain_suffix, MigrateDestination, ParsedEmail,
+};
+use crate::prover::Prover;
+use crate::smtp_client::EmailSenderClient;
+use crate::submitter::Submitters;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Everything a command needs from the email being processed.
+pub struct CommandContext<'a> {
+    pub email: &'a ParsedEmail,
+    pub emailer: &'a EmailSenderClient,
+    pub resolver: &'a dyn DkimKeyResolver,
+    pub chains: &'a ChainRegistry,
+    pub tokens: &'a TokenRegistry,
+    pub prover: &'a dyn Prover,
+    pub submitters: &'a Submitters,
+    pub store: &'a Store,
+    pub zk_email_circom_path: &'a str,
+}
+
+/// The services every email is processed with, built once when the relayer starts and shared by all of them.
+#[derive(Clone)]
+pub struct RelayerContext {
+    pub emailer: EmailSenderClient,
+    pub resolver: Arc<dyn DkimKeyResolver>,
+    pub registry: Arc<CommandRegistry>,
+    pub chains: ChainRegistry,
+    pub tokens: TokenRegistry,
+    pub prover: Arc<dyn Prover>,
+    pub submitters: Submitters,
+    pub funds: FundsMonitor,
+    pub store: Arc<Store>,
+    pub zk_email_circom_path: String,
+}
+
+impl RelayerContext {
+    /// The context commands run in for one email.
+    pub fn command_context<'a>(&'a self, email: &'a ParsedEmail) -> CommandContext<'a> {
+        CommandContext {
+            email,
+            emailer: &self.emailer,
+            resolver: self.resolver.as_ref(),
+            chains: &self.chains,
+            tokens: &self.tokens,
+            prover: self.prover.as_ref(),
+            submitters: &self.submitters,
+            store: self.store.as_ref(),
+            zk_email_circom_path: &self.zk_email_circom_path,
+        }
+    }
+}
+
+/// The arguments of a command, as parsed from the email subject.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParsedCommand {
+    Send {
+        amount: String,
+        currency: String,
+        recipient: String,
+    },
+    Balance {
+        token_name: Option<String>,
+    },
+    Migrate {
+        token_name: String,
+        destination: MigrateDestination,
+    },
+}
+
+/// An email command grammar. The registry tries `parse` on every incoming subject, minus any trailing
+/// "on <chain>"; the first command that matches is validated on the chosen chain (balances, salts,
+/// addresses, and the reply to send) and, once its validation is Ready, executed. Executing returns
+/// the final state of the email's job: Confirmed once it is done, or Failed. Commands that prove the email
+/// move its job through Proving, Proved and Submitted on the way.
+#[async_trait]
+pub trait Command: Send + Sync {
+    /// Short name used in logs.
+    fn name(&self) -> &'static str;
+    /// An example subject, listed to users whose subject matched no command.
+    fn format(&self) -> &'static str;
+    fn parse(&self, subject: &str) -> Option<ParsedCommand>;
+    async fn validate(
+        &self,
+        ctx: &CommandContext<'_>,
+        chain: &ChainConfig,
+        command: &ParsedCommand,
+    ) -> Result<CommandValidation>;
+    async fn execute(
+        &self,
+        ctx: &CommandContext<'_>,
+        command: &ParsedCommand,
+        validation: &CommandValidation,
+    ) -> Result<JobState>;
+}
+
+/// A subject that matched a registered command, along with the handler that parsed it.
+#[derive(Clone)]
+pub struct MatchedCommand {
+    pub handler: Arc<dyn Command>,
+    pub command: ParsedCommand,
+    /// The chain the subject named, if any
+    pub chain: Option<String>,
+}
+
+#[derive(Clone, Default)]
+pub struct CommandRegistry {
+    commands: Vec<Arc<dyn Command>>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The registry with every command the relayer supports, in the order they are tried.
+    pub fn with_default_commands() -> Self {
+        Self::new()
+            .with(Arc::new(SendCommand))
+            .with(Arc::new(BalanceCommand))
+            .with(Arc::new(MigrateCommand))
+    }
+
+    pub fn with(mut self, command: Arc<dyn Command>) -> Self {
+        self.commands.push(command);
+        self
+    }
+
+    pub fn parse(&self, subject: &str) -> Option<MatchedCommand> {
+        let (subject, chain) = split_chain_suffix(subject);
+        self.commands.iter().find_map(|handler| {
+            handler.parse(subject).map(|command| {
+                println!(
+                    "Subject matched the {} command: {:?}",
+                    handler.name(),
+                    command
+                );
+                MatchedCommand {
+                    handler: handler.clone(),
+                    command,
+                    chain: chain.clone(),
+                }
+            })
+        })
+    }
+
+    pub fn formats(&self) -> Vec<&'static str> {
+        self.commands
+            .iter()
+            .map(|command| command.format())
+            .collect()
+    }
+}
+
+pub struct SendCommand;
+
+#[async_trait]
+impl Command for SendCommand {
+    fn name(&self) -> &'static str {
+        "send"
+    }
+
+    fn format(&self) -> &'static str {
+        "Send _ DAI to __@__.___"
+    }
+
+    fn parse(&self, subject: &str) -> Option<ParsedCommand> {
+        let (amount, currency, recipient) = parse_subject_for_send(subject).ok()?;
+        Some(ParsedCommand::Send {
+            amount,
+            currency,
+            recipient,
+        })
+    }
+
+    async fn validate(
+        &self,
+        ctx: &CommandContext<'_>,
+        chain: &ChainConfig,
+        command: &ParsedCommand,
+    ) -> Result<CommandValidation> {
+        let ParsedCommand::Send {
+            amount,
+            currency,
+            recipient,
+        } = command
+        else {
+            return Err(anyhow!("Send command given {:?}", command));
+        };
+        validate_send(ctx.store, ctx.email, chain, ctx.tokens, amount, currency, recipient).await
+    }
+
+    async fn execute(
+        &self,
+        ctx: &CommandContext<'_>,
+        _command: &ParsedCommand,
+        validation: &CommandValidation,
+    ) -> Result<JobState> {
+        // Proves the email, then sends the transfer and waits for it to be confirmed or revert
+        prove_and_send(ctx, validation).await
+    }
+}
+
+pub struct BalanceCommand;
+
+#[async_trait]
+impl Command for BalanceCommand {
+    fn name(&self) -> &'static str {
+        "balance"
+    }
+
+    fn format(&self) -> &'static str {
+        "Balance"
+    }
+
+    fn parse(&self, subject: &str) -> Option<ParsedCommand> {
+        let token_name = parse_subject_for_balance(subject).ok()?;
+        Some(ParsedCommand::Balance { token_name })
+    }
+
+    async fn validate(
+        &self,
+        ctx: &CommandContext<'_>,
+        chain: &ChainConfig,
+        command: &ParsedCommand,
+    ) -> Result<CommandValidation> {
+        let ParsedCommand::Balance { token_name } = command else {
+            return Err(anyhow!("Balance command given {:?}", command));
+        };
+        validate_balance(ctx.store, ctx.email, chain, ctx.tokens, token_name.as_deref()).await
+    }
+
+    async fn execute(
+        &self,
+        _ctx: &CommandContext<'_>,
+        _command: &ParsedCommand,
+        _validation: &CommandValidation,
+    ) -> Result<JobState> {
+        // The balances were already sent in the validation reply, and nothing is proven or sent to chain
+        Ok(JobState::Confirmed)
+    }
+}
+
+pub struct MigrateCommand;
+
+#[async_trait]
+impl Command for MigrateCommand {
+    fn name(&self) -> &'static str {
+        "migrate"
+    }
+
+    fn format(&self) -> &'static str {
+        "Withdraw all DAI to 0x___ (or to __@__.___)"
+    }
+
+    fn parse(&self, subject: &str) -> Option<ParsedCommand> {
+        let (token_name, destination) = parse_subject_for_migrate(subject).ok()?;
+        Some(ParsedCommand::Migrate {
+            token_name,
+            destination,
+        })
+    }
+
+    async fn validate(
+        &self,
+        ctx: &CommandContext<'_>,
+        chain: &ChainConfig,
+        command: &ParsedCommand,
+    ) -> Result<CommandValidation> {
+        let ParsedCommand::Migrate {
+            token_name,
+            destination,
+        } = command
+        else {
+            return Err(anyhow!("Migrate command given {:?}", command));
+        };
+        validate_migrate(ctx.store, ctx.email, chain, ctx.tokens, token_name, destination).await
+    }
+
+    async fn execute(
+        &self,
+        ctx: &CommandContext<'_>,
+        command: &ParsedCommand,
+        validation: &CommandValidation,
+    ) -> Result<JobState> {
+        let ParsedCommand::Migrate {
+            token_name,
+            destination,
+        } = command
+        else {
+            return Err(anyhow!("Migrate command given {:?}", command));
+        };
+        send_migrate(ctx, validation, token_name, destination).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_registry_matches_commands() {
+        let registry = CommandRegistry::with_default_commands();
+        let matched = registry.parse("Send 1.5 DAI to bob@example.org").unwrap();
+        assert_eq!(matched.handler.name(), "send");
+        assert_eq!(
+            matched.command,
+            ParsedCommand::Send {
+                amount: "1.5".to_string(),
+                currency: "DAI".to_string(),
+                recipient: "bob@example.org".to_string(),
+            }
+        );
+        let matched = registry.parse("Balance USDC").unwrap();
+        assert_eq!(matched.handler.name(), "balance");
+        assert_eq!(
+            matched.command,
+            ParsedCommand::Balance {
+                token_name: Some("USDC".to_string())
+            }
+        );
+        let matched = registry.parse("Move all DAI to bob@example.org").unwrap();
+        assert_eq!(matched.handler.name(), "migrate");
+        let matched = registry
+            .parse("Send 5 USDC to a@b.com on Arbitrum")
+            .unwrap();
+        assert_eq!(matched.chain.as_deref(), Some("Arbitrum"));
+        assert_eq!(
+            matched.command,
+            ParsedCommand::Send {
+                amount: "5".to_string(),
+                currency: "USDC".to_string(),
+                recipient: "a@b.com".to_string(),
+            }
+        );
+        assert!(registry.parse("Email Wallet Manipulation 3").is_none());
+        assert_eq!(registry.formats().len(), 3);
+    }
+}
@@ -1,5 +1,12 @@
 pub const ZK_EMAIL_PATH_KEY: &'static str = "LOCAL_ZK_EMAIL_CIRCOM_PATH";
 pub const INCOMING_EML_PATH: &'static str = "INCOMING_EML_PATH";
+pub const CIRCUIT_NAME_KEY: &'static str = "CIRCUIT_NAME";
+pub const PROOF_DIR_KEY: &'static str = "PROOF_DIR";
+pub const DB_PATH_KEY: &'static str = "DB_PATH";
+pub const PROVER_KEY: &'static str = "PROVER";
+pub const RAPIDSNARK_PATH_KEY: &'static str = "RAPIDSNARK_PATH";
+pub const PROVER_URL_KEY: &'static str = "PROVER_URL";
+pub const VERIFICATION_KEY_PATH_KEY: &'static str = "VERIFICATION_KEY_PATH";
 
 pub const IMAP_DOMAIN_NAME_KEY: &'static str = "IMAP_DOMAIN_NAME";
 pub const IMAP_PORT_KEY: &'static str = "IMAP_PORT";
@@ -16,4 +23,14 @@ pub const SMTP_PORT_KEY: &'static str = "SMTP_PORT";
 
 pub const LOGIN_ID_KEY: &'static str = "LOGIN_ID";
 pub const LOGIN_PASSWORD_KEY: &'static str = "LOGIN_PASSWORD";
-pub const ETHERSCAN_KEY: &'static str = "ETHERSCAN_KEY";
\ No newline at end of file
+pub const ETHERSCAN_KEY: &'static str = "ETHERSCAN_KEY";
+
+pub const GAS_MAX_FEE_GWEI_KEY: &'static str = "GAS_MAX_FEE_GWEI";
+pub const GAS_MAX_PRIORITY_FEE_GWEI_KEY: &'static str = "GAS_MAX_PRIORITY_FEE_GWEI";
+pub const GAS_BUMP_PERCENT_KEY: &'static str = "GAS_BUMP_PERCENT";
+pub const GAS_STUCK_BLOCKS_KEY: &'static str = "GAS_STUCK_BLOCKS";
+pub const GAS_MAX_REPLACEMENTS_KEY: &'static str = "GAS_MAX_REPLACEMENTS";
+pub const TX_CONFIRMATIONS_KEY: &'static str = "TX_CONFIRMATIONS";
+pub const TOKEN_REFRESH_INTERVAL_KEY: &'static str = "TOKEN_REFRESH_INTERVAL_SECS";
+pub const TOKEN_NAMES_KEY: &'static str = "TOKEN_NAMES";
+pub const FUNDS_EXPIRY_KEY: &'static str = "FUNDS_EXPIRY_SECS";
\ No newline at end of file
@@ -5,9 +5,15 @@ use crate::config::{
 };
 // use crate::imap_client::{ImapClient, IMAPAuth};
 use crate::parse_email::*;
-use crate::chain::{query_address, query_balance};
+use crate::chain::{email_submitter, query_address, query_balance, query_nullifier_used, send_email_proof_to_chain, send_migrate_to_chain, TokenRegistry};
+use crate::chain_config::ChainConfig;
+use crate::amount::TokenAmount;
 use crate::smtp_client::EmailSenderClient;
-use crate::db::{get_or_store_salt};
+use crate::circuit_input::{generate_circuit_input, CircuitParams};
+use crate::dkim::verify_dkim;
+use crate::command::{CommandContext, CommandRegistry, MatchedCommand};
+use crate::db::{JobState, Store};
+use crate::prover::CircuitFiles;
 use crate::strings::*;
 use anyhow::{anyhow, Result};
 use arkworks_mimc::params::round_keys_contants_to_vec;
@@ -25,20 +31,17 @@ use ethers::core::types::{U256};
 // use ark_ff::{fields::Fp256, PrimeField};
 use ark_bn254::{Bn254, FrParameters, Fr};
 use dotenv::dotenv;
-use http::StatusCode;
 use std::future::Future;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use regex::Regex;
-use reqwest::Client;
 use std::string;
-use std::{
-    collections::hash_map::DefaultHasher,
-    env,
-    error::Error,
-    fs,
-    hash::{Hash, Hasher},
-};
+use sha2::{Digest, Sha256};
+use std::{error::Error, fs};
+
+/// The longest email address and message id (salt) the salt circuit takes, in bytes
+pub const MAX_EMAIL_LEN: usize = 31;
+pub const MAX_MESSAGE_ID_LEN: usize = 128;
 
 #[derive(Debug, Deserialize, Serialize)]
 struct EmailEvent {
@@ -48,24 +51,50 @@ struct EmailEvent {
     to: Option<String>,
 }
 
+#[derive(Clone, Debug)]
 pub struct BalanceRequest {
+    pub chain: ChainConfig,
     pub address: String,
     pub amount: String,
     pub token_name: String
 }
 
-/// Pending means we are monitoring the blockchain for a transaction to fill the wallet
-/// Ready means we have a transaction that has filled the wallet and we sent the tx and reply
-/// Failure means we have a transaction that has filled the wallet but we failed to send the tx and reply properly
-/// Unvalidated means we just saw the email and haven't processed it yet
+/// The outcome of validating a command: the new status, the reply to send, and what the command needs to execute.
+#[derive(Clone, Debug)]
+pub struct CommandValidation {
+    pub status: ValidationStatus,
+    pub reply: String,
+    pub salt_sender: Option<String>,
+    pub salt_receiver: Option<String>,
+    pub balance_request: Option<BalanceRequest>,
+}
+
+impl CommandValidation {
+    pub fn failure(reply: String) -> Self {
+        Self {
+            status: ValidationStatus::Failure,
+            reply,
+            salt_sender: None,
+            salt_receiver: None,
+            balance_request: None,
+        }
+    }
+}
+
+/// The outcome of validating a command. How far the command got executing is tracked by the email's job instead.
+/// Ready means the command can execute now
+/// Pending means it can execute once the sender's wallet holds enough of the token
+/// Failure means we failed to validate the command
 #[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum ValidationStatus {
     Ready,
     Failure,
     Pending,
-    Unvalidated
 }
 
+/// How many times an email is proven or sent from a saved proof, across restarts, before its job is failed
+pub const MAX_JOB_ATTEMPTS: u32 = 3;
+
 // Dummy future that does nothing
 struct DummyFuture;
 
@@ -77,54 +106,10 @@ impl Future for DummyFuture {
     }
 }
 
-// Used for Rust to automatically send to modal
-// However, I use the python coordinator for now since
-// this code needs to add aws s3 upload code to reach parity with that.
-pub async fn send_to_modal(raw_email: String, hash: u64) -> Result<()> {
-    // Path 2: Send to modal
-    // Construct the URL with query parameters
-    let webhook_url = format!(
-        "https://ziztuww--aayush-pull-and-prove-email.modal.run?aws_url={}&nonce={}",
-        urlencoding::encode(&raw_email),
-        hash
-    );
-
-    // Create a new reqwest client
-    let client = Client::new();
-
-    // Send the POST request
-    let response_result: Result<reqwest::Response, reqwest::Error> = client
-        .post(&webhook_url)
-        .header("Content-Type", "application/octet-stream")
-        .body(raw_email)
-        .send()
-        .await;
-    let response = response_result?;
-
-    // Check the status code of the response
-    match response.status() {
-        StatusCode::OK => {
-            // Read the response body
-            let response_body = response.text().await?;
-            // Handle the successful response (e.g., print the response body)
-            println!("Modal response: {}", response_body);
-        }
-        StatusCode::BAD_REQUEST => {
-            // Handle the bad request error (e.g., print an error message)
-            println!("Bad request to Modal");
-        }
-        _ => {
-            // Handle other status codes (e.g., print a generic error message)
-            println!("An error occurred on Modal...");
-        }
-    };
-    Ok(())
-}
-
-pub fn calculate_hash(raw_email: &String) -> String {
-    let mut hasher = DefaultHasher::new();
-    raw_email.hash(&mut hasher);
-    hasher.finish().to_string()
+/// The id of an email: the hex SHA-256 of the raw message. It keys the email in the database and names its proof files,
+/// so it has to stay the same across Rust releases, unlike DefaultHasher. `relayer migrate` rekeys emails stored by the old id.
+pub fn calculate_hash(raw_email: &str) -> String {
+    hex::encode(Sha256::digest(raw_email.as_bytes()))
 }
 
 pub async fn handle_email(raw_email: String, zk_email_circom_dir: &String, nonce: Option<String>) -> Result<()> {
@@ -135,14 +120,134 @@ pub async fn handle_email(raw_email: String, zk_email_circom_dir: &String, nonce
         None => calculate_hash(&raw_email),
     };
 
-    let file_path = format!("{}/wallet_{}.eml", "./received_eml", file_id);
-    match fs::write(file_path.clone(), raw_email.clone()) {
-        Ok(_) => println!("Email data written successfully to {}", file_path),
-        Err(e) => println!("Error writing data to file: {}", e),
-    }
+    let file_path = CircuitFiles::from_env(zk_email_circom_dir).eml_path(&file_id);
+    fs::create_dir_all(CircuitFiles::eml_dir())?;
+    fs::write(&file_path, raw_email).map_err(|e| anyhow!("Error writing email to {}: {}", file_path, e))?;
+    println!("Email data written successfully to {}", file_path);
     Ok(())
 }
 
+/// Proves an email with the configured prover and sends it to chain in-process, moving its job through Proving, Proved and
+/// Submitted, and returns the state to store once it's done: Confirmed, or Failed. The user is replied to either way.
+/// An email that was proven before a restart is sent with its saved proof, as long as that still loads and verifies.
+pub async fn prove_and_send(ctx: &CommandContext<'_>, validation: &CommandValidation) -> Result<JobState> {
+    let job = ctx.store.get_email_data_from_email(&ctx.email.raw)?.job;
+    let sender_nonce = match claim_sender_nonce(ctx).await? {
+        Ok(sender_nonce) => sender_nonce,
+        Err(replayed) => return Ok(replayed),
+    };
+    // Jobs keep the id their files were written with, which predates a rekey by `relayer migrate`
+    let nonce = job.file_id.unwrap_or_else(|| proof_file_id(validation, &ctx.email.raw, Some(sender_nonce)));
+    let saved_proof = match job.state {
+        JobState::Proved => match ctx.prover.load_proof(&nonce) {
+            Ok(calldata) => Some(calldata),
+            Err(e) => {
+                println!("Saved proof of email {} is unusable, proving it again: {}", nonce, e);
+                None
+            }
+        },
+        _ => None,
+    };
+
+    let calldata = match saved_proof {
+        Some(calldata) => {
+            println!("Resuming email {} from its saved proof", nonce);
+            calldata
+        }
+        None => {
+            handle_email(ctx.email.raw.clone(), &ctx.zk_email_circom_path.to_string(), Some(nonce.clone())).await?;
+            ctx.store.update_email_job(&ctx.email.raw, |job| {
+                job.transition(JobState::Proving);
+                job.file_id = Some(nonce.clone());
+            })?;
+
+            // The email was verified during validation, so this only fails if its DKIM key was rotated since
+            let dkim = verify_dkim(&ctx.email.raw, &ctx.email.from, ctx.resolver).await?;
+            let salt_sender = validation.salt_sender.as_deref().ok_or(anyhow!("Validation has no sender salt"))?;
+            let input = generate_circuit_input(&dkim, salt_sender, validation.salt_receiver.as_deref(), &nonce, &CircuitParams::default());
+
+            println!("Proving email {} with {}...", nonce, ctx.prover.name());
+            let proven = match input {
+                Ok(input) => ctx.prover.prove(&nonce, &input).await,
+                Err(e) => Err(e),
+            };
+            match proven {
+                Ok(calldata) => {
+                    ctx.store.update_email_state_with_raw_email(&ctx.email.raw, JobState::Proved)?;
+                    calldata
+                }
+                Err(e) => {
+                    println!("Proving email {} failed: {}", nonce, e);
+                    send_confirmation_email(ctx.email, &proof_failure_reply(), ctx.emailer).await;
+                    return Ok(JobState::Failed(format!("proving failed: {}", e)));
+                }
+            }
+        }
+    };
+
+    // The chain step moves the job to Submitted once it broadcasts, and replies with the transaction's outcome itself
+    match send_email_proof_to_chain(ctx.submitters, ctx.chains, ctx.emailer, ctx.email, &nonce, &calldata).await {
+        Ok(()) => {
+            use_sender_nonce(ctx.store, &ctx.email.from, sender_nonce);
+            Ok(JobState::Confirmed)
+        }
+        Err(e) => {
+            println!("Sending email {} to chain failed: {}", nonce, e);
+            Ok(JobState::Failed(format!("sending to chain failed: {}", e)))
+        }
+    }
+}
+
+/// Sends a migrate to chain, moving its job to Submitted, and returns the state to store once it's done: Confirmed, or Failed.
+/// Migrates aren't proven, since migrateAllToken takes no proof: they are only sent for emails that passed DKIM verification
+/// and whose nullifier is unused, which validate_email_envelope checked.
+pub async fn send_migrate(ctx: &CommandContext<'_>, validation: &CommandValidation, token_name: &str, destination: &MigrateDestination) -> Result<JobState> {
+    let job = ctx.store.get_email_data_from_email(&ctx.email.raw)?.job;
+    let sender_nonce = match claim_sender_nonce(ctx).await? {
+        Ok(sender_nonce) => sender_nonce,
+        Err(replayed) => return Ok(replayed),
+    };
+    let nonce = job.file_id.unwrap_or_else(|| proof_file_id(validation, &ctx.email.raw, Some(sender_nonce)));
+    // The chain step reads the email back by its file id, also when it is resumed at its submitted transaction
+    handle_email(ctx.email.raw.clone(), &ctx.zk_email_circom_path.to_string(), Some(nonce.clone())).await?;
+    ctx.store.update_email_job(&ctx.email.raw, |job| job.file_id = Some(nonce.clone()))?;
+
+    let submitter = email_submitter(ctx.submitters, ctx.chains, ctx.email)?;
+    match send_migrate_to_chain(submitter, ctx.emailer, &nonce, &ctx.email.from, validation, token_name, destination).await {
+        Ok(()) => {
+            use_sender_nonce(ctx.store, &ctx.email.from, sender_nonce);
+            Ok(JobState::Confirmed)
+        }
+        Err(e) => {
+            println!("Sending migrate {} to chain failed: {}", nonce, e);
+            Ok(JobState::Failed(format!("sending to chain failed: {}", e)))
+        }
+    }
+}
+
+/// Has a validated command claim the nonce its sender's account is at, which it keeps when resumed. A command whose nonce
+/// another command of the account already executed at is a replay of an older command: the sender is told, and the state
+/// to fail its job with is returned instead.
+async fn claim_sender_nonce(ctx: &CommandContext<'_>) -> Result<Result<u64, JobState>> {
+    let sender_nonce = ctx.store.claim_nonce(&ctx.email.raw, &ctx.email.from)?;
+    if ctx.store.is_stale_nonce(&ctx.email.from, sender_nonce)? {
+        println!("Email {} is a replay at nonce {} of {}", calculate_hash(&ctx.email.raw), sender_nonce, ctx.email.from);
+        send_confirmation_email(ctx.email, &replayed_reply(sender_nonce), ctx.emailer).await;
+        return Ok(Err(JobState::Failed(format!("replay of an older command at nonce {}", sender_nonce))));
+    }
+    Ok(Ok(sender_nonce))
+}
+
+/// Moves the sender's account on from the nonce a command executed at. The transaction is already out by then,
+/// so this only logs if another command of the account executed at the nonce first.
+pub fn use_sender_nonce(store: &Store, sender: &str, nonce: u64) {
+    match store.use_nonce(sender, nonce) {
+        Ok(true) => {}
+        Ok(false) => println!("Another command of {} executed at nonce {} first", sender, nonce),
+        Err(e) => println!("Could not use nonce {} of {}: {}", nonce, sender, e),
+    }
+}
+
 pub async fn calculate_decimal_salt(email_address: &str, message_id: &str) -> Result<String> {
     let mimc = MiMC::<Fr, MIMC_5_220_BN254_PARAMS>::new(
         1,
@@ -152,9 +257,7 @@ pub async fn calculate_decimal_salt(email_address: &str, message_id: &str) -> Re
 
     let email_arr = email_address.as_bytes();
     let message_id_arr = message_id.as_bytes();
-    
-    const MAX_EMAIL_LEN: usize = 31;
-    const MAX_MESSAGE_ID_LEN: usize = 128;
+
     let mut email_arr_32 = [0u8; MAX_EMAIL_LEN];
     let mut message_id_arr_32 = [0u8; MAX_MESSAGE_ID_LEN];
     if email_arr.len() > MAX_EMAIL_LEN || message_id_arr.len() > MAX_MESSAGE_ID_LEN {
@@ -183,104 +286,251 @@ pub async fn calculate_decimal_salt(email_address: &str, message_id: &str) -> Re
     Ok(decimal_salt)
 }
 
-pub async fn calculate_address(email_address: &str, message_id: &str) -> Result<String> {
+pub async fn calculate_address(chain: &ChainConfig, email_address: &str, message_id: &str) -> Result<String> {
     let decimal_salt = calculate_decimal_salt(email_address, message_id).await?;
     println!("Decimal salt: {}", decimal_salt);
-    let address_raw = query_address(false, decimal_salt.as_str()).await?;
+    let address_raw = query_address(chain, decimal_salt.as_str()).await?;
     println!("address_raw: {}", address_raw);
     let address = format!("0x{:x}", address_raw);
     println!("address: {}", address);
     Ok(address)
 }
 
-// Note: This function often mis-infers things, and gives weird subjects like "Subject:To;"
-pub async fn validate_email_infer(raw_email: &str, emailer: &EmailSenderClient, send_reply: Option<bool>) -> Result<(ValidationStatus, Option<String>, Option<String>, Option<BalanceRequest>)> {
-    let from = extract_from(raw_email).unwrap_or("".to_string());
-    let subject = extract_subject(raw_email).unwrap_or("".to_string());
-    validate_email_envelope(raw_email, emailer, "From", "Subject", send_reply).await
-}
-
-/// This function validates the email envelope by checking the subject and sender of the email.
-/// It uses regular expressions to match the subject to a specific pattern and extracts the necessary information.
-/// If the subject matches the pattern, it calculates the sender and recipient addresses and checks the sender's balance.
-/// Depending on the validation status, it sends a reply email and returns the validation status, sender salt, recipient salt, and balance request.
-pub async fn validate_email_envelope(raw_email: &str, emailer: &EmailSenderClient, from_str: &str, subject_str: &str, send_reply: Option<bool>) -> Result<(ValidationStatus, Option<String>, Option<String>, Option<BalanceRequest>)> {
-    let from = from_str.to_string();
-    let subject = subject_str.to_string();
+/// This function validates the email envelope by matching the subject against the registered commands.
+/// If a command matches, it verifies the DKIM signature and the message id, then lets the command validate itself
+/// (e.g. calculating the sender and recipient addresses and checking the sender's balance for a send).
+/// Depending on the validation status, it sends a reply email and returns the validation along with the matched command.
+pub async fn validate_email_envelope(ctx: &CommandContext<'_>, registry: &CommandRegistry, send_reply: Option<bool>) -> Result<(CommandValidation, Option<MatchedCommand>)> {
+    let email = ctx.email;
+    let emailer = ctx.emailer;
+    let from = email.from.clone();
     let send_reply = match send_reply {
         Some(value) => value,
         None => true,
     };
-    let mut custom_reply: String = "".to_string();
-    let mut valid: ValidationStatus = ValidationStatus::Pending;
-    let mut balance_request: Option<BalanceRequest> = None;
 
     // Validate subject, and send rejection/reformatting email if necessary
-    let result = parse_subject_for_send(subject.as_str());
-    let (amount, currency, recipient) = match result {
-        Ok((amt, cur, rec)) => (amt, cur, rec),
-        Err(_) => {
-            custom_reply = invalid_reply();
+    let matched = match registry.parse(email.subject.as_str()) {
+        Some(matched) => matched,
+        None => {
+            println!("Subject matched no command: {:?}", email.subject);
+            let custom_reply = invalid_reply(&registry.formats(), &ctx.tokens.names(ctx.chains.default_chain()));
             if send_reply {
-                send_confirmation_email(raw_email, &custom_reply, emailer).await;
+                send_confirmation_email(email, &custom_reply, emailer).await;
             }
-            return Ok((ValidationStatus::Failure, None, None, None));
+            return Ok((CommandValidation::failure(custom_reply), None));
         }
     };
 
-    let message_id_unwrapped = match extract_message_id(&raw_email) {
-        Ok(id) => Some(id),
-        Err(_) => None,
-    };
-
-    let message_id = match message_id_unwrapped {
-        Some(id) => id,
-        None => {
-            custom_reply = bad_message_id();
+    // Reject forged or modified emails before storing any salts for them
+    let dkim = match verify_dkim(&email.raw, from.as_str(), ctx.resolver).await {
+        Ok(dkim) => dkim,
+        Err(e) => {
+            println!("DKIM verification failed for email from {}: {}", from, e);
+            let custom_reply = dkim_failure_reply(&e.to_string());
             if send_reply {
-                send_confirmation_email(raw_email, &custom_reply, emailer).await;
+                send_confirmation_email(email, &custom_reply, emailer).await;
             }
-            return Ok((ValidationStatus::Failure, None, None, None));
+            return Ok((CommandValidation::failure(custom_reply), None));
+        }
+    };
+
+    if email.message_id.is_none() {
+        let custom_reply = bad_message_id();
+        if send_reply {
+            send_confirmation_email(email, &custom_reply, emailer).await;
+        }
+        return Ok((CommandValidation::failure(custom_reply), None));
+    }
+
+    let Some(chain) = ctx.chains.select(matched.chain.as_deref()) else {
+        let custom_reply = unsupported_chain_reply(matched.chain.as_deref().unwrap_or_default(), ctx.chains);
+        if send_reply {
+            send_confirmation_email(email, &custom_reply, emailer).await;
         }
+        return Ok((CommandValidation::failure(custom_reply), None));
     };
 
     println!(
-        "Subject, from, message id: {:?} {:?} {:?}",
-        subject, from, message_id
+        "Subject, from, message id, chain: {:?} {:?} {:?} {}",
+        email.subject, from, email.message_id, chain.name
     );
 
-    let (sender_salt_exists, sender_salt_raw) = get_or_store_salt(from.as_str(), message_id.as_str()).await.unwrap();
-    let (recipient_salt_exists, recipient_salt_raw) = get_or_store_salt(recipient.as_str(), message_id.as_str()).await.unwrap();
-    let sender_salt = Some(sender_salt_raw.clone());
-    let recipient_salt = Some(recipient_salt_raw.clone());
-    let sender_address = Some(calculate_address(from.as_str(), sender_salt_raw.as_str()).await.unwrap());
-    let recipient_address = calculate_address(recipient.as_str(), recipient_salt_raw.as_str()).await.unwrap();
-    custom_reply = pending_reply(sender_address.clone().unwrap().as_str(), &amount, &currency, &recipient).await;
-    valid = ValidationStatus::Pending;
-    
-    balance_request = Some(BalanceRequest {
-        address: sender_address.unwrap(),
-        amount: amount.to_string(),
-        token_name: currency.to_string(),
-    });
-
-    if ValidationStatus::Ready == valid {
-        println!("Send valid! Validating proof...");
-    } else if valid == ValidationStatus::Pending {  
-        println!("Send valid, waiting for funds...");
-    } else {
-        println!("Send invalid! Regex failed...");
+    // Don't prove an email again that was already executed, e.g. after a DB reset or a re-fetch of the same email
+    let nullifier = dkim.nullifier();
+    match query_nullifier_used(chain, nullifier).await {
+        Ok(true) => {
+            println!("Nullifier {} of email from {} is already used on {}", nullifier, from, chain.name);
+            let tx_hash = ctx
+                .store
+                .get_email_data_from_email(&email.raw)
+                .ok()
+                .and_then(|email_data| email_data.tx)
+                .filter(|outcome| outcome.chain_id == chain.id)
+                .and_then(|outcome| outcome.tx_hash.parse().ok());
+            let custom_reply = already_executed_reply(chain, tx_hash);
+            if send_reply {
+                send_confirmation_email(email, &custom_reply, emailer).await;
+            }
+            return Ok((CommandValidation::failure(custom_reply), None));
+        }
+        Ok(false) => {}
+        // The simulation before sending still catches a used nullifier
+        Err(e) => println!("Could not check nullifier {} on {}: {}", nullifier, chain.name, e),
     }
 
-    if send_reply {
-        send_confirmation_email(raw_email, &custom_reply, emailer).await;
+    let validation = matched.handler.validate(ctx, chain, &matched.command).await?;
+    if send_reply && !validation.reply.is_empty() {
+        send_confirmation_email(email, &validation.reply, emailer).await;
     }
 
-    return Ok((valid, sender_salt, recipient_salt, balance_request));
+    Ok((validation, Some(matched)))
+}
+
+/// This function validates a send: it calculates the sender and recipient addresses and checks the sender's balance.
+/// Sends always start as Pending, since they execute once the balance is detected.
+pub async fn validate_send(store: &Store, email: &ParsedEmail, chain: &ChainConfig, tokens: &TokenRegistry, amount: &str, currency: &str, recipient: &str) -> Result<CommandValidation> {
+    let from = email.from.as_str();
+    let message_id = email.message_id.clone().ok_or(anyhow!("Email has no message id"))?;
+    let Some(token) = tokens.resolve(chain, currency).await? else {
+        return Ok(CommandValidation::failure(unsupported_token_reply(currency, &tokens.names(chain))));
+    };
+
+    let (_sender_salt_exists, sender_salt_raw) = store.get_or_store_salt(from, message_id.as_str())?;
+    let (_recipient_salt_exists, recipient_salt_raw) = store.get_or_store_salt(recipient, message_id.as_str())?;
+    let sender_address = calculate_address(chain, from, sender_salt_raw.as_str()).await?;
+    // Computes the recipient's counterfactual wallet address, an eth_call of getOrCreateWallet that deploys nothing,
+    // so a salt the contract can't derive a wallet from fails here rather than on chain
+    calculate_address(chain, recipient, recipient_salt_raw.as_str()).await?;
+
+    let decimals = token.decimals;
+    let required = match TokenAmount::parse(amount, decimals) {
+        Ok(required) if !required.is_zero() => required,
+        _ => return Ok(CommandValidation::failure(invalid_amount_reply(amount, currency, decimals))),
+    };
+    let balance = match query_balance(chain, sender_address.as_str(), &token).await {
+        Ok(balance) => Some(balance),
+        Err(e) => {
+            println!("Error querying {} balance of {}: {}", currency, sender_address, e);
+            None
+        }
+    };
+    let custom_reply = pending_reply(chain, sender_address.as_str(), balance.as_ref(), &required, currency, recipient);
+    println!("Send valid, waiting for funds...");
+
+    Ok(CommandValidation {
+        status: ValidationStatus::Pending,
+        reply: custom_reply,
+        salt_sender: Some(sender_salt_raw),
+        salt_receiver: Some(recipient_salt_raw),
+        balance_request: Some(BalanceRequest {
+            chain: chain.clone(),
+            address: sender_address,
+            amount: amount.to_string(),
+            token_name: currency.to_string(),
+        }),
+    })
+}
+
+/// This function validates a balance query: it derives the sender's wallet and fetches the balance of each registered token,
+/// or only of the requested token. Balance queries are Ready right away since they only reply and never touch the chain state.
+pub async fn validate_balance(store: &Store, email: &ParsedEmail, chain: &ChainConfig, tokens: &TokenRegistry, token_name: Option<&str>) -> Result<CommandValidation> {
+    let from = email.from.as_str();
+    let message_id = email.message_id.clone().ok_or(anyhow!("Email has no message id"))?;
+    if let Some(token_name) = token_name {
+        if tokens.resolve(chain, token_name).await?.is_none() {
+            return Ok(CommandValidation::failure(unsupported_token_reply(token_name, &tokens.names(chain))));
+        }
+    }
+
+    let (_sender_salt_exists, sender_salt_raw) = store.get_or_store_salt(from, message_id.as_str())?;
+    let sender_address = calculate_address(chain, from, sender_salt_raw.as_str()).await?;
+    let token_names: Vec<String> = match token_name {
+        Some(token_name) => vec![token_name.to_string()],
+        None => tokens.names(chain),
+    };
+    let mut balances = vec![];
+    for token_name in token_names {
+        let Some(token) = tokens.get(chain, &token_name) else {
+            balances.push((token_name, None));
+            continue;
+        };
+        let balance = match query_balance(chain, sender_address.as_str(), &token).await {
+            Ok(balance) => Some(balance),
+            Err(e) => {
+                println!("Error querying {} balance of {}: {}", token_name, sender_address, e);
+                None
+            }
+        };
+        balances.push((token_name, balance));
+    }
+
+    Ok(CommandValidation {
+        status: ValidationStatus::Ready,
+        reply: balance_reply(chain, sender_address.as_str(), &balances),
+        salt_sender: None,
+        salt_receiver: None,
+        balance_request: None,
+    })
+}
+
+/// This function validates a migrate: it derives the sender's wallet (and the recipient's, when moving to an email)
+/// and checks that there is something to move. Migrates are Ready right away since they always move the whole balance.
+pub async fn validate_migrate(store: &Store, email: &ParsedEmail, chain: &ChainConfig, tokens: &TokenRegistry, token_name: &str, destination: &MigrateDestination) -> Result<CommandValidation> {
+    let from = email.from.as_str();
+    let message_id = email.message_id.clone().ok_or(anyhow!("Email has no message id"))?;
+    let Some(token) = tokens.resolve(chain, token_name).await? else {
+        return Ok(CommandValidation::failure(unsupported_token_reply(token_name, &tokens.names(chain))));
+    };
+
+    let (_sender_salt_exists, sender_salt_raw) = store.get_or_store_salt(from, message_id.as_str())?;
+    let sender_address = calculate_address(chain, from, sender_salt_raw.as_str()).await?;
+    let recipient_salt = match destination {
+        MigrateDestination::Email(recipient) => {
+            let (_recipient_salt_exists, recipient_salt_raw) = store.get_or_store_salt(recipient, message_id.as_str())?;
+            // Computes the recipient's counterfactual wallet address, an eth_call that deploys nothing
+            calculate_address(chain, recipient, recipient_salt_raw.as_str()).await?;
+            Some(recipient_salt_raw)
+        }
+        MigrateDestination::Wallet(_) => None,
+    };
+
+    let balance = query_balance(chain, sender_address.as_str(), &token).await?;
+    if balance.is_zero() {
+        return Ok(CommandValidation::failure(migrate_empty_reply(sender_address.as_str(), token_name)));
+    }
+    println!("Migrate valid, moving {} {}", balance, token_name);
+
+    Ok(CommandValidation {
+        status: ValidationStatus::Ready,
+        reply: migrate_reply(chain, sender_address.as_str(), &balance, token_name, destination),
+        salt_sender: Some(sender_salt_raw),
+        salt_receiver: recipient_salt,
+        balance_request: None,
+    })
+}
+
+/// The id used to name the eml, input, and proof files of an email.
+/// This nonce is usually (from_message_id)_(to_message_id)_(hash), but absent of that is the hash,
+/// followed by _(sender nonce) for commands that claimed a nonce of the sender's account to execute at
+pub fn proof_file_id(validation: &CommandValidation, raw_email: &str, sender_nonce: Option<u64>) -> String {
+    let file_id = match (&validation.salt_sender, &validation.salt_receiver) {
+        (Some(salt_sender), Some(salt_receiver)) => format!(
+            "({})_({})_({})",
+            salt_sender,
+            salt_receiver,
+            calculate_hash(raw_email)
+        ),
+        _ => calculate_hash(raw_email),
+    };
+    match sender_nonce {
+        Some(sender_nonce) => format!("{}_({})", file_id, sender_nonce),
+        None => file_id,
+    }
 }
 
-async fn send_confirmation_email(raw_email: &str, custom_reply: &str, emailer: &EmailSenderClient) -> () {
-    let confirmation: std::result::Result<(), Box<dyn Error>> = emailer.reply_all(raw_email, custom_reply, false);
+async fn send_confirmation_email(email: &ParsedEmail, custom_reply: &str, emailer: &EmailSenderClient) {
+    let confirmation: std::result::Result<(), Box<dyn Error>> = emailer.reply_all(email, custom_reply, false);
     match confirmation {
         Ok(_) => println!("Confirmation email sent successfully."),
         Err(e) => println!("Error sending confirmation email: {}", e),
@@ -306,7 +556,9 @@ mod tests {
         println!("decimal_salt: {}", decimal_salt);
         assert!(decimal_salt == "11578046119786885486589898473893761816011340408005885677852497807442621066251", "Decimal salt is incorrect");
         
-        let result_address = calculate_address(email_address, message_id).await;
+        dotenv().ok();
+        let chains = crate::chain_config::ChainRegistry::from_env()?;
+        let result_address = calculate_address(chains.default_chain(), email_address, message_id).await;
         match result_address {
             Ok(_) => (),
             Err(e) => return Err(anyhow!("Failed to calculate address: {}", e)),
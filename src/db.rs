@@ -1,220 +1,821 @@
-
-use sled;
-use sled::{Db, Error};
-use crate::coordinator::{ValidationStatus, calculate_hash};
-use serde::{Serialize, Deserialize};
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::{Db, Transactional, Tree};
+use crate::coordinator::calculate_hash;
+use crate::chain::TransferInfo;
+use crate::config::DB_PATH_KEY;
+use crate::gas::TxReplacement;
+use ethers::core::types::{H160, U256};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use anyhow::{anyhow, Result};
 
-/// The `get_db` function attempts to open a database at the given path.
-/// If the database cannot be opened, it will retry with an exponential backoff strategy.
-/// The backoff starts at 1 second and doubles after each failed attempt, up to 8 seconds.
-/// After reaching 8 seconds, the backoff increases linearly by 1 second after each failed attempt.
-/// The function gives up and returns an error if the database cannot be opened after 15 seconds.
-// TODO: This is very blocking and not concurrency friendly. Implement less blocking db.
-
-pub fn get_db(path: &str) -> Result<Db, Error> {
-    let mut db = sled::open(path);
-    let mut backoff = 1;
-    while db.is_err() {
-        // Give up
-        if (backoff == 15) {
-            return db;
-        }
-
-        println!("Backing off of db access for {} db with {} +- 1 seconds!", path, backoff);
-        let offset = rand::random::<f64>() * 2.0 - 1.0; // Random float between -1 and 1
-        let sleep_duration = backoff as f64 + offset;
-        std::thread::sleep(std::time::Duration::from_secs_f64(sleep_duration));
-        db = sled::open(path);
-
-        // Back off exponentially till 8 then linearly to 15, at which point we give up
-        if (backoff < 7) {
-            backoff *= 2; // Exponential backoff
-        } else {
-            backoff += 1;
+/// Where the relayer's database lives, unless DB_PATH is set
+pub const DEFAULT_DB_PATH: &str = "./db/relayer";
+
+/// A sled tree of JSON values keyed by strings.
+pub struct TypedTree<V> {
+    tree: Tree,
+    value: PhantomData<V>,
+}
+
+impl<V: Serialize + DeserializeOwned> TypedTree<V> {
+    fn open(db: &Db, name: &str) -> Result<Self> {
+        Ok(Self {
+            tree: db.open_tree(name)?,
+            value: PhantomData,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<V>> {
+        match self.tree.get(key)? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn insert(&self, key: &str, value: &V) -> Result<()> {
+        self.tree.insert(key, serde_json::to_vec(value)?)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.tree.remove(key)?;
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, V)>> {
+        self.tree.iter().map(|entry| {
+            let (key, value) = entry?;
+            Ok((String::from_utf8(key.to_vec())?, serde_json::from_slice(&value)?))
+        })
+    }
+
+    /// Stores the value under a key only if the key is free, and returns whichever value the key holds afterwards.
+    pub fn get_or_insert(&self, key: &str, value: &V) -> Result<V> {
+        match self.insert_new(key, value)? {
+            None => Ok(serde_json::from_slice(&serde_json::to_vec(value)?)?),
+            Some(current) => Ok(current),
+        }
+    }
+
+    /// Stores the value under a key only if the key is free. Returns None if it was stored, or the value the key already holds.
+    pub fn insert_new(&self, key: &str, value: &V) -> Result<Option<V>> {
+        match self.tree.compare_and_swap(key, None as Option<&[u8]>, Some(serde_json::to_vec(value)?))? {
+            Ok(()) => Ok(None),
+            Err(conflict) => match conflict.current {
+                Some(current) => Ok(Some(serde_json::from_slice(&current)?)),
+                None => Err(anyhow!("{} was removed while it was stored", key)),
+            },
+        }
+    }
+
+    /// Atomically replaces the value of a key with what `update` makes of it, or removes it if that is None.
+    /// If another write to the key lands first, `update` is applied again to the value that write stored, so no write is lost.
+    pub fn update<F: FnMut(Option<V>) -> Result<Option<V>>>(&self, key: &str, mut update: F) -> Result<Option<V>> {
+        loop {
+            let current = self.tree.get(key)?;
+            let value = match &current {
+                Some(bytes) => Some(serde_json::from_slice(bytes)?),
+                None => None,
+            };
+            let updated = update(value)?;
+            let bytes = match &updated {
+                Some(value) => Some(serde_json::to_vec(value)?),
+                None => None,
+            };
+            if self.tree.compare_and_swap(key, current, bytes)?.is_ok() {
+                return Ok(updated);
+            }
         }
     }
-    db
 }
 
-/// This function migrates everything in email_to_salt to email_to_salt_and_nonce where the nonce is 0.
-pub async fn migrate_email_dbs() -> Result<()> {
-    let db_old = match get_db("./db/email_to_salt") {
-        Ok(database) => database,
-        Err(e) => return Err(anyhow!("Failed to open old database: {}", e)),
-    };
-    let db_new = match get_db("./db/email_to_salt_and_nonce") {
-        Ok(database) => database,
-        Err(e) => return Err(anyhow!("Failed to open new database: {}", e)),
-    };
+/// The relayer's database, opened once at startup and shared by everything that reads or writes state.
+/// Each kind of record lives in its own tree:
+/// salts maps an email address to the salt of its wallet, for wallets created before they had nonces,
+/// emails maps an email id (see calculate_hash) to the email and its job,
+/// relayer_nonces maps a relayer account on a chain, as (chain id)_(address), to the next nonce it will send with,
+/// wallets maps an email address to the salt of its wallet and the nonce its next command executes at.
+pub struct Store {
+    db: Db,
+    pub salts: TypedTree<String>,
+    pub emails: TypedTree<EmailData>,
+    pub relayer_nonces: TypedTree<U256>,
+    pub wallets: TypedTree<(String, u64)>,
+}
 
-    for result in db_old.iter() {
-        let (email, salt) = result?;
-        let email_str = String::from_utf8(email.to_vec())?;
-        let salt_str: String = serde_json::from_slice(&salt)?;
-        let value = serde_json::to_vec(&(salt_str, 0))?;
-        db_new.insert(email_str, value)?;
+impl Store {
+    /// Opens the database at DB_PATH in a blocking task, so the runtime never waits on the disk.
+    pub async fn open_from_env() -> Result<Arc<Self>> {
+        let path = std::env::var(DB_PATH_KEY).unwrap_or(DEFAULT_DB_PATH.to_string());
+        let store = tokio::task::spawn_blocking(move || Self::open(&path)).await??;
+        Ok(Arc::new(store))
     }
 
-    Ok(())
+    /// Opens the database at a path, importing the databases earlier versions kept in a directory each next to it,
+    /// e.g. ./db/email_statuses for the default ./db/relayer.
+    pub fn open(path: &str) -> Result<Self> {
+        let store = Self::with_db(sled::open(path).map_err(|e| anyhow!("Failed to open database at {}: {}", path, e))?)?;
+        store.import_legacy_dbs(&legacy_db_dir(path))?;
+        Ok(store)
+    }
+
+    /// A database that is deleted once dropped.
+    pub fn temporary() -> Result<Self> {
+        Self::with_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn with_db(db: Db) -> Result<Self> {
+        Ok(Self {
+            salts: TypedTree::open(&db, "salts")?,
+            emails: TypedTree::open(&db, "emails")?,
+            relayer_nonces: TypedTree::open(&db, "relayer_nonces")?,
+            wallets: TypedTree::open(&db, "wallets")?,
+            db,
+        })
+    }
+
+    /// Persists every write so far.
+    pub async fn flush(&self) -> Result<()> {
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    /// Copies each legacy database into its tree, as long as the tree is still empty, so this only happens once.
+    /// Imported emails are rekeyed by their SHA-256 id, so they are found by the id the relayer looks them up with.
+    fn import_legacy_dbs(&self, dir: &str) -> Result<()> {
+        // Salts were stored as raw strings instead of JSON
+        import_legacy_db(&self.salts, &Path::new(dir).join("email_to_salt"), |salt| {
+            Ok(serde_json::to_vec(std::str::from_utf8(salt)?)?)
+        })?;
+        import_legacy_db(&self.emails, &Path::new(dir).join("email_statuses"), |value| Ok(value.to_vec()))?;
+        import_legacy_db(&self.relayer_nonces, &Path::new(dir).join("relayer_nonces"), |value| Ok(value.to_vec()))?;
+        import_legacy_db(&self.wallets, &Path::new(dir).join("email_to_salt_and_nonce"), |value| Ok(value.to_vec()))?;
+        self.move_stale_emails()?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// This function migrates everything in salts to wallets where the nonce is 0.
+    /// Wallets that already exist keep their salt and nonce, so running it again changes nothing. Returns how many were migrated.
+    pub async fn migrate_email_dbs(&self) -> Result<usize> {
+        let mut migrated = 0;
+        for entry in self.salts.iter() {
+            let (email, salt) = entry?;
+            if self.wallets.get(&email)?.is_none() {
+                self.wallets.get_or_insert(&email, &(salt, 0))?;
+                migrated += 1;
+            }
+        }
+        self.flush().await?;
+        Ok(migrated)
+    }
+
+    /// This function rekeys every email by its current id, the SHA-256 of its raw message.
+    /// Emails stored with the old DefaultHasher id are moved, so running it again changes nothing. Returns how many were moved.
+    pub async fn rekey_emails(&self) -> Result<usize> {
+        let moved = self.move_stale_emails()?;
+        self.flush().await?;
+        Ok(moved)
+    }
+
+    fn move_stale_emails(&self) -> Result<usize> {
+        let mut stale = vec![];
+        for entry in self.emails.iter() {
+            let (id, email_data) = entry?;
+            let email_hash = calculate_hash(&email_data.body);
+            if id != email_hash {
+                stale.push((id, email_hash, email_data));
+            }
+        }
+        for (id, email_hash, email_data) in &stale {
+            // An email already stored under its new id is newer than the stale copy
+            self.emails.get_or_insert(email_hash, email_data)?;
+            self.emails.remove(id)?;
+        }
+        Ok(stale.len())
+    }
+
+    /// This function retrieves the salt associated with an email address and message ID.
+    /// If the email exists in the database, it returns true and the salt as a string.
+    /// If the email is not found, it stores the message id and returns false and that as the salt string.
+    pub fn get_or_store_salt(&self, email: &str, message_id: &str) -> Result<(bool, String)> {
+        let (exists, salt, _nonce) = self.get_or_store_salt_and_nonce(email, message_id)?;
+        Ok((exists, salt))
+    }
+
+    /// This function retrieves the salt and nonce associated with an email address.
+    /// If the email exists in the database, it returns true, the salt, and the nonce.
+    /// If the email is not found, it stores the message id as the salt and a nonce of 0, and returns false, the salt, and the nonce.
+    /// Two emails from a new address can't both store their salt, the second one gets the salt of the first.
+    /// Wallets from before nonces existed keep their salt even if `relayer migrate` wasn't run, starting at nonce 0.
+    pub fn get_or_store_salt_and_nonce(&self, email: &str, message_id: &str) -> Result<(bool, String, u64)> {
+        if let Some((salt, nonce)) = self.wallets.get(email)? {
+            return Ok((true, salt, nonce));
+        }
+        let salt = self.salts.get(email)?.unwrap_or(message_id.to_string());
+        let (salt, nonce) = self.wallets.get_or_insert(email, &(salt, 0))?;
+        Ok((salt != message_id, salt, nonce))
+    }
+
+    /// This function has a validated email's job claim the nonce its sender's account is at, in one atomic transaction.
+    /// A job keeps the nonce it claimed, so one resumed after a restart is proven with the same file id.
+    /// The account only moves on to the next nonce once the command executed, see use_nonce.
+    pub fn claim_nonce(&self, raw_email: &str, sender: &str) -> Result<u64> {
+        let email_hash = calculate_hash(raw_email);
+        let claimed = (&self.wallets.tree, &self.emails.tree).transaction(|(wallets, emails)| {
+            let abort = |e: String| ConflictableTransactionError::Abort(e);
+            let email_value = emails.get(&email_hash)?.ok_or(abort("No value found for key".to_string()))?;
+            let mut email_data: EmailData = serde_json::from_slice(&email_value).map_err(|e| abort(e.to_string()))?;
+            if let Some(nonce) = email_data.job.nonce {
+                return Ok(nonce);
+            }
+            let wallet_value = wallets.get(sender)?.ok_or(abort(format!("{} has no wallet", sender)))?;
+            let (_salt, nonce): (String, u64) = serde_json::from_slice(&wallet_value).map_err(|e| abort(e.to_string()))?;
+            email_data.job.nonce = Some(nonce);
+            emails.insert(email_hash.as_bytes(), serde_json::to_vec(&email_data).map_err(|e| abort(e.to_string()))?)?;
+            Ok(nonce)
+        });
+        claimed.map_err(|e| match e {
+            TransactionError::Abort(e) => anyhow!(e),
+            TransactionError::Storage(e) => anyhow!(e),
+        })
+    }
+
+    /// This function returns whether a command at a nonce is a replay of an older command, i.e. the sender's account
+    /// already executed a command at that nonce.
+    pub fn is_stale_nonce(&self, sender: &str, nonce: u64) -> Result<bool> {
+        let (_salt, account_nonce) = self.wallets.get(sender)?.ok_or(anyhow!("{} has no wallet", sender))?;
+        Ok(nonce < account_nonce)
+    }
+
+    /// This function moves the sender's account to the next nonce once the command at `nonce` executed, as a compare-and-swap.
+    /// Returns false without changing anything if the account isn't at `nonce`, i.e. another command executed at it first.
+    pub fn use_nonce(&self, sender: &str, nonce: u64) -> Result<bool> {
+        let mut used = false;
+        self.wallets.update(sender, |wallet| {
+            let (salt, account_nonce) = wallet.ok_or(anyhow!("{} has no wallet", sender))?;
+            used = account_nonce == nonce;
+            Ok(Some((salt, if used { nonce + 1 } else { account_nonce })))
+        })?;
+        Ok(used)
+    }
 }
 
+/// The directory earlier versions kept their databases in, the one the database at `path` is in.
+fn legacy_db_dir(path: &str) -> String {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_string_lossy().to_string(),
+        _ => ".".to_string(),
+    }
+}
 
-/// This function retrieves the salt associated with an email address and message ID.
-/// If the email exists in the database, it returns true and the salt as a string.
-/// If the email is not found, it stores the message id and returns false and that as the salt string.
-pub async fn get_or_store_salt(email: &str, message_id: &str) -> Result<(bool, String)> {
-    let db = match get_db("./db/email_to_salt") {
-        Ok(database) => database,
-        Err(e) => return Err(anyhow!("Failed to open database: {}", e)),
-    };
-    let email_exists = db.get(email)?;
-    if let Some(salt) = email_exists {
-        Ok((true, std::str::from_utf8(&salt)?.to_string()))
-    } else {
-        db.insert(email, message_id)?;
-        Ok((false, message_id.to_string()))
-    }
-}
-
-/// This function retrieves the salt and nonce associated with an email address.
-/// If the email exists in the database, it returns true, the salt, and the nonce as a string.
-/// If the email is not found, it stores the message id as the salt and a nonce of 0, and returns false, the salt, and the nonce.
-/// If the increment_nonce argument is true, it increments the nonce by 1.
-// pub async fn get_or_store_salt_and_nonce(email: &str, message_id: &str, increment_nonce: bool) -> Result<(bool, String, u64)> {
-//     let db = match get_db("./db/email_to_salt_and_nonce") {
-//         Ok(database) => database,
-//         Err(e) => return Err(anyhow!("Failed to open database: {}", e)),
-//     };
-//     let email_exists = db.get(email)?;
-//     if let Some(value) = email_exists {
-//         let (salt, mut nonce): (String, u64) = serde_json::from_slice(&value)?;
-//         if increment_nonce {
-//             nonce += 1;
-//             let new_value = serde_json::to_vec(&(salt.clone(), nonce))?;
-//             db.insert(email, new_value)?;
-//         }
-//         Ok((true, salt, nonce))
-//     } else {
-
-//         let value = serde_json::to_vec(&(message_id.to_string(), 0))?;
-//         db.insert(email, value)?;
-//         Ok((false, message_id.to_string(), 0))
-//     }
-// }
+fn import_legacy_db<V, F>(tree: &TypedTree<V>, path: &Path, convert: F) -> Result<()>
+where
+    V: Serialize + DeserializeOwned,
+    F: Fn(&[u8]) -> Result<Vec<u8>>,
+{
+    if !tree.is_empty() || !path.exists() {
+        return Ok(());
+    }
+    let legacy = sled::open(path).map_err(|e| anyhow!("Failed to open legacy database {}: {}", path.display(), e))?;
+    for entry in legacy.iter() {
+        let (key, value) = entry?;
+        tree.tree.insert(key, convert(&value)?)?;
+    }
+    println!("Imported {} records from {}", tree.tree.len(), path.display());
+    Ok(())
+}
 
 
 /// Define the EmailData struct that the database will store.
 /// Raw email is the raw email body as a string (including headers)
 /// From is the raw sender email address
+/// Job is how far the relayer got executing the email, which is where it resumes after a restart
+/// Tx is the outcome of the email's transaction, once it has one
+/// Replacements are the re-broadcasts of the email's transaction with bumped fees, oldest first
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct EmailData {
     pub body: String,
     pub from: String,
     pub subject: String,
-    pub state: ValidationStatus,
-}
-
-/// This database maps ids (hashes) to a struct/JSON with raw emails, from email, subject, and validation status. 
-/// This function extracts and returns all emails that have a pending validation status.
-pub async fn get_pending_and_unvalidated_emails() -> Result<Vec<EmailData>> {
-    let db = match get_db("./db/email_statuses") {
-        Ok(database) => database,
-        Err(e) => return Err(anyhow!("Failed to open database: {}", e)),
-    };
-    let mut pending_emails = Vec::new();
-
-    for result in db.iter() {
-        let (_id, value) = result?;
-        let email_data: EmailData = serde_json::from_slice(&value)?;
-        if email_data.state == ValidationStatus::Pending || email_data.state == ValidationStatus::Unvalidated {
-            pending_emails.push(email_data);
-        }
-    }
-
-    Ok(pending_emails)
-}
-
-/// This function sets the email state given the raw email, from, subject, and state.
-/// It first opens the database, creates an EmailData object, serializes it, calculates the email hash, and then inserts it into the database.
-pub async fn set_email_state(raw_email: &str, from: &str, subject: &str, state: ValidationStatus) -> Result<()> {
-    let db = match get_db("./db/email_statuses") {
-        Ok(database) => database,
-        Err(e) => return Err(anyhow!("Failed to open database: {}", e)),
-    };
-    let email_data = EmailData {
-        body: raw_email.to_string(),
-        from: from.to_string(),
-        subject: subject.to_string(),
-        state,
-    };
-    let serialized_email_data = serde_json::to_vec(&email_data)?;
-    let email_hash = calculate_hash(&email_data.body);
-    db.insert(email_hash.as_bytes(), serialized_email_data)?;
-    Ok(())
+    #[serde(alias = "state", deserialize_with = "job_or_legacy_status")]
+    pub job: Job,
+    #[serde(default)]
+    pub tx: Option<TxOutcome>,
+    #[serde(default)]
+    pub replacements: Vec<TxReplacement>,
+    #[serde(default)]
+    pub simulation: Option<Simulation>,
 }
 
-/// This function retrieves the email data from the database given the email hash as the DB ID.
-pub async fn get_email_data(email_hash: &str) -> Result<EmailData> {
-    let db = match get_db("./db/email_statuses") {
-        Ok(database) => database,
-        Err(e) => return Err(anyhow!("Failed to open database: {}", e)),
-    };
-
-    let value = db.get(email_hash.as_bytes())?;
-    let value_bytes = match value {
-        Some(v) => v.clone().as_ref().to_vec(),
-        None => return Err(anyhow!("No value found for key")),
-    };
-    let email_data: EmailData = serde_json::from_slice(&value_bytes)?;
-    Ok(email_data)
-}
-
-/// This function retrieves the email data from the database given the email hash as the DB ID.
-pub async fn get_email_data_from_email(raw_email: &str) -> Result<EmailData> {
-    let db = match get_db("./db/email_statuses") {
-        Ok(database) => database,
-        Err(e) => return Err(anyhow!("Failed to open database: {}", e)),
-    };
-    
-    let email_hash = calculate_hash(&raw_email.to_string());
-    let value = db.get(email_hash.as_bytes())?;
-    let value_bytes = match value {
-        Some(v) => v.clone().as_ref().to_vec(),
-        None => return Err(anyhow!("No value found for key")),
-    };
-    let email_data: EmailData = serde_json::from_slice(&value_bytes)?;
-    Ok(email_data)
-}
-
-/// This function updates the email state given the raw email.
-/// It first retrieves the email data from the database, updates the state, and then reinserts it into the database.
-pub async fn update_email_state_with_raw_email(raw_email: &str, state: ValidationStatus) -> Result<()> {
-    let email_hash = calculate_hash(&raw_email.to_string());
-    let mut email_data = get_email_data(&email_hash).await?;
-
-    let db = match get_db("./db/email_statuses") {
-        Ok(database) => database,
-        Err(e) => return Err(anyhow!("Failed to open database: {}", e)),
-    };
-
-    email_data.state = state;
-    let serialized_email_data = serde_json::to_vec(&email_data)?;
-    let email_hash = calculate_hash(&raw_email.to_string());
-    db.insert(email_hash.as_bytes(), serialized_email_data)?;
-    Ok(())
+/// The stages of executing an email's command. Failed, Confirmed and Expired are final; every other state is resumed on restart.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub enum JobState {
+    /// Stored but not validated yet
+    Received,
+    /// Validated and ready to execute
+    Validated,
+    /// Validated, but waiting for the sender's wallet to hold enough of the token
+    AwaitingFunds,
+    /// Handed to the prover
+    Proving,
+    /// Proven, with the proof saved under the job's file id, but not sent yet
+    Proved,
+    /// Its transaction was broadcast, with the job's tx hash, and hasn't been mined yet
+    Submitted,
+    /// Done: its transaction was confirmed, or it never needed one
+    Confirmed,
+    /// Validation, proving or the transaction failed, for the given reason
+    Failed(String),
+    /// The sender's wallet never received the funds the command needed
+    Expired,
 }
 
-/// This function updates the email state given the email hash.
-/// It first retrieves the email data from the database, updates the state, and then reinserts it into the database.
-pub async fn update_email_state_with_hash(email_hash: &str, state: ValidationStatus) -> Result<()> {
-    let db = match get_db("./db/email_statuses") {
-        Ok(database) => database,
-        Err(e) => return Err(anyhow!("Failed to open database: {}", e)),
-    };
-    let mut email_data = get_email_data(&email_hash).await?;
-    email_data.state = state;
-    let serialized_email_data = serde_json::to_vec(&email_data)?;
-    db.insert(email_hash.as_bytes(), serialized_email_data)?;
-    Ok(())
+impl JobState {
+    pub fn is_final(&self) -> bool {
+        matches!(self, JobState::Confirmed | JobState::Failed(_) | JobState::Expired)
+    }
+}
+
+/// A state a job was in and left.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct JobTransition {
+    pub state: JobState,
+    /// When the job entered the state, in seconds since the epoch
+    pub at: u64,
+    pub attempts: u32,
+}
+
+/// Where executing an email's command is at, with what each stage left behind.
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub state: JobState,
+    pub created_at: u64,
+    /// When the job entered its current state, in seconds since the epoch
+    pub updated_at: u64,
+    /// How many times the job entered its current state, e.g. proving again after a restart
+    pub attempts: u32,
+    /// The id the email's eml and proof files are saved under, once it is being proven
+    pub file_id: Option<String>,
+    pub chain_id: Option<u64>,
+    /// The hash of the latest broadcast of the job's transaction, once it was submitted
+    pub tx_hash: Option<String>,
+    /// The sender's account nonce the job executes at, once it claimed it after validation
+    #[serde(default)]
+    pub nonce: Option<u64>,
+    /// The states the job was in before its current one, oldest first
+    pub history: Vec<JobTransition>,
+}
+
+impl Job {
+    pub fn new(state: JobState) -> Self {
+        let now = unix_time();
+        Self {
+            state,
+            created_at: now,
+            updated_at: now,
+            attempts: 1,
+            file_id: None,
+            chain_id: None,
+            tx_hash: None,
+            nonce: None,
+            history: vec![],
+        }
+    }
+
+    /// Moves the job to a state. Entering the state it is already in counts as another attempt at it.
+    pub fn transition(&mut self, state: JobState) {
+        let now = unix_time();
+        if self.state == state {
+            self.attempts += 1;
+        } else {
+            let previous = std::mem::replace(&mut self.state, state);
+            self.history.push(JobTransition {
+                state: previous,
+                at: self.updated_at,
+                attempts: self.attempts,
+            });
+            self.attempts = 1;
+        }
+        self.updated_at = now;
+    }
+
+    /// The job of an email stored before jobs existed, from its old validation status.
+    fn from_legacy_status(status: LegacyStatus) -> Self {
+        Self::new(match status {
+            LegacyStatus::Unvalidated => JobState::Received,
+            LegacyStatus::Pending => JobState::AwaitingFunds,
+            LegacyStatus::Executing => JobState::Proving,
+            LegacyStatus::Ready => JobState::Confirmed,
+            LegacyStatus::Failure => JobState::Failed("failed before jobs were tracked".to_string()),
+        })
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default()
 }
+
+/// The status emails were stored with before jobs existed.
+#[derive(Deserialize)]
+enum LegacyStatus {
+    Ready,
+    Failure,
+    Pending,
+    Executing,
+    Unvalidated,
+}
+
+fn job_or_legacy_status<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Job, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredJob {
+        Job(Job),
+        Legacy(LegacyStatus),
+    }
+    Ok(match StoredJob::deserialize(deserializer)? {
+        StoredJob::Job(job) => job,
+        StoredJob::Legacy(status) => Job::from_legacy_status(status),
+    })
+}
+
+/// How an email's transaction ended: confirmed, reverted on chain, or never mined.
+#[derive(PartialEq, Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum TxStatus {
+    Confirmed,
+    Reverted,
+    Dropped,
+}
+
+/// The final outcome of the transaction sent for an email, including the transfers it emitted.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TxOutcome {
+    pub chain_id: u64,
+    pub tx_hash: String,
+    pub status: TxStatus,
+    pub block_number: Option<u64>,
+    pub revert_reason: Option<String>,
+    pub transfers: Vec<TransferInfo>,
+}
+
+/// The result of simulating an email's call with eth_call and estimating its gas, before anything was broadcast.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Simulation {
+    pub chain_id: u64,
+    pub block_number: u64,
+    pub passed: bool,
+    pub gas_estimate: Option<U256>,
+    pub revert_reason: Option<String>,
+}
+
+
+impl Store {
+    /// This function extracts and returns all emails whose job isn't finished, to resume them from their current state.
+    pub fn get_unfinished_emails(&self) -> Result<Vec<EmailData>> {
+        let mut unfinished_emails = Vec::new();
+        for entry in self.emails.iter() {
+            let (_id, email_data) = entry?;
+            if !email_data.job.state.is_final() {
+                unfinished_emails.push(email_data);
+            }
+        }
+        Ok(unfinished_emails)
+    }
+
+    /// This function sets the email state given the raw email, from, subject, and state.
+    /// An email that is already stored keeps its job, which is moved to the state, along with the simulation,
+    /// outcome and replacements of any transaction already sent for it.
+    pub fn set_email_state(&self, raw_email: &str, from: &str, subject: &str, state: JobState) -> Result<()> {
+        self.emails.update(&calculate_hash(raw_email), |existing| {
+            let email_data = match existing {
+                Some(mut existing) => {
+                    existing.job.transition(state.clone());
+                    EmailData {
+                        body: raw_email.to_string(),
+                        from: from.to_string(),
+                        subject: subject.to_string(),
+                        ..existing
+                    }
+                }
+                None => EmailData {
+                    body: raw_email.to_string(),
+                    from: from.to_string(),
+                    subject: subject.to_string(),
+                    job: Job::new(state.clone()),
+                    tx: None,
+                    replacements: vec![],
+                    simulation: None,
+                },
+            };
+            Ok(Some(email_data))
+        })?;
+        Ok(())
+    }
+
+    /// This function stores a received email as Received, unless an email with its id is already stored.
+    /// It returns whether the email is new, and the email data stored for it, whose job a fetched again email resumes.
+    /// Nothing about the sender's account is stored here, since the email wasn't verified yet.
+    pub fn receive_email(&self, raw_email: &str, from: &str, subject: &str) -> Result<(bool, EmailData)> {
+        let received = EmailData {
+            body: raw_email.to_string(),
+            from: from.to_string(),
+            subject: subject.to_string(),
+            job: Job::new(JobState::Received),
+            tx: None,
+            replacements: vec![],
+            simulation: None,
+        };
+        match self.emails.insert_new(&calculate_hash(raw_email), &received)? {
+            None => Ok((true, received)),
+            Some(stored) => Ok((false, stored)),
+        }
+    }
+
+    /// This function retrieves the email data from the database given the email hash as the DB ID.
+    pub fn get_email_data(&self, email_hash: &str) -> Result<EmailData> {
+        self.emails.get(email_hash)?.ok_or(anyhow!("No value found for key"))
+    }
+
+    /// This function retrieves the email data from the database given the raw email.
+    pub fn get_email_data_from_email(&self, raw_email: &str) -> Result<EmailData> {
+        self.get_email_data(&calculate_hash(raw_email))
+    }
+
+    /// This function updates an email that is already stored given the email hash.
+    /// The update is atomic, and applied again if another write to the email landed first.
+    pub fn update_email<F: FnMut(&mut EmailData)>(&self, email_hash: &str, mut update: F) -> Result<()> {
+        self.emails.update(email_hash, |email_data| {
+            let mut email_data = email_data.ok_or(anyhow!("No value found for key"))?;
+            update(&mut email_data);
+            Ok(Some(email_data))
+        })?;
+        Ok(())
+    }
+
+    /// This function updates the email state given the raw email.
+    pub fn update_email_state_with_raw_email(&self, raw_email: &str, state: JobState) -> Result<()> {
+        self.update_email_state_with_hash(&calculate_hash(raw_email), state)
+    }
+
+    /// This function updates the email state given the email hash.
+    pub fn update_email_state_with_hash(&self, email_hash: &str, state: JobState) -> Result<()> {
+        self.update_email(email_hash, |email_data| email_data.job.transition(state.clone()))
+    }
+
+    /// This function moves the email's job to a state only if it is still in one of the expected states, atomically,
+    /// so two tasks resuming the same email can't both move it on. Returns whether the job was moved.
+    pub fn transition_email_state(&self, raw_email: &str, expected: &[JobState], state: JobState) -> Result<bool> {
+        let mut moved = false;
+        self.update_email(&calculate_hash(raw_email), |email_data| {
+            moved = expected.contains(&email_data.job.state);
+            if moved {
+                email_data.job.transition(state.clone());
+            }
+        })?;
+        Ok(moved)
+    }
+
+    /// This function updates the job of an email given the raw email, e.g. to move it to a state along with what that state carries.
+    pub fn update_email_job<F: FnMut(&mut Job)>(&self, raw_email: &str, mut update: F) -> Result<()> {
+        self.update_email(&calculate_hash(raw_email), |email_data| update(&mut email_data.job))
+    }
+
+    /// This function stores the outcome of an email's transaction given the raw email.
+    /// The job's state is stored separately, by whatever executed the email.
+    pub fn set_email_tx_outcome(&self, raw_email: &str, outcome: TxOutcome) -> Result<()> {
+        self.update_email(&calculate_hash(raw_email), |email_data| email_data.tx = Some(outcome.clone()))
+    }
+
+    /// This function stores the simulation of an email's call given the raw email.
+    pub fn set_email_simulation(&self, raw_email: &str, simulation: Simulation) -> Result<()> {
+        self.update_email(&calculate_hash(raw_email), |email_data| email_data.simulation = Some(simulation.clone()))
+    }
+
+    /// This function records that an email's transaction was re-broadcast with bumped fees, given the raw email.
+    /// The job follows the replacement, so it is the one waited for if the relayer restarts.
+    pub fn record_tx_replacement(&self, raw_email: &str, replacement: TxReplacement) -> Result<()> {
+        self.update_email(&calculate_hash(raw_email), |email_data| {
+            email_data.job.tx_hash = Some(format!("0x{:x}", replacement.tx_hash));
+            email_data.replacements.push(replacement.clone());
+        })
+    }
+
+    /// This function resets the next nonce of an account to its pending transaction count on chain, dropping nonces that were
+    /// reserved but never broadcast and skipping nonces used outside the relayer. It returns the next nonce.
+    pub async fn reconcile_nonce(&self, chain_id: u64, address: &H160, pending_count: U256) -> Result<U256> {
+        let key = nonce_key(chain_id, address);
+        if let Some(stored) = self.relayer_nonces.get(&key)? {
+            if stored != pending_count {
+                println!("Reconciling nonce of {} from {} to the pending count {}", key, stored, pending_count);
+            }
+        }
+        self.relayer_nonces.insert(&key, &pending_count)?;
+        // Persist before the nonce is used, so a crash can never hand it out twice
+        self.flush().await?;
+        Ok(pending_count)
+    }
+
+    /// This function reserves the next nonce of an account: the stored next nonce, or its pending transaction count if that is higher.
+    pub async fn reserve_nonce(&self, chain_id: u64, address: &H160, pending_count: U256) -> Result<U256> {
+        let mut nonce = U256::zero();
+        self.relayer_nonces.update(&nonce_key(chain_id, address), |stored| {
+            nonce = stored.unwrap_or_default().max(pending_count);
+            Ok(Some(nonce + 1))
+        })?;
+        self.flush().await?;
+        Ok(nonce)
+    }
+
+    /// This function hands back a reserved nonce whose transaction was never broadcast, unless a later nonce was reserved since.
+    pub async fn release_nonce(&self, chain_id: u64, address: &H160, nonce: U256) -> Result<()> {
+        self.relayer_nonces.update(&nonce_key(chain_id, address), |stored| {
+            Ok(match stored {
+                Some(stored) if stored == nonce + 1 => Some(nonce),
+                stored => stored,
+            })
+        })?;
+        self.flush().await
+    }
+}
+
+fn nonce_key(chain_id: u64, address: &H160) -> String {
+    format!("{}_{:?}", chain_id, address)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_job_transitions_and_legacy_status() {
+        let mut job = Job::new(JobState::Received);
+        job.transition(JobState::Validated);
+        job.transition(JobState::Proving);
+        job.transition(JobState::Proving);
+        assert_eq!(job.state, JobState::Proving);
+        assert_eq!(job.attempts, 2);
+        assert_eq!(
+            job.history.iter().map(|transition| transition.state.clone()).collect::<Vec<_>>(),
+            vec![JobState::Received, JobState::Validated]
+        );
+        job.transition(JobState::Failed("invalid proof".to_string()));
+        assert!(job.state.is_final());
+        assert_eq!(job.history.last().unwrap().attempts, 2);
+
+        let email_data = EmailData {
+            body: "body".to_string(),
+            from: "alice@example.com".to_string(),
+            subject: "Send 1 DAI to bob@example.com".to_string(),
+            job,
+            tx: None,
+            replacements: vec![],
+            simulation: None,
+        };
+        let stored: EmailData = serde_json::from_slice(&serde_json::to_vec(&email_data).unwrap()).unwrap();
+        assert_eq!(stored.job, email_data.job);
+
+        // Emails stored before jobs existed have a validation status instead
+        let legacy: EmailData =
+            serde_json::from_str(r#"{"body": "body", "from": "alice@example.com", "subject": "Balance", "state": "Pending"}"#).unwrap();
+        assert_eq!(legacy.job.state, JobState::AwaitingFunds);
+        let legacy: EmailData =
+            serde_json::from_str(r#"{"body": "body", "from": "alice@example.com", "subject": "Balance", "state": "Ready"}"#).unwrap();
+        assert!(legacy.job.state.is_final());
+    }
+
+    fn email_data(body: &str) -> EmailData {
+        EmailData {
+            body: body.to_string(),
+            from: "alice@example.com".to_string(),
+            subject: "Balance".to_string(),
+            job: Job::new(JobState::Received),
+            tx: None,
+            replacements: vec![],
+            simulation: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rekey_emails() {
+        let store = Store::temporary().unwrap();
+        store.emails.insert("12345678901234567890", &email_data("old")).unwrap();
+        store.emails.insert(&calculate_hash("new"), &email_data("new")).unwrap();
+
+        assert_eq!(store.rekey_emails().await.unwrap(), 1);
+        assert_eq!(store.rekey_emails().await.unwrap(), 0);
+        assert!(store.emails.get("12345678901234567890").unwrap().is_none());
+        assert_eq!(store.get_email_data_from_email("old").unwrap().body, "old");
+        assert_eq!(calculate_hash("old").len(), 64);
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let store = Store::temporary().unwrap();
+        assert_eq!(legacy_db_dir(DEFAULT_DB_PATH), "./db");
+        assert_eq!(legacy_db_dir("/var/relayer/db"), "/var/relayer");
+        assert_eq!(legacy_db_dir("relayer"), ".");
+        assert_eq!(store.get_or_store_salt("alice@example.com", "<1@example.com>").unwrap(), (false, "<1@example.com>".to_string()));
+        assert_eq!(store.get_or_store_salt("alice@example.com", "<2@example.com>").unwrap(), (true, "<1@example.com>".to_string()));
+
+        store.set_email_state("raw", "alice@example.com", "Balance", JobState::Received).unwrap();
+        let fundable = [JobState::Received, JobState::AwaitingFunds];
+        assert!(store.transition_email_state("raw", &fundable, JobState::Validated).unwrap());
+        assert!(!store.transition_email_state("raw", &fundable, JobState::Validated).unwrap());
+        assert_eq!(store.get_email_data_from_email("raw").unwrap().job.state, JobState::Validated);
+        assert!(store.update_email_state_with_raw_email("unknown", JobState::Validated).is_err());
+
+        // Fetching an email again resumes its job instead of starting over
+        let (new, received) = store.receive_email("fetched", "alice@example.com", "Balance").unwrap();
+        assert!(new);
+        assert_eq!(received.job.state, JobState::Received);
+        store.update_email_state_with_raw_email("fetched", JobState::Confirmed).unwrap();
+        let (new, stored) = store.receive_email("fetched", "alice@example.com", "Balance").unwrap();
+        assert!(!new);
+        assert_eq!(stored.job.state, JobState::Confirmed);
+
+        let address = H160::repeat_byte(1);
+        assert_eq!(store.reconcile_nonce(1, &address, 5.into()).await.unwrap(), 5.into());
+        assert_eq!(store.reserve_nonce(1, &address, 3.into()).await.unwrap(), 5.into());
+        assert_eq!(store.reserve_nonce(1, &address, 3.into()).await.unwrap(), 6.into());
+        // Only the latest reservation can be handed back
+        store.release_nonce(1, &address, 5.into()).await.unwrap();
+        store.release_nonce(1, &address, 6.into()).await.unwrap();
+        assert_eq!(store.reserve_nonce(1, &address, 3.into()).await.unwrap(), 6.into());
+    }
+
+    #[tokio::test]
+    async fn test_salt_and_nonce() {
+        let store = Store::temporary().unwrap();
+        // Wallets from before nonces keep their salt, whether or not they were migrated
+        store.salts.insert("alice@example.com", &"<0@example.com>".to_string()).unwrap();
+        store.salts.insert("bob@example.com", &"<1@example.com>".to_string()).unwrap();
+        assert_eq!(
+            store.get_or_store_salt_and_nonce("alice@example.com", "<2@example.com>").unwrap(),
+            (true, "<0@example.com>".to_string(), 0)
+        );
+        assert_eq!(store.migrate_email_dbs().await.unwrap(), 1);
+        assert_eq!(store.migrate_email_dbs().await.unwrap(), 0);
+
+        // Receiving an email stores nothing about its sender, who isn't verified yet
+        store.receive_email("carol", "carol@example.com", "Send 1 DAI to bob@example.com").unwrap();
+        assert_eq!(store.wallets.get("carol@example.com").unwrap(), None);
+        assert!(store.claim_nonce("carol", "carol@example.com").is_err());
+
+        // Two commands validated at once from one account claim the nonce it is at, and keep it when resumed
+        for email in ["first", "second", "funded"] {
+            store.receive_email(email, "alice@example.com", "Send 1 DAI to bob@example.com").unwrap();
+        }
+        assert_eq!(store.claim_nonce("first", "alice@example.com").unwrap(), 0);
+        assert_eq!(store.claim_nonce("second", "alice@example.com").unwrap(), 0);
+        assert_eq!(store.claim_nonce("first", "alice@example.com").unwrap(), 0);
+        assert_eq!(store.get_email_data_from_email("first").unwrap().job.nonce, Some(0));
+
+        // Only an executed command moves the account on, so the other is a replay of an older command
+        assert!(store.use_nonce("alice@example.com", 0).unwrap());
+        assert!(store.is_stale_nonce("alice@example.com", 0).unwrap());
+        assert!(!store.use_nonce("alice@example.com", 0).unwrap());
+        assert_eq!(store.wallets.get("alice@example.com").unwrap(), Some(("<0@example.com>".to_string(), 1)));
+
+        // A command that claims its nonce later, e.g. a send that waited for funds, executes at the next one
+        assert_eq!(store.claim_nonce("funded", "alice@example.com").unwrap(), 1);
+        assert!(!store.is_stale_nonce("alice@example.com", 1).unwrap());
+        assert!(store.use_nonce("alice@example.com", 1).unwrap());
+
+        // Migrating again never resets a nonce
+        assert_eq!(store.migrate_email_dbs().await.unwrap(), 0);
+        assert_eq!(store.get_or_store_salt_and_nonce("alice@example.com", "<3@example.com>").unwrap().2, 2);
+    }
+
+    #[test]
+    fn test_import_legacy_dbs() {
+        let dir = std::env::temp_dir().join("relayer_legacy_dbs_test");
+        let _ = std::fs::remove_dir_all(&dir);
+        {
+            let salts = sled::open(dir.join("email_to_salt")).unwrap();
+            salts.insert("alice@example.com", "<1@example.com>").unwrap();
+            let statuses = sled::open(dir.join("email_statuses")).unwrap();
+            statuses
+                .insert("1234", r#"{"body": "raw", "from": "alice@example.com", "subject": "Balance", "state": "Pending"}"#)
+                .unwrap();
+        }
+
+        let store = Store::temporary().unwrap();
+        store.import_legacy_dbs(&dir.to_string_lossy()).unwrap();
+        assert_eq!(store.salts.get("alice@example.com").unwrap(), Some("<1@example.com>".to_string()));
+        assert!(store.get_email_data("1234").is_err());
+        assert_eq!(store.get_email_data_from_email("raw").unwrap().job.state, JobState::AwaitingFunds);
+        // Trees that already have records aren't imported again
+        store.salts.insert("alice@example.com", &"<2@example.com>".to_string()).unwrap();
+        store.import_legacy_dbs(&dir.to_string_lossy()).unwrap();
+        assert_eq!(store.salts.get("alice@example.com").unwrap(), Some("<2@example.com>".to_string()));
+    }
+}
\ No newline at end of file
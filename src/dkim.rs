@@ -0,0 +1,592 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine as _};
+use ethers::core::types::U256;
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::{Pkcs1v15Sign, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// Looks up the DKIM TXT record published at `<selector>._domainkey.<domain>`.
+/// The DNS implementation is used in production; the static table lets tests verify emails offline.
+#[async_trait]
+pub trait DkimKeyResolver: Send + Sync {
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>>;
+}
+
+pub struct DnsKeyResolver {
+    resolver: TokioAsyncResolver,
+}
+
+impl DnsKeyResolver {
+    pub fn new() -> Result<Self> {
+        let resolver =
+            TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())?;
+        Ok(Self { resolver })
+    }
+}
+
+#[async_trait]
+impl DkimKeyResolver for DnsKeyResolver {
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>> {
+        let response = self.resolver.txt_lookup(name).await?;
+        let records = response
+            .iter()
+            .map(|txt| {
+                // Long keys are split over several character-strings, which must be concatenated
+                let data_bytes: Vec<u8> = txt
+                    .txt_data()
+                    .iter()
+                    .flat_map(|b| b.iter())
+                    .cloned()
+                    .collect();
+                String::from_utf8_lossy(&data_bytes).to_string()
+            })
+            .collect();
+        Ok(records)
+    }
+}
+
+/// A fixed table of `<selector>._domainkey.<domain>` -> TXT record, used to verify emails without DNS.
+#[derive(Default, Clone)]
+pub struct StaticKeyResolver {
+    records: HashMap<String, String>,
+}
+
+impl StaticKeyResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_record(mut self, name: &str, txt_record: &str) -> Self {
+        self.records.insert(
+            name.trim_end_matches('.').to_lowercase(),
+            txt_record.to_string(),
+        );
+        self
+    }
+}
+
+#[async_trait]
+impl DkimKeyResolver for StaticKeyResolver {
+    async fn lookup_txt(&self, name: &str) -> Result<Vec<String>> {
+        match self.records.get(&name.trim_end_matches('.').to_lowercase()) {
+            Some(record) => Ok(vec![record.clone()]),
+            None => Err(anyhow!("No TXT record for {}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DkimError {
+    MissingSignature,
+    MalformedSignature(String),
+    UnsupportedAlgorithm(String),
+    BodyHashMismatch,
+    KeyNotFound(String),
+    MalformedKey(String),
+    SignatureMismatch,
+    FromHeaderCount(usize),
+    /// Why each of an email's signatures failed, when it has more than one
+    NoValidSignature(Vec<DkimError>),
+    DomainMismatch {
+        from_domain: String,
+        signing_domain: String,
+    },
+}
+
+impl fmt::Display for DkimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DkimError::MissingSignature => write!(f, "email has no DKIM-Signature header"),
+            DkimError::MalformedSignature(reason) => {
+                write!(f, "malformed DKIM-Signature header: {}", reason)
+            }
+            DkimError::UnsupportedAlgorithm(algorithm) => {
+                write!(f, "unsupported DKIM algorithm {}", algorithm)
+            }
+            DkimError::BodyHashMismatch => {
+                write!(f, "body hash does not match the DKIM-Signature bh= tag")
+            }
+            DkimError::KeyNotFound(name) => write!(f, "no DKIM public key found at {}", name),
+            DkimError::MalformedKey(reason) => write!(f, "malformed DKIM public key: {}", reason),
+            DkimError::SignatureMismatch => {
+                write!(f, "RSA signature does not match the signed headers")
+            }
+            DkimError::FromHeaderCount(count) => {
+                write!(f, "email has {} From headers instead of one", count)
+            }
+            DkimError::NoValidSignature(errors) => {
+                let reasons: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+                write!(f, "none of the DKIM signatures verify: {}", reasons.join("; "))
+            }
+            DkimError::DomainMismatch {
+                from_domain,
+                signing_domain,
+            } => write!(
+                f,
+                "email from {} is signed by unrelated domain {}",
+                from_domain, signing_domain
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DkimError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+/// The tags of a DKIM-Signature header that we need to verify it (RFC 6376 section 3.5).
+#[derive(Debug, Clone)]
+pub struct DkimSignature {
+    pub domain: String,
+    pub selector: String,
+    pub signed_headers: Vec<String>,
+    pub body_hash: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub header_canonicalization: Canonicalization,
+    pub body_canonicalization: Canonicalization,
+}
+
+/// The outcome of a passing verification, kept around so later stages can reuse the exact signed data.
+#[derive(Debug, Clone)]
+pub struct VerifiedDkim {
+    pub signature: DkimSignature,
+    pub public_key: RsaPublicKey,
+    pub canonicalized_header: Vec<u8>,
+    pub canonicalized_body: Vec<u8>,
+}
+
+impl VerifiedDkim {
+    pub fn domain_key(&self) -> String {
+        format!(
+            "{}._domainkey.{}",
+            self.signature.selector, self.signature.domain
+        )
+    }
+
+    /// The email's nullifier, which the wallet contract marks as used once the email is executed: the SHA-256 of the
+    /// DKIM signature, truncated to its last 31 bytes so it fits in a field element. It must match the circuit's nullifier signal.
+    pub fn nullifier(&self) -> U256 {
+        let hash = Sha256::digest(&self.signature.signature);
+        U256::from_big_endian(&hash[1..])
+    }
+}
+
+/// Splits a raw email into its header fields (with folding preserved) and its body.
+fn split_email(raw_email: &str) -> (Vec<String>, String) {
+    // IMAP delivers CRLF, but tolerate bare LF files written by hand
+    let normalized = if raw_email.contains("\r\n") {
+        raw_email.to_string()
+    } else {
+        raw_email.replace('\n', "\r\n")
+    };
+    let (header, body) = match normalized.find("\r\n\r\n") {
+        Some(idx) => (&normalized[..idx + 2], &normalized[idx + 4..]),
+        None => (normalized.as_str(), ""),
+    };
+    let mut fields: Vec<String> = Vec::new();
+    for line in header.split_inclusive("\r\n") {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = fields.last_mut() {
+                last.push_str(line);
+                continue;
+            }
+        }
+        fields.push(line.to_string());
+    }
+    (fields, body.to_string())
+}
+
+fn header_name(field: &str) -> &str {
+    field.split(':').next().unwrap_or("").trim()
+}
+
+fn collapse_whitespace(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut in_whitespace = false;
+    for c in value.chars() {
+        if c == ' ' || c == '\t' {
+            in_whitespace = true;
+        } else {
+            if in_whitespace {
+                out.push(' ');
+            }
+            in_whitespace = false;
+            out.push(c);
+        }
+    }
+    if in_whitespace {
+        out.push(' ');
+    }
+    out
+}
+
+pub fn canonicalize_header(field: &str, method: Canonicalization) -> String {
+    match method {
+        Canonicalization::Simple => field.to_string(),
+        Canonicalization::Relaxed => {
+            let (name, value) = field.split_once(':').unwrap_or((field, ""));
+            let unfolded = value.replace("\r\n", "");
+            format!(
+                "{}:{}\r\n",
+                name.trim().to_lowercase(),
+                collapse_whitespace(&unfolded).trim()
+            )
+        }
+    }
+}
+
+pub fn canonicalize_body(body: &str, method: Canonicalization) -> String {
+    let mut lines: Vec<String> = body
+        .split("\r\n")
+        .map(|line| match method {
+            Canonicalization::Simple => line.to_string(),
+            Canonicalization::Relaxed => {
+                collapse_whitespace(line).trim_end_matches(' ').to_string()
+            }
+        })
+        .collect();
+    while lines.last().map_or(false, |line| line.is_empty()) {
+        lines.pop();
+    }
+    if lines.is_empty() {
+        // An empty body canonicalizes to CRLF under simple, and to nothing under relaxed
+        return match method {
+            Canonicalization::Simple => "\r\n".to_string(),
+            Canonicalization::Relaxed => String::new(),
+        };
+    }
+    let mut canonicalized = String::with_capacity(body.len());
+    for line in lines {
+        canonicalized.push_str(&line);
+        canonicalized.push_str("\r\n");
+    }
+    canonicalized
+}
+
+fn parse_tag_list(value: &str) -> HashMap<String, String> {
+    value
+        .split(';')
+        .filter_map(|tag| {
+            let (name, value) = tag.split_once('=')?;
+            Some((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+fn strip_whitespace(value: &str) -> String {
+    value.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+fn parse_canonicalization(name: &str) -> Result<Canonicalization, DkimError> {
+    match name {
+        "simple" => Ok(Canonicalization::Simple),
+        "relaxed" => Ok(Canonicalization::Relaxed),
+        other => Err(DkimError::MalformedSignature(format!(
+            "unknown canonicalization {}",
+            other
+        ))),
+    }
+}
+
+pub fn parse_dkim_signature(field: &str) -> Result<DkimSignature, DkimError> {
+    let value = field.split_once(':').map(|(_, v)| v).unwrap_or("");
+    let tags = parse_tag_list(value);
+    let tag = |name: &str| -> Result<&String, DkimError> {
+        tags.get(name)
+            .ok_or_else(|| DkimError::MalformedSignature(format!("missing {}= tag", name)))
+    };
+
+    if tag("v")? != "1" {
+        return Err(DkimError::MalformedSignature(
+            "version is not 1".to_string(),
+        ));
+    }
+    let algorithm = tag("a")?;
+    if algorithm != "rsa-sha256" {
+        return Err(DkimError::UnsupportedAlgorithm(algorithm.to_string()));
+    }
+    let (header_canonicalization, body_canonicalization) = match tags.get("c") {
+        Some(c) => match c.split_once('/') {
+            Some((header, body)) => (
+                parse_canonicalization(header)?,
+                parse_canonicalization(body)?,
+            ),
+            None => (parse_canonicalization(c)?, Canonicalization::Simple),
+        },
+        None => (Canonicalization::Simple, Canonicalization::Simple),
+    };
+    let decode = |name: &str| -> Result<Vec<u8>, DkimError> {
+        general_purpose::STANDARD
+            .decode(strip_whitespace(tag(name)?))
+            .map_err(|e| DkimError::MalformedSignature(format!("{}= is not base64: {}", name, e)))
+    };
+    // A body length lets anyone append to the signed body, so only signatures over the whole body are accepted
+    if tags.contains_key("l") {
+        return Err(DkimError::MalformedSignature(
+            "l= only signs part of the body".to_string(),
+        ));
+    }
+    let signed_headers: Vec<String> = strip_whitespace(tag("h")?)
+        .split(':')
+        .map(|h| h.to_lowercase())
+        .collect();
+    // The From header must be signed (RFC 6376 section 5.4), since it is who the command is executed for
+    if !signed_headers.iter().any(|h| h == "from") {
+        return Err(DkimError::MalformedSignature(
+            "h= does not sign the From header".to_string(),
+        ));
+    }
+
+    Ok(DkimSignature {
+        domain: tag("d")?.to_lowercase(),
+        selector: tag("s")?.to_string(),
+        signed_headers,
+        body_hash: decode("bh")?,
+        signature: decode("b")?,
+        header_canonicalization,
+        body_canonicalization,
+    })
+}
+
+/// Empties the value of the b= tag of a DKIM-Signature header while keeping every other byte intact,
+/// since the signature is computed over the header as it appears with an empty b= value.
+fn remove_signature_value(field: &str) -> String {
+    let (name, value) = field.split_once(':').unwrap_or((field, ""));
+    let tags: Vec<String> = value
+        .split(';')
+        .map(|tag| match tag.split_once('=') {
+            Some((tag_name, _)) if tag_name.trim() == "b" => format!("{}=", tag_name),
+            _ => tag.to_string(),
+        })
+        .collect();
+    let stripped = format!("{}:{}", name, tags.join(";"));
+    // The header ends with CRLF which belongs to the removed b= value, so put it back
+    if field.ends_with("\r\n") && !stripped.ends_with("\r\n") {
+        format!("{}\r\n", stripped)
+    } else {
+        stripped
+    }
+}
+
+fn parse_public_key(txt_record: &str) -> Result<RsaPublicKey, DkimError> {
+    let tags = parse_tag_list(txt_record);
+    if let Some(k) = tags.get("k") {
+        if k != "rsa" {
+            return Err(DkimError::UnsupportedAlgorithm(format!("k={}", k)));
+        }
+    }
+    let p = tags
+        .get("p")
+        .ok_or_else(|| DkimError::MalformedKey("missing p= tag".to_string()))?;
+    if p.is_empty() {
+        return Err(DkimError::MalformedKey("key has been revoked".to_string()));
+    }
+    let der = general_purpose::STANDARD
+        .decode(strip_whitespace(p))
+        .map_err(|e| DkimError::MalformedKey(format!("p= is not base64: {}", e)))?;
+    RsaPublicKey::from_public_key_der(&der)
+        .or_else(|_| RsaPublicKey::from_pkcs1_der(&der))
+        .map_err(|e| DkimError::MalformedKey(e.to_string()))
+}
+
+/// Returns the domain part of an address such as "Name <user@domain>" or "user@domain".
+fn address_domain(address: &str) -> Option<String> {
+    let address = address.trim().trim_end_matches('>');
+    let (_, domain) = address.rsplit_once('@')?;
+    Some(domain.trim().to_lowercase())
+}
+
+async fn verify_signature(
+    fields: &[String],
+    body: &str,
+    dkim_field: &str,
+    resolver: &dyn DkimKeyResolver,
+) -> Result<VerifiedDkim, DkimError> {
+    let signature = parse_dkim_signature(dkim_field)?;
+
+    let canonicalized_body =
+        canonicalize_body(body, signature.body_canonicalization).into_bytes();
+    if Sha256::digest(&canonicalized_body).as_slice() != signature.body_hash.as_slice() {
+        return Err(DkimError::BodyHashMismatch);
+    }
+
+    // Each listed header consumes the bottom-most instance not yet used; missing ones are skipped
+    let mut used = vec![false; fields.len()];
+    let mut canonicalized_header = String::new();
+    for name in signature.signed_headers.iter() {
+        let found = (0..fields.len())
+            .rev()
+            .find(|&i| !used[i] && header_name(&fields[i]).eq_ignore_ascii_case(name));
+        if let Some(i) = found {
+            used[i] = true;
+            canonicalized_header.push_str(&canonicalize_header(
+                &fields[i],
+                signature.header_canonicalization,
+            ));
+        }
+    }
+    let stripped_dkim = canonicalize_header(
+        &remove_signature_value(dkim_field),
+        signature.header_canonicalization,
+    );
+    canonicalized_header.push_str(stripped_dkim.trim_end_matches("\r\n"));
+
+    let key_name = format!("{}._domainkey.{}", signature.selector, signature.domain);
+    let records = resolver
+        .lookup_txt(&key_name)
+        .await
+        .map_err(|_| DkimError::KeyNotFound(key_name.clone()))?;
+    let record = records
+        .iter()
+        .find(|r| r.contains("p="))
+        .ok_or_else(|| DkimError::KeyNotFound(key_name.clone()))?;
+    let public_key = parse_public_key(record)?;
+
+    let hashed = Sha256::digest(canonicalized_header.as_bytes());
+    public_key
+        .verify(Pkcs1v15Sign::new::<Sha256>(), &hashed, &signature.signature)
+        .map_err(|_| DkimError::SignatureMismatch)?;
+
+    Ok(VerifiedDkim {
+        signature,
+        public_key,
+        canonicalized_header: canonicalized_header.into_bytes(),
+        canonicalized_body,
+    })
+}
+
+/// Verifies the DKIM signatures of a raw email, returning the first one that passes and whose
+/// signing domain matches the sender's domain (or a parent of it), so a third party domain
+/// cannot vouch for someone else's address.
+pub async fn verify_dkim(
+    raw_email: &str,
+    from: &str,
+    resolver: &dyn DkimKeyResolver,
+) -> Result<VerifiedDkim, DkimError> {
+    let (fields, body) = split_email(raw_email);
+    let dkim_fields: Vec<&String> = fields
+        .iter()
+        .filter(|f| header_name(f).eq_ignore_ascii_case("DKIM-Signature"))
+        .collect();
+    if dkim_fields.is_empty() {
+        return Err(DkimError::MissingSignature);
+    }
+    // Signatures only cover the bottom-most From, so another one prepended could be shown as the sender instead
+    let from_fields = fields
+        .iter()
+        .filter(|f| header_name(f).eq_ignore_ascii_case("From"))
+        .count();
+    if from_fields != 1 {
+        return Err(DkimError::FromHeaderCount(from_fields));
+    }
+    let from_domain = address_domain(from).unwrap_or_default();
+
+    let mut errors = vec![];
+    for dkim_field in dkim_fields {
+        match verify_signature(&fields, &body, dkim_field, resolver).await {
+            Ok(verified) => {
+                let signing_domain = &verified.signature.domain;
+                if from_domain == *signing_domain
+                    || from_domain.ends_with(&format!(".{}", signing_domain))
+                {
+                    return Ok(verified);
+                }
+                errors.push(DkimError::DomainMismatch {
+                    from_domain: from_domain.clone(),
+                    signing_domain: signing_domain.clone(),
+                });
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+    match errors.len() {
+        1 => Err(errors.remove(0)),
+        _ => Err(DkimError::NoValidSignature(errors)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../fixtures/dkim_relaxed.eml");
+    const FIXTURE_KEY: &str = "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQC5+ZkeOLLgpgWb+RO6y2k26tCBPbYQgT2NrRMY/AfI1VYW7VeGB1YWiLAWfLxBdDZQhaIjQ2qS5I5EXQYkZJAtdkWzyBEnx1V+lV88rGre5kyZQrwCRBSzhLSVe3Gj/CaK/zUMcB3bFJ47A2g1xu6799Y6CIDbHgnwgSisBLg6OQIDAQAB";
+
+    fn fixture_resolver() -> StaticKeyResolver {
+        StaticKeyResolver::new().with_record("test._domainkey.example.com", FIXTURE_KEY)
+    }
+
+    #[test]
+    fn test_canonicalize_relaxed() {
+        let header = canonicalize_header(
+            "SubJect:  Send 1 DAI\r\n\t to  bob@example.org \r\n",
+            Canonicalization::Relaxed,
+        );
+        assert_eq!(header, "subject:Send 1 DAI to bob@example.org\r\n");
+        let body = canonicalize_body(" a  b \t\r\nc\r\n\r\n\r\n", Canonicalization::Relaxed);
+        assert_eq!(body, " a b\r\nc\r\n");
+        assert_eq!(
+            canonicalize_body("\r\n\r\n", Canonicalization::Simple),
+            "\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_fixture() {
+        let verified = verify_dkim(FIXTURE, "alice@example.com", &fixture_resolver()).await;
+        let verified = verified.expect("fixture should verify");
+        assert_eq!(verified.domain_key(), "test._domainkey.example.com");
+        let nullifier = verified.nullifier();
+        assert!(!nullifier.is_zero() && nullifier.bits() <= 248);
+    }
+
+    #[tokio::test]
+    async fn test_verify_rejects_tampering() {
+        let forged_subject = FIXTURE.replace("Send 1 DAI", "Send 9 DAI");
+        let result = verify_dkim(&forged_subject, "alice@example.com", &fixture_resolver()).await;
+        assert_eq!(result.unwrap_err(), DkimError::SignatureMismatch);
+
+        let forged_body = FIXTURE.replace("please", "kindly");
+        let result = verify_dkim(&forged_body, "alice@example.com", &fixture_resolver()).await;
+        assert_eq!(result.unwrap_err(), DkimError::BodyHashMismatch);
+
+        let result = verify_dkim(FIXTURE, "mallory@attacker.org", &fixture_resolver()).await;
+        assert!(matches!(result, Err(DkimError::DomainMismatch { .. })));
+
+        let result = verify_dkim(FIXTURE, "alice@example.com", &StaticKeyResolver::new()).await;
+        assert!(matches!(result, Err(DkimError::KeyNotFound(_))));
+
+        // The signature still covers the original From, but a second one could be read as the sender
+        let prepended_from = format!("From: Mallory <mallory@example.com>\r\n{}", FIXTURE);
+        let result = verify_dkim(&prepended_from, "alice@example.com", &fixture_resolver()).await;
+        assert_eq!(result.unwrap_err(), DkimError::FromHeaderCount(2));
+
+        // Every signature's failure is returned, not only the last one's
+        let unknown_key = "DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=other; h=from:to; bh=AAAA; b=AAAA\r\n";
+        let result = verify_dkim(&format!("{}{}", unknown_key, forged_subject), "alice@example.com", &fixture_resolver()).await;
+        assert_eq!(
+            result.unwrap_err(),
+            DkimError::NoValidSignature(vec![DkimError::BodyHashMismatch, DkimError::SignatureMismatch])
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_partial_signatures() {
+        let field = "DKIM-Signature: v=1; a=rsa-sha256; d=example.com; s=test; h=from:to:subject; bh=AAAA; b=AAAA";
+        assert!(parse_dkim_signature(field).is_ok());
+        let unsigned_from = field.replace("h=from:to", "h=to");
+        assert!(matches!(parse_dkim_signature(&unsigned_from), Err(DkimError::MalformedSignature(_))));
+        let body_length = format!("{}; l=10", field);
+        assert!(matches!(parse_dkim_signature(&body_length), Err(DkimError::MalformedSignature(_))));
+    }
+}
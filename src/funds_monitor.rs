@@ -0,0 +1,279 @@
+use crate::amount::TokenAmount;
+use crate::chain::{get_provider, query_balance, TokenRegistry, NATIVE_TOKEN};
+use crate::chain_config::{ChainConfig, ChainRegistry};
+use crate::config::FUNDS_EXPIRY_KEY;
+use crate::coordinator::BalanceRequest;
+use crate::db::unix_time;
+use anyhow::{anyhow, Result};
+use ethers::core::types::{Address, Filter, H256, U64};
+use ethers::providers::{Http, Middleware, Provider};
+use futures::StreamExt;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// How long a command waits for the funds it needs before it expires, unless FUNDS_EXPIRY_SECS is set
+pub const DEFAULT_FUNDS_EXPIRY_SECS: u64 = 60 * 60 * 24;
+/// How often expiries are checked, so jobs expire on time even on a chain that stopped producing blocks
+const EXPIRY_CHECK_SECS: u64 = 60;
+
+/// What happened to a command that was waiting for funds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FundsEvent {
+    Funded,
+    Expired,
+}
+
+/// A command waiting for its wallet to hold enough of a token.
+struct Watch {
+    request: BalanceRequest,
+    wallet: Address,
+    /// When the command expires, in seconds since the epoch
+    deadline: u64,
+    notify: oneshot::Sender<FundsEvent>,
+}
+
+/// Watches the wallets of commands waiting for funds, with one task per chain that follows new blocks.
+/// On each block it looks for ERC20 `Transfer`s to the watched wallets, and only re-reads the balances of wallets
+/// that received the token they wait for. Native balances have no logs, so they are re-read every block.
+#[derive(Clone)]
+pub struct FundsMonitor {
+    by_chain: Arc<HashMap<u64, mpsc::UnboundedSender<Watch>>>,
+    expiry_secs: u64,
+}
+
+impl FundsMonitor {
+    pub fn spawn(chains: &ChainRegistry, tokens: &TokenRegistry) -> Self {
+        let expiry_secs = env::var(FUNDS_EXPIRY_KEY)
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_FUNDS_EXPIRY_SECS);
+        let mut by_chain = HashMap::new();
+        for chain in chains.chains() {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::task::spawn(watch_chain(chain.clone(), tokens.clone(), receiver));
+            by_chain.insert(chain.id, sender);
+        }
+        Self {
+            by_chain: Arc::new(by_chain),
+            expiry_secs,
+        }
+    }
+
+    pub fn expiry_secs(&self) -> u64 {
+        self.expiry_secs
+    }
+
+    /// Watches the wallet of a command that started waiting for funds at `since` (in seconds since the epoch),
+    /// resolving once the wallet holds the amount or the command expired.
+    pub fn watch(&self, request: BalanceRequest, since: u64) -> Result<oneshot::Receiver<FundsEvent>> {
+        let wallet = Address::from_str(&request.address)?;
+        let sender = self
+            .by_chain
+            .get(&request.chain.id)
+            .ok_or(anyhow!("Funds aren't monitored on {}", request.chain.name))?;
+        let (notify, event) = oneshot::channel();
+        let watch = Watch {
+            request,
+            wallet,
+            deadline: since + self.expiry_secs,
+            notify,
+        };
+        sender.send(watch).map_err(|_| anyhow!("The funds monitor stopped"))?;
+        Ok(event)
+    }
+}
+
+async fn watch_chain(chain: ChainConfig, tokens: TokenRegistry, mut requests: mpsc::UnboundedReceiver<Watch>) {
+    let provider = match get_provider(&chain).await {
+        Ok(provider) => provider,
+        Err(e) => return println!("Not monitoring funds on {}: {}", chain.name, e),
+    };
+    let mut blocks = match provider.watch_blocks().await {
+        Ok(blocks) => blocks,
+        Err(e) => return println!("Not monitoring funds on {}, could not follow its blocks: {}", chain.name, e),
+    };
+    let mut last_block = provider.get_block_number().await.unwrap_or_default();
+    let mut expiry_check = tokio::time::interval(Duration::from_secs(EXPIRY_CHECK_SECS));
+    let mut watches: Vec<Watch> = vec![];
+
+    loop {
+        tokio::select! {
+            watch = requests.recv() => {
+                let Some(watch) = watch else {
+                    return;
+                };
+                // The funds may have arrived before the command was watched, e.g. while the relayer was down
+                if is_funded(&watch.request, &tokens).await {
+                    let _ = watch.notify.send(FundsEvent::Funded);
+                } else {
+                    println!("Watching {} on {} for {} {}", watch.request.address, chain.name, watch.request.amount, watch.request.token_name);
+                    watches.push(watch);
+                }
+            }
+            block = blocks.next() => {
+                if block.is_none() {
+                    return println!("Stopped following blocks on {}, no longer monitoring funds", chain.name);
+                }
+                if watches.is_empty() {
+                    continue;
+                }
+                let block_number = match provider.get_block_number().await {
+                    Ok(block_number) => block_number,
+                    Err(e) => {
+                        println!("Could not read the block number of {}: {}", chain.name, e);
+                        continue;
+                    }
+                };
+                // Only read the latest block if the block the monitor started at is unknown, not the whole chain
+                let from_block = if last_block.is_zero() { block_number } else { last_block + 1 };
+                let deposits = deposits(&provider, &chain, &tokens, &watches, from_block, block_number).await;
+                last_block = block_number;
+                let mut waiting = vec![];
+                for watch in watches.drain(..) {
+                    let woken = match &deposits {
+                        Some(deposits) => wakes_on(&watch, &chain, &tokens, deposits),
+                        None => true,
+                    };
+                    if woken && is_funded(&watch.request, &tokens).await {
+                        println!("Funds arrived at {} on {}", watch.request.address, chain.name);
+                        let _ = watch.notify.send(FundsEvent::Funded);
+                    } else {
+                        waiting.push(watch);
+                    }
+                }
+                watches = waiting;
+            }
+            _ = expiry_check.tick() => {
+                let now = unix_time();
+                let (expired, waiting): (Vec<Watch>, Vec<Watch>) = watches.drain(..).partition(|watch| watch.deadline <= now);
+                for watch in expired {
+                    println!("Stopped waiting for {} {} at {} on {}", watch.request.amount, watch.request.token_name, watch.request.address, chain.name);
+                    let _ = watch.notify.send(FundsEvent::Expired);
+                }
+                watches = waiting;
+            }
+        }
+    }
+}
+
+/// The (token, wallet) pairs of the ERC20 transfers to watched wallets in a range of blocks, or None if they couldn't be read.
+async fn deposits(
+    provider: &Provider<Http>,
+    chain: &ChainConfig,
+    tokens: &TokenRegistry,
+    watches: &[Watch],
+    from_block: U64,
+    to_block: U64,
+) -> Option<HashSet<(Address, Address)>> {
+    let token_addresses: Vec<Address> = watches
+        .iter()
+        .filter_map(|watch| tokens.get(chain, &watch.request.token_name))
+        .filter(|token| token.name != NATIVE_TOKEN)
+        .map(|token| token.address)
+        .collect();
+    if token_addresses.is_empty() {
+        return Some(HashSet::new());
+    }
+    let wallets: Vec<H256> = watches.iter().map(|watch| H256::from(watch.wallet)).collect();
+    let filter = Filter::new()
+        .from_block(from_block)
+        .to_block(to_block)
+        .address(token_addresses)
+        .event("Transfer(address,address,uint256)")
+        .topic2(wallets);
+    match provider.get_logs(&filter).await {
+        Ok(logs) => Some(
+            logs.iter()
+                .filter_map(|log| Some((log.address, Address::from(*log.topics.get(2)?))))
+                .collect(),
+        ),
+        Err(e) => {
+            println!("Could not read transfers on {}, re-reading every watched balance: {}", chain.name, e);
+            None
+        }
+    }
+}
+
+/// Whether a block's deposits may have funded a watch. Native balances and tokens that aren't cached are always re-read.
+fn wakes_on(watch: &Watch, chain: &ChainConfig, tokens: &TokenRegistry, deposits: &HashSet<(Address, Address)>) -> bool {
+    match tokens.get(chain, &watch.request.token_name) {
+        Some(token) if token.name != NATIVE_TOKEN => deposits.contains(&(token.address, watch.wallet)),
+        _ => true,
+    }
+}
+
+async fn is_funded(request: &BalanceRequest, tokens: &TokenRegistry) -> bool {
+    let token = match tokens.resolve(&request.chain, &request.token_name).await {
+        Ok(Some(token)) => token,
+        Ok(None) => {
+            println!("{} is no longer registered on {}", request.token_name, request.chain.name);
+            return false;
+        }
+        Err(error) => {
+            println!("Could not look up {} on {}: {}", request.token_name, request.chain.name, error);
+            return false;
+        }
+    };
+    match query_balance(&request.chain, &request.address, &token).await {
+        Ok(balance) => {
+            println!("Balance of address {} on {}: {} {}", request.address, request.chain.name, balance, request.token_name);
+            match TokenAmount::parse(&request.amount, balance.decimals) {
+                Ok(required) => balance >= required,
+                Err(error) => {
+                    println!("Invalid amount {}: {}", request.amount, error);
+                    false
+                }
+            }
+        }
+        Err(error) => {
+            println!("Could not read the balance of {} on {}: {}", request.address, request.chain.name, error);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chain::TokenInfo;
+
+    #[test]
+    fn test_wakes_on_deposits() {
+        let chain = ChainConfig::new(1, "http://localhost:8545", Address::zero());
+        let dai = TokenInfo {
+            name: "DAI".to_string(),
+            address: Address::repeat_byte(1),
+            decimals: 18,
+        };
+        let eth = TokenInfo {
+            name: NATIVE_TOKEN.to_string(),
+            address: Address::zero(),
+            decimals: 18,
+        };
+        let tokens = TokenRegistry::with_tokens(&chain, vec![dai.clone(), eth]);
+        let watch = |token_name: &str, wallet: Address| Watch {
+            request: BalanceRequest {
+                chain: chain.clone(),
+                address: format!("{:?}", wallet),
+                amount: "1".to_string(),
+                token_name: token_name.to_string(),
+            },
+            wallet,
+            deadline: 0,
+            notify: oneshot::channel().0,
+        };
+        let wallet = Address::repeat_byte(2);
+        let deposits = HashSet::from([(dai.address, wallet)]);
+
+        assert!(wakes_on(&watch("DAI", wallet), &chain, &tokens, &deposits));
+        assert!(!wakes_on(&watch("DAI", Address::repeat_byte(3)), &chain, &tokens, &deposits));
+        assert!(!wakes_on(&watch("DAI", wallet), &chain, &tokens, &HashSet::new()));
+        // Native deposits and tokens that aren't cached leave no logs to match, so they are always re-read
+        assert!(wakes_on(&watch(NATIVE_TOKEN, wallet), &chain, &tokens, &HashSet::new()));
+        assert!(wakes_on(&watch("USDC", wallet), &chain, &tokens, &HashSet::new()));
+    }
+}
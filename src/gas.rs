@@ -0,0 +1,371 @@
+use crate::config::{
+    GAS_BUMP_PERCENT_KEY, GAS_MAX_FEE_GWEI_KEY, GAS_MAX_PRIORITY_FEE_GWEI_KEY,
+    GAS_MAX_REPLACEMENTS_KEY, GAS_STUCK_BLOCKS_KEY,
+};
+use anyhow::{anyhow, Result};
+use ethers::core::types::transaction::eip2718::TypedTransaction;
+use ethers::core::types::{
+    Eip1559TransactionRequest, TransactionReceipt, TransactionRequest, H256, U256,
+};
+use ethers::providers::Middleware;
+use ethers::utils::parse_units;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::future::Future;
+use std::time::Duration;
+
+/// How often to check whether a sent transaction was mined
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Nodes reject replacements that don't raise fees by at least 10%
+const MIN_BUMP_PERCENT: u64 = 10;
+
+/// The fees a transaction pays: EIP-1559 fees where the chain supports them, or a legacy gas price.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Fees {
+    Eip1559 {
+        max_fee_per_gas: U256,
+        max_priority_fee_per_gas: U256,
+    },
+    Legacy {
+        gas_price: U256,
+    },
+}
+
+impl Fees {
+    /// Sets these fees on a transaction, converting it to the matching transaction type.
+    pub fn apply(&self, tx: TypedTransaction) -> TypedTransaction {
+        match *self {
+            Fees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            } => <Eip1559TransactionRequest as From<TypedTransaction>>::from(tx)
+                .max_fee_per_gas(max_fee_per_gas)
+                .max_priority_fee_per_gas(max_priority_fee_per_gas)
+                .into(),
+            Fees::Legacy { gas_price } => {
+                <TransactionRequest as From<TypedTransaction>>::from(tx)
+                    .gas_price(gas_price)
+                    .into()
+            }
+        }
+    }
+}
+
+/// A transaction that was re-broadcast with bumped fees at the same nonce, because it wasn't mined in time.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TxReplacement {
+    pub replaced_tx_hash: H256,
+    pub tx_hash: H256,
+    pub nonce: U256,
+    pub fees: Fees,
+    pub block_number: u64,
+}
+
+/// The transaction that was finally mined, with its receipt, or the last one sent and None if none of them were mined.
+#[derive(Clone, Debug)]
+pub struct MinedTransaction {
+    pub tx_hash: H256,
+    pub receipt: Option<TransactionReceipt>,
+}
+
+/// How the relayer prices its transactions, and when it replaces the ones stuck in the mempool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GasStrategy {
+    /// The most the relayer will pay per gas, including the priority fee
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+    /// How much each replacement raises the fees by
+    pub bump_percent: u64,
+    /// How many blocks a transaction can wait in the mempool before it is replaced
+    pub stuck_blocks: u64,
+    pub max_replacements: usize,
+}
+
+impl Default for GasStrategy {
+    fn default() -> Self {
+        Self {
+            max_fee_per_gas: gwei(500),
+            max_priority_fee_per_gas: gwei(3),
+            bump_percent: 20,
+            stuck_blocks: 5,
+            max_replacements: 5,
+        }
+    }
+}
+
+fn gwei(amount: u64) -> U256 {
+    U256::from(amount) * U256::exp10(9)
+}
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+fn env_gwei_or(key: &str, default: U256) -> U256 {
+    env::var(key)
+        .ok()
+        .and_then(|value| parse_units(value.trim(), "gwei").ok())
+        .map(U256::from)
+        .unwrap_or(default)
+}
+
+impl GasStrategy {
+    /// Reads the caps and replacement policy from GAS_MAX_FEE_GWEI, GAS_MAX_PRIORITY_FEE_GWEI, GAS_BUMP_PERCENT,
+    /// GAS_STUCK_BLOCKS and GAS_MAX_REPLACEMENTS, defaulting any that aren't set.
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_fee_per_gas: env_gwei_or(GAS_MAX_FEE_GWEI_KEY, default.max_fee_per_gas),
+            max_priority_fee_per_gas: env_gwei_or(
+                GAS_MAX_PRIORITY_FEE_GWEI_KEY,
+                default.max_priority_fee_per_gas,
+            ),
+            bump_percent: env_or(GAS_BUMP_PERCENT_KEY, default.bump_percent).max(MIN_BUMP_PERCENT),
+            stuck_blocks: env_or(GAS_STUCK_BLOCKS_KEY, default.stuck_blocks).max(1),
+            max_replacements: env_or(GAS_MAX_REPLACEMENTS_KEY, default.max_replacements),
+        }
+    }
+
+    /// Caps fees to the configured maximums, keeping the priority fee within the max fee.
+    pub fn cap(&self, fees: Fees) -> Fees {
+        match fees {
+            Fees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            } => {
+                let max_fee_per_gas = max_fee_per_gas.min(self.max_fee_per_gas);
+                Fees::Eip1559 {
+                    max_fee_per_gas,
+                    max_priority_fee_per_gas: max_priority_fee_per_gas
+                        .min(self.max_priority_fee_per_gas)
+                        .min(max_fee_per_gas),
+                }
+            }
+            Fees::Legacy { gas_price } => Fees::Legacy {
+                gas_price: gas_price.min(self.max_fee_per_gas),
+            },
+        }
+    }
+
+    /// The fees for a replacement: every fee raised by bump_percent, then capped.
+    /// Returns None once the caps keep any fee from rising by MIN_BUMP_PERCENT, since nodes would reject the replacement,
+    /// so the transaction can't be replaced again.
+    pub fn bump(&self, fees: Fees) -> Option<Fees> {
+        let bump = |fee: U256| fee + fee * self.bump_percent / 100 + 1;
+        let enough = |old: U256, new: U256| new > old + old * MIN_BUMP_PERCENT / 100;
+        let bumped = self.cap(match fees {
+            Fees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            } => Fees::Eip1559 {
+                max_fee_per_gas: bump(max_fee_per_gas),
+                max_priority_fee_per_gas: bump(max_priority_fee_per_gas),
+            },
+            Fees::Legacy { gas_price } => Fees::Legacy {
+                gas_price: bump(gas_price),
+            },
+        });
+        let raised = match (fees, bumped) {
+            (
+                Fees::Eip1559 {
+                    max_fee_per_gas,
+                    max_priority_fee_per_gas,
+                },
+                Fees::Eip1559 {
+                    max_fee_per_gas: bumped_max_fee,
+                    max_priority_fee_per_gas: bumped_priority_fee,
+                },
+            ) => enough(max_fee_per_gas, bumped_max_fee) && enough(max_priority_fee_per_gas, bumped_priority_fee),
+            (Fees::Legacy { gas_price }, Fees::Legacy { gas_price: bumped_gas_price }) => enough(gas_price, bumped_gas_price),
+            _ => false,
+        };
+        raised.then_some(bumped)
+    }
+
+    /// Estimates EIP-1559 fees, falling back to the legacy gas price on chains without a base fee.
+    pub async fn estimate_fees<M: Middleware>(&self, client: &M) -> Result<Fees>
+    where
+        M::Error: 'static,
+    {
+        let fees = match client.estimate_eip1559_fees(None).await {
+            Ok((max_fee_per_gas, max_priority_fee_per_gas)) => Fees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            },
+            Err(e) => {
+                println!("EIP-1559 fees unavailable, using the legacy gas price: {}", e);
+                Fees::Legacy {
+                    gas_price: client.get_gas_price().await?,
+                }
+            }
+        };
+        Ok(self.cap(fees))
+    }
+
+    /// Prices a transaction and fills in its nonce and gas limit, so that it can be re-broadcast as is.
+    pub async fn prepare<M: Middleware>(
+        &self,
+        client: &M,
+        tx: TypedTransaction,
+    ) -> Result<(TypedTransaction, Fees)>
+    where
+        M::Error: 'static,
+    {
+        let fees = self.estimate_fees(client).await?;
+        let mut tx = fees.apply(tx);
+        client.fill_transaction(&mut tx, None).await?;
+        Ok((tx, fees))
+    }
+
+    /// Waits until a broadcast transaction has the given number of confirmations.
+    /// Whenever the latest transaction has waited stuck_blocks blocks without being mined, it is re-broadcast at the same nonce
+    /// with bumped fees, and `on_replacement` is called with the replacement. Any of the sent transactions may be the one mined.
+    pub async fn wait_until_mined<M, F, Fut>(
+        &self,
+        client: &M,
+        tx: TypedTransaction,
+        fees: Fees,
+        tx_hash: H256,
+        confirmations: usize,
+        mut on_replacement: F,
+    ) -> Result<MinedTransaction>
+    where
+        M: Middleware,
+        M::Error: 'static,
+        F: FnMut(TxReplacement) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let nonce = *tx.nonce().ok_or(anyhow!("Transaction must be prepared before it is sent"))?;
+        let mut tx = tx;
+        let mut fees = fees;
+        let mut sent = vec![tx_hash];
+        let mut sent_at = client.get_block_number().await?.as_u64();
+        let mut replacements = 0;
+        println!("Sent {:?} with nonce {} and fees {:?}", tx_hash, nonce, fees);
+
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let block_number = client.get_block_number().await?.as_u64();
+
+            let mut mined = None;
+            for tx_hash in sent.iter().rev() {
+                if let Some(receipt) = client.get_transaction_receipt(*tx_hash).await? {
+                    mined = Some((*tx_hash, receipt));
+                    break;
+                }
+            }
+            if let Some((tx_hash, receipt)) = mined {
+                let mined_at = receipt.block_number.map_or(block_number, |mined_at| mined_at.as_u64());
+                if block_number + 1 >= mined_at + confirmations as u64 {
+                    return Ok(MinedTransaction {
+                        tx_hash,
+                        receipt: Some(receipt),
+                    });
+                }
+                continue;
+            }
+
+            if block_number < sent_at + self.stuck_blocks {
+                continue;
+            }
+            let bumped = match self.bump(fees) {
+                Some(bumped) if replacements < self.max_replacements => bumped,
+                // Out of replacements: give the last one another stuck_blocks to be mined, then treat it as dropped
+                _ => {
+                    if block_number >= sent_at + 2 * self.stuck_blocks {
+                        return Ok(MinedTransaction {
+                            tx_hash: *sent.last().unwrap(),
+                            receipt: None,
+                        });
+                    }
+                    continue;
+                }
+            };
+
+            let replacement_tx = bumped.apply(tx.clone());
+            match client.send_transaction(replacement_tx.clone(), None).await {
+                Ok(pending) => {
+                    let replacement = TxReplacement {
+                        replaced_tx_hash: *sent.last().unwrap(),
+                        tx_hash: *pending,
+                        nonce,
+                        fees: bumped,
+                        block_number,
+                    };
+                    println!("Replaced stuck transaction: {:?}", replacement);
+                    sent.push(replacement.tx_hash);
+                    sent_at = block_number;
+                    replacements += 1;
+                    fees = bumped;
+                    tx = replacement_tx;
+                    on_replacement(replacement).await;
+                }
+                // e.g. "nonce too low" once one of the sent transactions is mined, which the next poll picks up.
+                // Failed attempts count as replacements, so a transaction that was dropped is given up on eventually
+                Err(e) => {
+                    println!("Could not replace {:?}: {}", sent.last().unwrap(), e);
+                    sent_at = block_number;
+                    replacements += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bump_and_cap_fees() {
+        let strategy = GasStrategy::default();
+        let fees = strategy.cap(Fees::Eip1559 {
+            max_fee_per_gas: gwei(40),
+            max_priority_fee_per_gas: gwei(10),
+        });
+        assert_eq!(
+            fees,
+            Fees::Eip1559 {
+                max_fee_per_gas: gwei(40),
+                max_priority_fee_per_gas: gwei(3),
+            }
+        );
+        // The priority fee is at its cap, so it can't rise enough for nodes to accept a replacement
+        assert_eq!(strategy.bump(fees), None);
+
+        let fees = Fees::Eip1559 {
+            max_fee_per_gas: gwei(40),
+            max_priority_fee_per_gas: gwei(2),
+        };
+        assert_eq!(
+            strategy.bump(fees),
+            Some(Fees::Eip1559 {
+                max_fee_per_gas: gwei(48) + 1,
+                max_priority_fee_per_gas: U256::from(2_400_000_001u64),
+            })
+        );
+
+        let at_cap = Fees::Legacy { gas_price: gwei(500) };
+        assert_eq!(strategy.bump(at_cap), None);
+    }
+
+    #[test]
+    fn test_apply_fees_converts_transaction_type() {
+        let tx: TypedTransaction = TransactionRequest::new().nonce(7).into();
+        let eip1559 = Fees::Eip1559 {
+            max_fee_per_gas: gwei(30),
+            max_priority_fee_per_gas: gwei(2),
+        }
+        .apply(tx);
+        let request = eip1559.as_eip1559_ref().unwrap();
+        assert_eq!(request.max_fee_per_gas, Some(gwei(30)));
+        assert_eq!(request.nonce, Some(7.into()));
+
+        let legacy = Fees::Legacy { gas_price: gwei(9) }.apply(eip1559);
+        assert_eq!(legacy.as_legacy_ref().unwrap().gas_price, Some(gwei(9)));
+        assert_eq!(legacy.nonce(), Some(&7.into()));
+    }
+}
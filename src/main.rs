@@ -1,37 +1,56 @@
+pub mod amount;
 pub mod chain;
+pub mod chain_config;
+pub mod circuit_input;
+pub mod command;
 pub mod config;
 pub mod coordinator;
 pub mod db;
+pub mod dkim;
+pub mod funds_monitor;
+pub mod gas;
 pub mod imap_client;
 pub mod parse_email;
-pub mod processer;
+pub mod proof_artifacts;
+pub mod prover;
+pub mod revert;
 pub mod smtp_client;
 pub mod strings;
+pub mod submitter;
+pub mod verifier;
+pub mod witness;
 use anyhow::{anyhow, Result};
-use chain::query_balance;
+use chain::{wait_for_submitted_tx, TokenRegistry};
+use chain_config::ChainRegistry;
 use config::{
     IMAP_AUTH_TYPE_KEY, IMAP_AUTH_URL_KEY, IMAP_CLIENT_ID_KEY, IMAP_CLIENT_SECRET_KEY,
     IMAP_DOMAIN_NAME_KEY, IMAP_PORT_KEY, IMAP_REDIRECT_URL_KEY, IMAP_TOKEN_URL_KEY, LOGIN_ID_KEY,
     LOGIN_PASSWORD_KEY, SMTP_DOMAIN_NAME_KEY, SMTP_PORT_KEY, ZK_EMAIL_PATH_KEY,
 };
+use command::{CommandContext, CommandRegistry, MatchedCommand, RelayerContext};
 use coordinator::{
-    calculate_address, calculate_hash, handle_email, send_to_modal, validate_email_envelope,
-    BalanceRequest, ValidationStatus,
+    calculate_hash, proof_file_id, use_sender_nonce, validate_email_envelope,
+    CommandValidation, ValidationStatus, MAX_JOB_ATTEMPTS,
 };
 use core::future::Future;
-use db::{
-    get_email_data, get_email_data_from_email, get_pending_and_unvalidated_emails,
-    migrate_email_dbs, set_email_state, update_email_state_with_hash,
-    update_email_state_with_raw_email, EmailData,
-};
+use db::{unix_time, EmailData, JobState, Store};
+use dkim::DnsKeyResolver;
 use dotenv::dotenv;
 use ethers_core::types::U256;
-use http::StatusCode;
+use funds_monitor::{FundsEvent, FundsMonitor};
 use imap_client::{IMAPAuth, ImapClient};
+use proof_artifacts::ProofFileError;
+use prover::prover_from_env;
 use smtp_client::EmailSenderClient;
-use std::{collections::VecDeque, env};
+use strings::funds_expired_reply;
+use submitter::Submitters;
+use std::{
+    collections::{HashSet, VecDeque},
+    env,
+    sync::{Arc, Mutex},
+};
 
-use crate::parse_email::{extract_from, extract_subject};
+use crate::parse_email::ParsedEmail;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -50,7 +69,12 @@ async fn main() -> Result<()> {
                     let dir = &args[3];
                     let nonce = &args[4];
                     println!("Sending to chain!");
-                    chain::send_to_chain(force_localhost, dir, nonce).await?;
+                    if let Err(e) = chain::send_proven_email_to_chain(force_localhost, dir, nonce).await {
+                        if let Some(file_error) = e.downcast_ref::<ProofFileError>() {
+                            println!("Could not load the proof of email {} from {}: {}", nonce, dir, file_error);
+                        }
+                        return Err(e);
+                    }
                 };
                 Ok(())
             }
@@ -59,14 +83,18 @@ async fn main() -> Result<()> {
                 Ok(())
             }
             "migrate" => {
-                // Unused for now
-                migrate_email_dbs().await?;
+                // Safe to run any number of times, each step only moves records that weren't moved yet
+                let store = Store::open_from_env().await?;
+                let rekeyed = store.rekey_emails().await?;
+                println!("Rekeyed {} emails by their SHA-256 id", rekeyed);
+                let migrated = store.migrate_email_dbs().await?;
+                println!("Migrated {} wallets to the salt and nonce model", migrated);
                 Ok(())
             }
-            _ => Err(anyhow!("Invalid function! Use either 'chain' or 'relayer'")),
+            _ => Err(anyhow!("Invalid function! Use 'chain', 'relayer' or 'migrate'")),
         },
         None => Err(anyhow!(
-            "Please provide a function to call! Use either 'chain' or 'relayer'"
+            "Please provide a function to call! Use 'chain', 'relayer' or 'migrate'"
         )),
     }
 }
@@ -122,24 +150,49 @@ async fn run_relayer() -> Result<()> {
         Some(env::var(SMTP_DOMAIN_NAME_KEY)?.as_str()),
     );
     println!("Email receiver constructed with auto-reconnect.");
+    let chains = ChainRegistry::from_env()?;
+    let tokens = TokenRegistry::load(&chains).await;
+    tokens.spawn_refresh(chains.clone());
+    let store = Store::open_from_env().await?;
+    let relayer = RelayerContext {
+        emailer: sender,
+        resolver: Arc::new(DnsKeyResolver::new()?),
+        registry: Arc::new(CommandRegistry::with_default_commands()),
+        prover: prover_from_env(&zk_email_circom_path)?,
+        submitters: Submitters::new(&chains, store.clone()).await?,
+        funds: FundsMonitor::spawn(&chains, &tokens),
+        chains,
+        tokens,
+        store,
+        zk_email_circom_path,
+    };
+    let store = relayer.store.clone();
+    // The ids of the emails being processed, so an email fetched again while its job runs isn't processed twice
+    let in_flight: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
 
-    // Re-queue emails that haven't been fully validated or sent yet
+    // Re-queue emails whose jobs aren't finished, each resumes from the state it was left in
     let mut email_queue = VecDeque::new();
-    let pending_and_unvalidated_emails = get_pending_and_unvalidated_emails().await?;
-    for email_data in pending_and_unvalidated_emails {
+    let unfinished_emails = store.get_unfinished_emails()?;
+    for email_data in unfinished_emails {
+        println!("Resuming email {} from {:?}", calculate_hash(&email_data.body), email_data.job.state);
         email_queue.push_back(email_data);
     }
 
     loop {
         // Process emails in the queue in a nonblocking manner
         while let Some(email_data) = email_queue.pop_front() {
-            let sender_clone = sender.clone();
-            let path_clone = zk_email_circom_path.clone();
+            let email_hash = calculate_hash(&email_data.body);
+            if !in_flight.lock().unwrap().insert(email_hash.clone()) {
+                println!("Email {} is already being processed", email_hash);
+                continue;
+            }
+            let relayer = relayer.clone();
+            let in_flight = in_flight.clone();
             tokio::spawn(async move {
-                let result = process_email(&email_data, &sender_clone, &path_clone).await;
-                if let Err(e) = result {
+                if let Err(e) = process_email(&email_data, &relayer).await {
                     println!("Error processing email: {}", e);
                 }
+                in_flight.lock().unwrap().remove(&email_hash);
             });
         }
 
@@ -151,55 +204,29 @@ async fn run_relayer() -> Result<()> {
         for fetched in fetches.into_iter() {
             for fetch in fetched.iter().into_iter() {
                 if let Some(b) = fetch.body() {
-                    let from_addr: String;
-                    let subject_str: String;
-                    if let Some(e) = fetch.envelope() {
-                        from_addr = {
-                            let tag = &e.from.as_ref();
-                            println!("from {:?}", tag.ok_or(anyhow!("No from"))?[0]);
-                            let former = tag.ok_or(anyhow!("No from"))?[0]
-                                .mailbox
-                                .clone()
-                                .ok_or(anyhow!("No former part of the from address"))?;
-                            let latter = tag.ok_or(anyhow!("No from"))?[0]
-                                .host
-                                .clone()
-                                .ok_or(anyhow!("No latter part of the from address"))?;
-                            let address = format!(
-                                "{}@{}",
-                                String::from_utf8(former.to_vec())?,
-                                String::from_utf8(latter.to_vec())?
-                            );
-                            address
-                        };
-                        println!("from address: {}", from_addr);
-                        subject_str =
-                            String::from_utf8(e.subject.as_ref().unwrap().to_vec()).unwrap();
-                        println!("subject: {}", subject_str);
-                    } else {
-                        println!("no envelope");
-                        break;
-                    }
                     let body = String::from_utf8(b.to_vec())?;
                     println!("body: {}", body);
-
-                    // Insert the email into the database with Unvalidated status
-                    let hash = calculate_hash(&body);
-                    set_email_state(
-                        &body,
-                        &from_addr,
-                        &subject_str,
-                        ValidationStatus::Unvalidated,
-                    )
-                    .await?;
-
-                    // Generate unvalidated EmailData and push it to the validation queue for further processing
-                    let email_data = EmailData {
-                        body: body.clone(),
-                        from: from_addr.clone(),
-                        subject: subject_str.clone(),
-                        state: ValidationStatus::Unvalidated,
+                    let parsed = match ParsedEmail::parse(&body) {
+                        Ok(parsed) => parsed,
+                        Err(e) => {
+                            println!("Could not parse email, skipping: {}", e);
+                            continue;
+                        }
                     };
+                    let from_addr = parsed.from.clone();
+                    let subject_str = parsed.subject.clone();
+                    println!("from address: {}", from_addr);
+                    println!("subject: {}", subject_str);
+
+                    // Insert the email into the database as Received, an email fetched again resumes the job stored for it
+                    let (new, email_data) = store.receive_email(&body, &from_addr, &subject_str)?;
+                    if !new {
+                        if email_data.job.state.is_final() {
+                            println!("Email {} was already handled, skipping", calculate_hash(&body));
+                            continue;
+                        }
+                        println!("Email {} was fetched again, resuming from {:?}", calculate_hash(&body), email_data.job.state);
+                    }
                     email_queue.push_back(email_data);
                 } else {
                     println!("For some reason, each email is parsed twice, and this is the failed parse.")
@@ -209,168 +236,137 @@ async fn run_relayer() -> Result<()> {
     }
 }
 
-/// This function processes an email. It first validates the email envelope and updates the email state to Pending.
-/// Depending on the validation status, it either handles the email, queries the balance, or returns an error.
+/// This function processes an email, resuming its job from the state it is in. Emails whose transaction was already submitted
+/// only wait for it. Any other email is validated, then depending on the validation status, its matched command is executed,
+/// executed once the sender has the balance for it, or the job fails.
 /// The function is asynchronous and returns a Result.
 ///
 /// # Arguments
 ///
-/// * `email_data` - A reference to the EmailData struct containing the email body, from address, subject, and state.
-/// * `sender` - A reference to the EmailSenderClient struct.
-/// * `zk_email_circom_path` - A string slice that holds the path to the zk_email_circom.
+/// * `email_data` - A reference to the EmailData struct containing the email body, from address, subject, and job.
+/// * `relayer` - The services shared by every email the relayer processes.
 ///
 /// # Returns
 ///
 /// * `Result<()>` - The function returns a Result. If the email processing is successful, it returns Ok(()), otherwise it returns an Err.
 ///
 
-async fn process_email(
-    email_data: &EmailData,
-    sender: &EmailSenderClient,
-    zk_email_circom_path: &str,
-) -> Result<()> {
-    // Validates any unvalidated/pending emails, but don't pending (already-validated email replies)
-    let validation = validate_email_envelope(
-        &email_data.body.as_str(),
-        sender,
-        &email_data.from.as_str(),
-        &email_data.subject.as_str(),
-        Some(email_data.state == ValidationStatus::Unvalidated),
+async fn process_email(email_data: &EmailData, relayer: &RelayerContext) -> Result<()> {
+    let email = ParsedEmail::parse(&email_data.body)?;
+    let ctx = relayer.command_context(&email);
+    let store = &relayer.store;
+    let job = &email_data.job;
+    match &job.state {
+        state if state.is_final() => return Ok(()),
+        // Never send an email again whose transaction is already out, just wait for it
+        JobState::Submitted => {
+            println!("Resuming email {} at its submitted transaction {:?}", calculate_hash(&email_data.body), job.tx_hash);
+            let state = match wait_for_submitted_tx(&relayer.submitters, &relayer.emailer, job).await {
+                Ok(()) => {
+                    if let Some(nonce) = job.nonce {
+                        use_sender_nonce(store, &email_data.from, nonce);
+                    }
+                    JobState::Confirmed
+                }
+                Err(e) => JobState::Failed(e.to_string()),
+            };
+            store.update_email_state_with_raw_email(&email_data.body, state)?;
+            return Ok(());
+        }
+        JobState::Proving | JobState::Proved if job.attempts >= MAX_JOB_ATTEMPTS => {
+            let reason = format!("gave up after {} attempts at {:?}", job.attempts, job.state);
+            store.update_email_state_with_raw_email(&email_data.body, JobState::Failed(reason.clone()))?;
+            return Err(anyhow!("Email {} {}", calculate_hash(&email_data.body), reason));
+        }
+        _ => {}
+    }
+
+    // Validates every email it resumes, but only replies to the validation of emails it sees for the first time
+    let (validation, matched) = validate_email_envelope(
+        &ctx,
+        &relayer.registry,
+        Some(job.state == JobState::Received),
     )
-    .await;
-    update_email_state_with_raw_email(&email_data.body, ValidationStatus::Pending).await?;
+    .await
+    .map_err(|error| anyhow!("Error processing email: {}", error))?;
 
-    match validation {
-        Ok((validation_status, salt_sender, salt_receiver, balance_request)) => {
-            // Calculate the nonce used in the filename
-            let file_id = if validation_status == ValidationStatus::Failure
-                || validation_status == ValidationStatus::Unvalidated
-            {
-                String::new()
-            } else {
-                format!(
-                    "({})_({})_({})",
-                    salt_sender.unwrap(),
-                    salt_receiver.unwrap().as_str(),
-                    calculate_hash(&email_data.body)
-                )
-            };
-            println!("File ID/Nonce: {}", file_id);
-            println!("Validation status: {:?}", validation_status);
+    println!("File ID/Nonce: {}", proof_file_id(&validation, &email_data.body, job.nonce));
+    println!("Validation status: {:?}, job state: {:?}", validation.status, job.state);
 
-            let email_handle_result = match validation_status {
-                ValidationStatus::Ready => {
-                    let handled = handle_email(
-                        email_data.body.clone(),
-                        &zk_email_circom_path.clone().to_string(),
-                        Some(file_id),
-                    )
-                    .await;
-                    set_email_state(
-                        &email_data.body,
-                        &email_data.from,
-                        &email_data.subject,
-                        ValidationStatus::Ready,
-                    )
-                    .await?;
-                    handled
-                }
-                ValidationStatus::Pending => {
-                    let BalanceRequest {
-                        address,
-                        amount,
-                        token_name,
-                    } = balance_request.unwrap();
-                    let zk_email_circom_path = zk_email_circom_path.to_string().clone();
-                    set_email_state(
-                        &email_data.body,
-                        &email_data.from,
-                        &email_data.subject,
-                        ValidationStatus::Pending,
-                    )
-                    .await?;
-                    let email_data = email_data.clone();
-                    let validation_future = tokio::task::spawn(async move {
-                        loop {
-                            let valid =
-                                match query_balance(false, address.as_str(), token_name.as_str())
-                                    .await
-                                {
-                                    Ok(balance) => {
-                                        let cloned_amount = amount.clone();
-                                        println!("Balance of address {}: {} {}", address, balance, token_name);
-                                        let amount_f64 =
-                                            cloned_amount.parse::<f64>().unwrap_or_else(|_| 0.0);
-                                        balance >= amount_f64
-                                    }
-                                    Err(error) => {
-                                        println!("error: {}", error);
-                                        false
-                                    }
-                                };
-                            if valid {
-                                break;
-                            }
-                            // Wait between 4 and 60 seconds to query again, staggering queries to avoid alchemy ratelimits
-                            let random_duration = rand::random::<u64>() % 56 + 4;
-                            tokio::time::sleep(tokio::time::Duration::from_secs(random_duration))
-                                .await;
-                        }
+    let matched = match (validation.status, matched) {
+        (ValidationStatus::Ready, Some(matched)) | (ValidationStatus::Pending, Some(matched)) => matched,
+        (status, _) => {
+            let reason = format!("validation failed with status {:?}", status);
+            store.update_email_state_with_raw_email(&email_data.body, JobState::Failed(reason.clone()))?;
+            return Err(anyhow!("Validation failed with status {:?}", status));
+        }
+    };
 
-                        // TODO: Only set state to READY once the email has been handled and we see a tx on etherscan with the right nullifier
-                        match handle_email(
-                            email_data.body.clone(),
-                            &zk_email_circom_path.clone().to_string(),
-                            Some(file_id),
-                        )
-                        .await
-                        {
-                            Ok(_) => {
-                                match set_email_state(
-                                    &email_data.body,
-                                    &email_data.from,
-                                    &email_data.subject,
-                                    ValidationStatus::Ready,
-                                )
-                                .await
-                                {
-                                    Ok(_) => println!("Email handled successfully"),
-                                    Err(e) => println!("Error setting email state: {}", e),
-                                }
-                            }
-                            Err(e) => println!("Error handling email: {}", e),
+    match validation.status {
+        ValidationStatus::Ready => {
+            // Emails resumed while proving keep their state, so a saved proof is picked up
+            let fresh = [JobState::Received, JobState::AwaitingFunds];
+            if fresh.contains(&job.state) && !store.transition_email_state(&email_data.body, &fresh, JobState::Validated)? {
+                println!("Email {} was already moved on", calculate_hash(&email_data.body));
+                return Ok(());
+            }
+            execute(relayer, &ctx, &matched, &validation, &email_data.body).await
+        }
+        ValidationStatus::Pending => {
+            let request = validation.balance_request.clone().ok_or(anyhow!("Pending command has no balance request"))?;
+            // Emails resumed while waiting keep the time they started waiting, so a restart doesn't extend their expiry
+            let since = if job.state == JobState::AwaitingFunds {
+                job.updated_at
+            } else {
+                store.update_email_state_with_raw_email(&email_data.body, JobState::AwaitingFunds)?;
+                unix_time()
+            };
+            match relayer.funds.watch(request.clone(), since)?.await {
+                Ok(FundsEvent::Funded) => {}
+                Ok(FundsEvent::Expired) => {
+                    if store.transition_email_state(&email_data.body, &[JobState::AwaitingFunds], JobState::Expired)? {
+                        let reply = funds_expired_reply(&request.chain, &request.address, &request.amount, &request.token_name, relayer.funds.expiry_secs());
+                        if let Err(e) = relayer.emailer.reply_all(&email, &reply, false) {
+                            println!("Error sending expiry reply: {}", e);
                         }
-                        // });
-                    });
-                    Ok(())
-                }
-                ValidationStatus::Failure => {
-                    set_email_state(
-                        &email_data.body,
-                        &email_data.from,
-                        &email_data.subject,
-                        ValidationStatus::Failure,
-                    )
-                    .await?;
-                    return Err(anyhow!("Validation failed"));
+                    }
+                    return Ok(());
                 }
-                ValidationStatus::Unvalidated => {
-                    // Note that this scenario should never be reached
-                    set_email_state(
-                        &email_data.body,
-                        &email_data.from,
-                        &email_data.subject,
-                        ValidationStatus::Unvalidated,
-                    )
-                    .await?;
-                    return Err(anyhow!("Validation unvalidated"));
+                // The monitor stopped, the job stays AwaitingFunds and is watched again on restart
+                Err(_) => {
+                    println!("Stopped waiting for funds for email {}", calculate_hash(&email_data.body));
+                    return Ok(());
                 }
-            };
-        }
-        Err(error) => {
-            // Handle the error case here
-            return Err(anyhow!("Error processing email: {}", error));
+            }
+
+            // Only one task executes a funded email, even if it was queued twice
+            if !store.transition_email_state(&email_data.body, &[JobState::AwaitingFunds], JobState::Validated)? {
+                println!("Email {} was already moved on", calculate_hash(&email_data.body));
+                return Ok(());
+            }
+            execute(relayer, &ctx, &matched, &validation, &email_data.body).await
         }
+        ValidationStatus::Failure => unreachable!("Failed validations are returned above"),
     }
+}
+
+/// This function executes a validated email's command and stores the state it left the job in.
+/// A command that errors fails the job, so it isn't resumed again.
+async fn execute(
+    relayer: &RelayerContext,
+    ctx: &CommandContext<'_>,
+    matched: &MatchedCommand,
+    validation: &CommandValidation,
+    raw_email: &str,
+) -> Result<()> {
+    let state = match matched.handler.execute(ctx, &matched.command, validation).await {
+        Ok(state) => state,
+        Err(e) => {
+            relayer.store.update_email_state_with_raw_email(raw_email, JobState::Failed(e.to_string()))?;
+            return Err(e);
+        }
+    };
+    relayer.store.update_email_state_with_raw_email(raw_email, state)?;
+    println!("Email handled successfully");
     Ok(())
 }
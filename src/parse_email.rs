@@ -1,128 +1,134 @@
-// use cfdkim::*;
-use futures::executor::block_on;
-// use mail_auth::common::verify::VerifySignature;
-use std::error::Error;
-// use mail_auth::Error;
 use anyhow::{anyhow, Result};
-// use mail_auth::{AuthenticatedMessage, DkimResult, Resolver};
-use sha2::{self, Digest, Sha256};
-use std::env;
-use trust_dns_resolver::config::{ResolverConfig, ResolverOpts};
-use trust_dns_resolver::proto::rr::{RData, RecordType};
-use trust_dns_resolver::AsyncResolver;
-
-// pub async fn parse_external_eml(
-//     raw_email: &String,
-// ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>), Box<dyn Error>> {
-//     let resolver = Resolver::new_cloudflare_tls().unwrap();
-//     let authenticated_message = AuthenticatedMessage::parse(raw_email.as_bytes()).unwrap();
-
-//     // Validate signature
-//     let result = resolver.verify_dkim(&authenticated_message).await;
-//     assert!(result.iter().all(|s| s.result() == &DkimResult::Pass));
-//     println!("Result: {:?}", result[0]);
-
-//     // Extract the parsed + canonicalized headers along with the signed value for them
-//     let (parsed_headers, signed_parsed_headers) =
-//         authenticated_message.get_canonicalized_header().unwrap();
-//     let signature = result[0].signature().unwrap();
-
-//     let body_bytes = &authenticated_message.raw_message[authenticated_message.body_offset..];
-//     let hash = Sha256::digest(&body_bytes);
-//     println!("Hashes {:?} {:?}: ", hash, signature.body_hash());
-
-//     #[warn(deprecated)]
-//     assert_eq!(
-//         base64::encode(hash),
-//         base64::encode(signature.body_hash()),
-//         "Extracted body hash and calculated body hash do not match!"
-//     );
-
-//     // Get DNS TXT record
-//     let dkim_domain = signature.domain_key();
-//     println!("Domain: {dkim_domain:?}");
-//     let key = get_public_key(dkim_domain.as_str()).await;
-//     println!("Public key of domain {key:?}");
-//     // Convert String key to [u8]
-//     let unwrapped_key = key.unwrap();
-//     let key_bytes = unwrapped_key.as_bytes();
-//     // let dkim_public_key = cfdkim::lookup_dkim_public_key(key).unwrap();
-//     // let rsa_public_key = dkim_public_key.rsa_public_key();
-
-//     // Convert body_bytes to a vector
-//     let body_bytes_vec = body_bytes.to_vec();
-//     Ok((
-//         parsed_headers.clone(),
-//         body_bytes.to_vec().clone(),
-//         key_bytes.to_vec().clone(),
-//         signature.clone().signature().to_vec().clone(),
-//     ))
-//     // signature.clone().signature()))
-// }
-
-// async fn get_public_key(domain: &str) -> Result<String, Box<dyn std::error::Error>> {
-//     let resolver = AsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())?;
-//     let response = resolver.lookup(domain, RecordType::TXT).await?;
-//     println!("Response: {:?}", response);
-//     for record in response.iter() {
-//         if let RData::TXT(ref txt) = *record {
-//             let txt_data = txt.txt_data();
-//             // Format txt data to convert from u8 to string
-//             let data_bytes: Vec<u8> = txt_data.iter().flat_map(|b| b.as_ref()).cloned().collect();
-//             let data = String::from_utf8_lossy(&data_bytes);
-//             println!("Data from txt record: {:?}", data);
-
-//             if data.contains("k=rsa;") {
-//                 let parts: Vec<_> = data.split("; ").collect();
-//                 for part in parts {
-//                     if part.starts_with("p=") {
-//                         assert_eq!(part.strip_prefix("p=").unwrap(), part[2..].to_string());
-//                         return Ok(part.strip_prefix("p=").unwrap().to_string());
-//                     }
-//                 }
-//             }
-//         }
-//     }
-
-//     Err("RSA public key not found.".into())
-// }
-
-pub fn extract_from(email: &str) -> Result<String, Box<dyn Error>> {
-    let mut from_addresses: Vec<String> = Vec::new();
-    let mut email_lines = email.lines();
-    while let Some(line) = email_lines.next() {
-        if line.starts_with("From:") {
-            let from_line = line;
-            let email_start = from_line.find('<');
-            let email_end = from_line.find('>');
-            if let (Some(start), Some(end)) = (email_start, email_end) {
-                let from = &from_line[start + 1..end];
-                println!("From email address: {}", from);
-                from_addresses.push(from.to_string());
+use mailparse::{addrparse_header, parse_mail, MailAddr, MailHeaderMap, ParsedMail};
+use std::error::Error;
+
+/// An incoming email parsed once into its RFC 5322 headers and MIME body.
+/// Header values are unfolded and RFC 2047 encoded-words are decoded, and addresses are bare (no display names).
+#[derive(Debug, Clone)]
+pub struct ParsedEmail {
+    pub raw: String,
+    pub from: String,
+    pub from_name: Option<String>,
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub subject: String,
+    pub message_id: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body_parts: Vec<BodyPart>,
+}
+
+/// A leaf (non-multipart) MIME part, with its transfer encoding and charset already decoded.
+#[derive(Debug, Clone)]
+pub struct BodyPart {
+    pub mimetype: String,
+    pub text: String,
+}
+
+impl ParsedEmail {
+    pub fn parse(raw_email: &str) -> Result<Self> {
+        let mail = parse_mail(raw_email.as_bytes())?;
+        let headers: Vec<(String, String)> = mail
+            .headers
+            .iter()
+            .map(|h| (h.get_key(), h.get_value()))
+            .collect();
+
+        // DKIM only signs the bottom-most From, so an email with another one prepended can't say who sent it
+        let from_headers = mail.headers.get_all_headers("From");
+        if from_headers.len() > 1 {
+            return Err(anyhow!("Email has {} From headers", from_headers.len()));
+        }
+        let (from, from_name) = match from_headers.first() {
+            Some(header) => {
+                let mut mailboxes = mailboxes_of(&addrparse_header(header)?);
+                if mailboxes.is_empty() {
+                    return Err(anyhow!("From header has no address"));
+                }
+                mailboxes.remove(0)
+            }
+            None => return Err(anyhow!("Email has no From header")),
+        };
+        let address_list = |name: &str| -> Result<Vec<String>> {
+            let mut addresses = vec![];
+            for header in mail.headers.get_all_headers(name) {
+                let list = addrparse_header(header)?;
+                addresses.extend(mailboxes_of(&list).into_iter().map(|(address, _)| address));
+            }
+            Ok(addresses)
+        };
+        let to = address_list("To")?;
+        let cc = address_list("Cc")?;
+
+        let subject = mail.headers.get_first_value("Subject").unwrap_or_default();
+        let message_id = mail.headers.get_first_value("Message-ID").and_then(|id| {
+            let id = id.trim().trim_start_matches('<').trim_end_matches('>').trim();
+            if id.is_empty() {
+                None
             } else {
-                let from = from_line.trim_start_matches("From: ").to_string();
-                println!("From email address: {}", from);
-                from_addresses.push(from);
+                Some(id.to_string())
             }
-        }
+        });
+
+        let mut body_parts = vec![];
+        collect_body_parts(&mail, &mut body_parts)?;
+
+        Ok(Self {
+            raw: raw_email.to_string(),
+            from,
+            from_name,
+            to,
+            cc,
+            subject: subject.trim().to_string(),
+            message_id,
+            headers,
+            body_parts,
+        })
     }
 
-    if !from_addresses.is_empty() {
-        return Ok(from_addresses.join(", "));
+    /// Returns the first value of the header with the given (case-insensitive) name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the text/plain body, falling back to the first text/html part.
+    pub fn text_body(&self) -> Option<&str> {
+        self.body_parts
+            .iter()
+            .find(|part| part.mimetype == "text/plain")
+            .or_else(|| self.body_parts.iter().find(|part| part.mimetype == "text/html"))
+            .map(|part| part.text.as_str())
     }
-    Err("Could not find from email address".into())
 }
 
-pub fn extract_subject(email: &str) -> Result<String, Box<dyn Error>> {
-    if let Some(subject_start) = email.find("Subject:") {
-        let subject_line_start = &email[subject_start..];
-        if let Some(subject_end) = subject_line_start.find("\r\n") {
-            let subject_line = &subject_line_start[..subject_end];
-            println!("Subject line: {}", subject_line);
-            return Ok(subject_line.to_string());
+fn mailboxes_of(list: &mailparse::MailAddrList) -> Vec<(String, Option<String>)> {
+    let mut mailboxes = vec![];
+    for addr in list.iter() {
+        match addr {
+            MailAddr::Single(info) => mailboxes.push((info.addr.clone(), info.display_name.clone())),
+            MailAddr::Group(group) => {
+                for info in group.addrs.iter() {
+                    mailboxes.push((info.addr.clone(), info.display_name.clone()));
+                }
+            }
         }
     }
-    Err("Could not find subject".into())
+    mailboxes
+}
+
+fn collect_body_parts(mail: &ParsedMail, parts: &mut Vec<BodyPart>) -> Result<()> {
+    if mail.subparts.is_empty() {
+        parts.push(BodyPart {
+            mimetype: mail.ctype.mimetype.to_lowercase(),
+            text: mail.get_body()?,
+        });
+    }
+    for subpart in mail.subparts.iter() {
+        collect_body_parts(subpart, parts)?;
+    }
+    Ok(())
 }
 
 pub fn extract_recipient_from_subject(original_subject: &str) -> Result<String, Box<dyn Error>> {
@@ -136,35 +142,29 @@ pub fn extract_recipient_from_subject(original_subject: &str) -> Result<String,
     Err("Could not find email in subject".into())
 }
 
-pub fn extract_message_id(email: &str) -> Result<String, Box<dyn Error>> {
-    for message_id in ["Message-ID:", "Message-Id:"] {
-        if let Some(message_id_start) = email.find(message_id) {
-            let message_id_line_start = &email[message_id_start..];
-            println!("Message id line start: {:?}", message_id_line_start);
-            if let Some(message_id_end) = message_id_line_start.find("\r\n") {
-                let message_id_line = &message_id_line_start[..message_id_end];
-                let email_start = message_id_line.find('<');
-                let email_end = message_id_line.find('>');
-                println!("{:?} -> {:?}", email_start, email_end);
-                if let (Some(start), Some(end)) = (email_start, email_end) {
-                    let message_id = &message_id_line[start + 1..end];
-                    println!("message_id value: {}", message_id);
-                    return Ok(message_id.to_string());
-                }
-            }
+/// Splits a trailing "on <chain>" off a subject, e.g. "Send 5 USDC to a@b.com on Arbitrum", returning the rest of the subject
+/// and the chain name, if any. Command parsers only ever see the rest.
+pub fn split_chain_suffix(subject_str: &str) -> (&str, Option<String>) {
+    let chain_regex = regex::Regex::new(r"(?i)\s+on\s+([a-z][a-z0-9 ]*?)\s*$").unwrap();
+    match chain_regex.captures(subject_str) {
+        Some(captures) => {
+            let whole = captures.get(0).unwrap();
+            let chain_name = captures.get(1).map(|m| m.as_str().to_string());
+            (&subject_str[..whole.start()], chain_name)
         }
+        None => (subject_str, None),
     }
-    Err("Could not find message_id value".into())
 }
 
 pub fn parse_subject_for_send(subject_str: &str) -> Result<(String, String, String), Box<dyn Error + Send>> {
-    let subject_regex = regex::Regex::new(r"(?i)([Ss]end|[Tt]ransfer) ?\$?(\d+(\.\d+)?) (eth|usdc|dai|test|ETH|USDC|DAI|TEST|Dai|Eth|Usdc|Test) to (.+@.+(\..+)+)").unwrap();
+    let subject_regex = regex::Regex::new(r"(?i)([Ss]end|[Tt]ransfer) ?\$?(\d+(\.\d+)?) ([a-z]+) to (.+@.+(\..+)+)").unwrap();
     if subject_regex.is_match(subject_str) {
         let captures = subject_regex.captures(subject_str);
         if let Some(captures) = captures {
             // Extract the amount, currency and recipient from the captures
             let amount = captures.get(2).map_or("", |m| m.as_str()).to_string();
-            let currency = captures.get(4).map_or("", |m| m.as_str()).to_string();
+            // Any asset name is accepted here, so unsupported ones can be rejected with a clear reply during validation
+            let currency = captures.get(4).map_or("", |m| m.as_str()).to_uppercase();
             let recipient = captures.get(5).map_or("", |m| m.as_str()).to_string();
             println!(
                 "Parsed subject: Amount: {}, Currency: {}, Recipient: {}",
@@ -176,16 +176,141 @@ pub fn parse_subject_for_send(subject_str: &str) -> Result<(String, String, Stri
     Err(anyhow!("Could not parse subject").into())
 }
 
+/// Parses "Balance" or "Balance DAI", returning the token to filter on, if any.
+pub fn parse_subject_for_balance(subject_str: &str) -> Result<Option<String>, Box<dyn Error + Send>> {
+    let subject_regex = regex::Regex::new(r"(?i)^\s*balance(?:\s+(?:of\s+)?\$?([a-z]+))?\s*$").unwrap();
+    if let Some(captures) = subject_regex.captures(subject_str) {
+        let token_name = captures.get(1).map(|m| m.as_str().to_uppercase());
+        println!("Parsed subject: Balance of {:?}", token_name);
+        return Ok(token_name);
+    }
+    Err(anyhow!("Could not parse subject").into())
+}
+
+/// Where a migrate command moves the wallet's tokens to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MigrateDestination {
+    /// The wallet of another email address, which is created if needed
+    Email(String),
+    /// An external (EOA or contract) address
+    Wallet(String),
+}
+
+/// Parses "Withdraw all DAI to 0x..." or "Move all USDC to bob@x.com", returning the token and the destination.
+pub fn parse_subject_for_migrate(subject_str: &str) -> Result<(String, MigrateDestination), Box<dyn Error + Send>> {
+    let subject_regex = regex::Regex::new(r"(?i)^\s*(?:withdraw|move|migrate)\s+all\s+(?:of\s+(?:my\s+)?)?\$?([a-z]+)\s+to\s+(\S+)\s*$").unwrap();
+    let wallet_regex = regex::Regex::new(r"^0x[0-9a-fA-F]{40}$").unwrap();
+    let email_regex = regex::Regex::new(r"^[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}$").unwrap();
+    if let Some(captures) = subject_regex.captures(subject_str) {
+        let token_name = captures.get(1).map_or("", |m| m.as_str()).to_uppercase();
+        let destination = captures.get(2).map_or("", |m| m.as_str());
+        let destination = if wallet_regex.is_match(destination) {
+            MigrateDestination::Wallet(destination.to_string())
+        } else if email_regex.is_match(destination) {
+            MigrateDestination::Email(destination.to_string())
+        } else {
+            return Err(anyhow!("Migrate destination is neither an address nor an email").into());
+        };
+        println!("Parsed subject: Migrate all {} to {:?}", token_name, destination);
+        return Ok((token_name, destination));
+    }
+    Err(anyhow!("Could not parse subject").into())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
-    // #[tokio::test]
-    // async fn get_public_key_test() {
-    //     let domain = "20210112._domainkey.gmail.com.";
-    //     match get_public_key(domain).await {
-    //         Ok(key) => println!("RSA public key: {}", key),
-    //         Err(e) => panic!("Error getting public key: {}", e),
-    //     }
-    // }
+    #[test]
+    fn test_parse_folded_and_encoded_headers() {
+        let raw = "from: =?UTF-8?Q?Al=C3=AFce?= <alice@example.com>\r\n\
+            TO: relayer@sendeth.org,\r\n Bob <bob@example.org>\r\n\
+            subject: =?UTF-8?B?U2VuZCAxIERBSSB0bw==?=\r\n bob@example.org\r\n\
+            Message-Id: <CAfixture0002@mail.example.com>\r\n\
+            \r\n\
+            Subject: Send 100 DAI to mallory@attacker.org\r\n";
+        let email = ParsedEmail::parse(raw).unwrap();
+        assert_eq!(email.from, "alice@example.com");
+        assert_eq!(email.from_name.as_deref(), Some("Al\u{ef}ce"));
+        assert_eq!(email.to, vec!["relayer@sendeth.org", "bob@example.org"]);
+        assert_eq!(email.subject, "Send 1 DAI to bob@example.org");
+        assert_eq!(email.message_id.as_deref(), Some("CAfixture0002@mail.example.com"));
+        assert_eq!(email.text_body(), Some("Subject: Send 100 DAI to mallory@attacker.org\r\n"));
+    }
+
+    #[test]
+    fn test_parse_rejects_prepended_from() {
+        let raw = "From: Alice <alice@example.com>\r\n\
+            Subject: Balance\r\n\
+            \r\n\
+            Hi\r\n";
+        assert!(ParsedEmail::parse(raw).is_ok());
+        let prepended = format!("From: Mallory <mallory@attacker.org>\r\n{}", raw);
+        assert!(ParsedEmail::parse(&prepended).is_err());
+    }
+
+    #[test]
+    fn test_parse_multipart_body() {
+        let raw = "From: alice@example.com\r\n\
+            Subject: Balance\r\n\
+            Content-Type: multipart/alternative; boundary=\"b1\"\r\n\
+            \r\n\
+            --b1\r\n\
+            Content-Type: text/html; charset=UTF-8\r\n\
+            \r\n\
+            <p>hi</p>\r\n\
+            --b1\r\n\
+            Content-Type: text/plain; charset=UTF-8\r\n\
+            Content-Transfer-Encoding: base64\r\n\
+            \r\n\
+            aGk=\r\n\
+            --b1--\r\n";
+        let email = ParsedEmail::parse(raw).unwrap();
+        assert_eq!(email.body_parts.len(), 2);
+        assert_eq!(email.text_body(), Some("hi"));
+        assert_eq!(email.message_id, None);
+    }
+
+    #[test]
+    fn test_parse_subject_for_send() {
+        let (amount, currency, recipient) = parse_subject_for_send("Send 0.01 eth to bob@example.org").unwrap();
+        assert_eq!((amount.as_str(), currency.as_str(), recipient.as_str()), ("0.01", "ETH", "bob@example.org"));
+        let (_, currency, _) = parse_subject_for_send("Transfer $5 Doge to bob@example.org").unwrap();
+        assert_eq!(currency, "DOGE");
+        assert!(parse_subject_for_send("Send all DAI to bob@example.org").is_err());
+    }
+
+    #[test]
+    fn test_split_chain_suffix() {
+        assert_eq!(
+            split_chain_suffix("Send 5 USDC to a@b.com on Arbitrum"),
+            ("Send 5 USDC to a@b.com", Some("Arbitrum".to_string()))
+        );
+        assert_eq!(split_chain_suffix("Balance on arbitrum one "), ("Balance", Some("arbitrum one".to_string())));
+        assert_eq!(split_chain_suffix("Send 5 USDC to a@b.com"), ("Send 5 USDC to a@b.com", None));
+        assert_eq!(split_chain_suffix("Send 5 USDC to bob@on.com"), ("Send 5 USDC to bob@on.com", None));
+    }
+
+    #[test]
+    fn test_parse_subject_for_balance() {
+        assert_eq!(parse_subject_for_balance("Balance").unwrap(), None);
+        assert_eq!(parse_subject_for_balance(" balance dai ").unwrap(), Some("DAI".to_string()));
+        assert!(parse_subject_for_balance("Balance DAI to bob@example.org").is_err());
+        assert!(parse_subject_for_balance("Send 1 DAI to bob@example.org").is_err());
+    }
+
+    #[test]
+    fn test_parse_subject_for_migrate() {
+        let wallet = "0x11fE4B6AE13d2a6055C8D9cF65c55bac32B5d844";
+        assert_eq!(
+            parse_subject_for_migrate(&format!("Withdraw all DAI to {}", wallet)).unwrap(),
+            ("DAI".to_string(), MigrateDestination::Wallet(wallet.to_string()))
+        );
+        assert_eq!(
+            parse_subject_for_migrate("move all usdc to bob@x.com").unwrap(),
+            ("USDC".to_string(), MigrateDestination::Email("bob@x.com".to_string()))
+        );
+        assert!(parse_subject_for_migrate("Withdraw all DAI to 0x1234").is_err());
+        assert!(parse_subject_for_migrate("Withdraw 5 DAI to bob@x.com").is_err());
+    }
 }
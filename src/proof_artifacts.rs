@@ -0,0 +1,185 @@
+use crate::chain::CircomCalldata;
+use crate::circuit_input::{CircuitParams, PUBLIC_SIGNALS};
+use ethers::core::types::U256;
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// A Groth16 proof and its public signals, as snarkjs and rapidsnark write them to disk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProofArtifacts {
+    pub pi_a: [U256; 2],
+    /// G2 coordinates as [c0, c1], the order snarkjs writes them in
+    pub pi_b: [[U256; 2]; 2],
+    pub pi_c: [U256; 2],
+    pub signals: Vec<U256>,
+}
+
+/// Why a proof's files couldn't be loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProofFileError {
+    /// The file doesn't exist, usually because the prover didn't finish
+    Missing { path: String },
+    Unreadable { path: String, error: String },
+    /// The file isn't JSON, e.g. because it was read while still being written
+    InvalidJson { path: String, error: String },
+    /// A field is missing or isn't a decimal number
+    InvalidField { path: String, field: String, error: String },
+    /// The proof has a different number of public signals than the circuit
+    SignalCount { path: String, expected: usize, found: usize },
+}
+
+impl ProofFileError {
+    /// Whether proving the email again may fix it. Proofs of the wrong shape come from a mismatched circuit and won't.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Missing { .. } | Self::Unreadable { .. } | Self::InvalidJson { .. })
+    }
+}
+
+impl fmt::Display for ProofFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing { path } => write!(f, "{} does not exist", path),
+            Self::Unreadable { path, error } => write!(f, "could not read {}: {}", path, error),
+            Self::InvalidJson { path, error } => write!(f, "{} is not valid JSON: {}", path, error),
+            Self::InvalidField { path, field, error } => write!(f, "{} in {} {}", field, path, error),
+            Self::SignalCount { path, expected, found } => {
+                write!(f, "{} has {} public signals, the circuit has {}", path, found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProofFileError {}
+
+impl ProofArtifacts {
+    /// Loads a proof and its public signals, checking that there are as many signals as the circuit has.
+    pub fn load(proof_path: &str, public_path: &str, params: &CircuitParams) -> Result<Self, ProofFileError> {
+        let proof = read_json(proof_path)?;
+        let public = read_json(public_path)?;
+
+        let point = |field: &str| -> Result<[U256; 2], ProofFileError> {
+            Ok([
+                decimal_at(proof_path, &format!("{}[0]", field), &proof[field][0])?,
+                decimal_at(proof_path, &format!("{}[1]", field), &proof[field][1])?,
+            ])
+        };
+        let pi_b_row = |i: usize| -> Result<[U256; 2], ProofFileError> {
+            Ok([
+                decimal_at(proof_path, &format!("pi_b[{}][0]", i), &proof["pi_b"][i][0])?,
+                decimal_at(proof_path, &format!("pi_b[{}][1]", i), &proof["pi_b"][i][1])?,
+            ])
+        };
+        let pi_a = point("pi_a")?;
+        let pi_b = [pi_b_row(0)?, pi_b_row(1)?];
+        let pi_c = point("pi_c")?;
+
+        let signals = public
+            .as_array()
+            .ok_or(ProofFileError::InvalidField {
+                path: public_path.to_string(),
+                field: "public signals".to_string(),
+                error: "is not an array".to_string(),
+            })?
+            .iter()
+            .enumerate()
+            .map(|(i, signal)| decimal_at(public_path, &format!("signal {}", i), signal))
+            .collect::<Result<Vec<_>, _>>()?;
+        if signals.len() != params.public_signals {
+            return Err(ProofFileError::SignalCount {
+                path: public_path.to_string(),
+                expected: params.public_signals,
+                found: signals.len(),
+            });
+        }
+        Ok(Self { pi_a, pi_b, pi_c, signals })
+    }
+
+    /// Loads the `rapidsnark_proof_<nonce>.json` and `rapidsnark_public_<nonce>.json` the provers write to a directory.
+    pub fn load_from_dir(dir: &str, nonce: &str, params: &CircuitParams) -> Result<Self, ProofFileError> {
+        let dir = Path::new(dir);
+        let proof_path = dir.join(format!("rapidsnark_proof_{}.json", nonce));
+        let public_path = dir.join(format!("rapidsnark_public_{}.json", nonce));
+        Self::load(&proof_path.to_string_lossy(), &public_path.to_string_lossy(), params)
+    }
+
+    /// The arguments the wallet contract takes, with the G2 coordinates swapped to the [c1, c0] order it expects.
+    pub fn to_calldata(&self) -> Result<CircomCalldata, ProofFileError> {
+        let signals = self.signals.as_slice().try_into().map_err(|_| ProofFileError::SignalCount {
+            path: "calldata".to_string(),
+            expected: PUBLIC_SIGNALS,
+            found: self.signals.len(),
+        })?;
+        Ok(CircomCalldata {
+            pi_a: self.pi_a,
+            pi_b: self.pi_b.map(|[c0, c1]| [c1, c0]),
+            pi_c: self.pi_c,
+            signals,
+        })
+    }
+}
+
+fn read_json(path: &str) -> Result<Value, ProofFileError> {
+    let json = fs::read_to_string(path).map_err(|e| match e.kind() {
+        ErrorKind::NotFound => ProofFileError::Missing { path: path.to_string() },
+        _ => ProofFileError::Unreadable {
+            path: path.to_string(),
+            error: e.to_string(),
+        },
+    })?;
+    serde_json::from_str(&json).map_err(|e| ProofFileError::InvalidJson {
+        path: path.to_string(),
+        error: e.to_string(),
+    })
+}
+
+fn decimal_at(path: &str, field: &str, value: &Value) -> Result<U256, ProofFileError> {
+    let invalid = |error: String| ProofFileError::InvalidField {
+        path: path.to_string(),
+        field: field.to_string(),
+        error,
+    };
+    let decimal = match value {
+        Value::Null => return Err(invalid("is missing".to_string())),
+        Value::String(decimal) => decimal,
+        other => return Err(invalid(format!("is {}, not a decimal string", other))),
+    };
+    U256::from_dec_str(decimal).map_err(|e| invalid(format!("is not a 256 bit decimal: {}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_load_proof_artifacts() {
+        let params = CircuitParams::default();
+        let artifacts = ProofArtifacts::load_from_dir("fixtures/groth16", "1", &params).unwrap();
+        assert_eq!(artifacts.signals.len(), PUBLIC_SIGNALS);
+        assert_eq!(artifacts.to_calldata().unwrap().pi_b[0], [artifacts.pi_b[0][1], artifacts.pi_b[0][0]]);
+
+        let fewer_signals = CircuitParams {
+            public_signals: 3,
+            ..params
+        };
+        let error = ProofArtifacts::load_from_dir("fixtures/groth16", "1", &fewer_signals).unwrap_err();
+        assert!(matches!(error, ProofFileError::SignalCount { expected: 3, found: 27, .. }));
+        assert!(!error.is_retryable());
+
+        let error = ProofArtifacts::load_from_dir("fixtures/groth16", "missing", &params).unwrap_err();
+        assert!(matches!(error, ProofFileError::Missing { .. }));
+        assert!(error.is_retryable());
+
+        let dir = std::env::temp_dir().join("relayer_proof_artifacts_test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy("fixtures/groth16/rapidsnark_public_1.json", dir.join("rapidsnark_public_1.json")).unwrap();
+        fs::write(dir.join("rapidsnark_proof_1.json"), r#"{"pi_a": ["1", 2], "pi_b": [], "pi_c": []}"#).unwrap();
+        let error = ProofArtifacts::load_from_dir(&dir.to_string_lossy(), "1", &params).unwrap_err();
+        assert_eq!(error.to_string(), format!("pi_a[1] in {} is 2, not a decimal string", dir.join("rapidsnark_proof_1.json").display()));
+        fs::write(dir.join("rapidsnark_proof_1.json"), r#"{"pi_a": ["1""#).unwrap();
+        let error = ProofArtifacts::load_from_dir(&dir.to_string_lossy(), "1", &params).unwrap_err();
+        assert!(matches!(error, ProofFileError::InvalidJson { .. }));
+    }
+}
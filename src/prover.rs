@@ -0,0 +1,335 @@
+use crate::chain::CircomCalldata;
+use crate::circuit_input::{CircuitInput, CircuitParams};
+use crate::config::{CIRCUIT_NAME_KEY, INCOMING_EML_PATH, PROOF_DIR_KEY, PROVER_KEY, PROVER_URL_KEY, RAPIDSNARK_PATH_KEY, VERIFICATION_KEY_PATH_KEY};
+use crate::proof_artifacts::{ProofArtifacts, ProofFileError};
+use crate::verifier::ProofVerifier;
+use crate::witness::WitnessCalculator;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::sync::Arc;
+use tokio::process::Command;
+
+/// Proves an email that the relayer saved as wallet_{nonce}.eml, returning the proof and public signals to send to chain.
+/// Local provers compute the witness of the given circuit input first; remote provers are handed the raw email.
+#[async_trait]
+pub trait Prover: Send + Sync {
+    /// Short name used in logs.
+    fn name(&self) -> &'static str;
+    async fn prove(&self, nonce: &str, input: &CircuitInput) -> Result<CircomCalldata>;
+    /// Loads the proof of an email proven before, e.g. to send it after a restart without proving it again.
+    fn load_proof(&self, nonce: &str) -> Result<CircomCalldata>;
+}
+
+/// How many times an email is proven before giving up on proofs that don't verify
+pub const PROOF_ATTEMPTS: usize = 3;
+
+/// Builds the prover chosen by PROVER: "rapidsnark" (the default), "snarkjs", or "http" to call PROVER_URL.
+/// Whichever it is, its proofs are verified against the circuit's verification key before they are used.
+pub fn prover_from_env(zk_email_circom_path: &str) -> Result<Arc<dyn Prover>> {
+    let files = CircuitFiles::from_env(zk_email_circom_path);
+    let verifier = ProofVerifier::load(&files.verification_key_path())?;
+    let prover: Arc<dyn Prover> = match env::var(PROVER_KEY).unwrap_or("rapidsnark".to_string()).as_str() {
+        "rapidsnark" => {
+            let binary = env::var(RAPIDSNARK_PATH_KEY)
+                .unwrap_or(format!("{}/../rapidsnark/build/prover", files.zk_email_path));
+            let witness = Arc::new(WitnessCalculator::load(&files.wasm_path())?);
+            Arc::new(RapidsnarkProver { files, witness, binary })
+        }
+        "snarkjs" => {
+            let witness = Arc::new(WitnessCalculator::load(&files.wasm_path())?);
+            Arc::new(SnarkjsProver { files, witness })
+        }
+        "http" => {
+            let url = env::var(PROVER_URL_KEY)
+                .map_err(|_| anyhow!("The {} environment variable must be set for the http prover", PROVER_URL_KEY))?;
+            Arc::new(HttpProver {
+                files,
+                url,
+                client: Client::new(),
+            })
+        }
+        other => return Err(anyhow!("Unknown prover {:?}, use rapidsnark, snarkjs or http", other)),
+    };
+    println!("Proving emails with {}", prover.name());
+    Ok(Arc::new(VerifiedProver { inner: prover, verifier }))
+}
+
+/// Where the circuit's build artifacts live, and where each email's eml, input, witness and proof files go.
+#[derive(Debug, Clone)]
+pub struct CircuitFiles {
+    pub zk_email_path: String,
+    pub circuit_name: String,
+    pub eml_dir: String,
+    pub proof_dir: String,
+}
+
+impl CircuitFiles {
+    pub fn from_env(zk_email_circom_path: &str) -> Self {
+        Self {
+            zk_email_path: zk_email_circom_path.trim_end_matches('/').to_string(),
+            circuit_name: env::var(CIRCUIT_NAME_KEY).unwrap_or("wallet".to_string()),
+            eml_dir: Self::eml_dir(),
+            proof_dir: env::var(PROOF_DIR_KEY).unwrap_or("./proofs".to_string()).trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// The directory emails are saved to for proving and read back from when replying: INCOMING_EML_PATH, or ./received_eml.
+    pub fn eml_dir() -> String {
+        env::var(INCOMING_EML_PATH).unwrap_or("./received_eml".to_string()).trim_end_matches('/').to_string()
+    }
+
+    fn build_dir(&self) -> String {
+        format!("{}/build/{}", self.zk_email_path, self.circuit_name)
+    }
+
+    pub fn eml_path(&self, nonce: &str) -> String {
+        format!("{}/wallet_{}.eml", self.eml_dir, nonce)
+    }
+
+    pub fn input_path(&self, nonce: &str) -> String {
+        format!("{}/input_{}.json", self.eml_dir, nonce)
+    }
+
+    pub fn witness_path(&self, nonce: &str) -> String {
+        format!("{}/witness_{}.wtns", self.build_dir(), nonce)
+    }
+
+    pub fn wasm_path(&self) -> String {
+        format!("{}/{}_js/{}.wasm", self.build_dir(), self.circuit_name, self.circuit_name)
+    }
+
+    /// VERIFICATION_KEY_PATH if set, so remote provers can be checked without the circuit's build directory.
+    pub fn verification_key_path(&self) -> String {
+        env::var(VERIFICATION_KEY_PATH_KEY).unwrap_or(format!("{}/verification_key.json", self.build_dir()))
+    }
+
+    pub fn zkey_path(&self) -> String {
+        format!("{}/{}.zkey", self.build_dir(), self.circuit_name)
+    }
+
+    pub fn proof_path(&self, nonce: &str) -> String {
+        format!("{}/rapidsnark_proof_{}.json", self.proof_dir, nonce)
+    }
+
+    pub fn public_path(&self, nonce: &str) -> String {
+        format!("{}/rapidsnark_public_{}.json", self.proof_dir, nonce)
+    }
+
+    fn write_input(&self, nonce: &str, input: &CircuitInput) -> Result<()> {
+        fs::write(self.input_path(nonce), serde_json::to_vec(input)?)?;
+        Ok(())
+    }
+
+    /// Computes the witness of an email's input with the circuit's wasm, off the async runtime, and writes it as .wtns.
+    async fn write_witness(&self, witness: &Arc<WitnessCalculator>, nonce: &str, input: &CircuitInput) -> Result<()> {
+        let input = serde_json::to_value(input)?;
+        let calculator = witness.clone();
+        println!("Calculating witness for email {}", nonce);
+        let witness = tokio::task::spawn_blocking(move || calculator.calculate(&input)).await??;
+        fs::write(self.witness_path(nonce), witness.to_wtns())?;
+        Ok(())
+    }
+
+    pub fn load_proof(&self, nonce: &str) -> Result<CircomCalldata> {
+        let artifacts = ProofArtifacts::load(&self.proof_path(nonce), &self.public_path(nonce), &CircuitParams::default())?;
+        Ok(artifacts.to_calldata()?)
+    }
+}
+
+/// Runs one step of the proving pipeline, failing with its stderr if it exits unsuccessfully.
+async fn run_step(step: &str, command: &mut Command) -> Result<()> {
+    println!("Running {}", step);
+    let output = command
+        .output()
+        .await
+        .map_err(|e| anyhow!("Could not start {}: {}", step, e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} failed with {}: {}",
+            step,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Proves locally with the rapidsnark binary, about 10x faster than snarkjs.
+pub struct RapidsnarkProver {
+    files: CircuitFiles,
+    witness: Arc<WitnessCalculator>,
+    binary: String,
+}
+
+#[async_trait]
+impl Prover for RapidsnarkProver {
+    fn name(&self) -> &'static str {
+        "rapidsnark"
+    }
+
+    async fn prove(&self, nonce: &str, input: &CircuitInput) -> Result<CircomCalldata> {
+        fs::create_dir_all(&self.files.proof_dir)?;
+        self.files.write_input(nonce, input)?;
+        self.files.write_witness(&self.witness, nonce, input).await?;
+        let mut command = Command::new(&self.binary);
+        command
+            .arg(self.files.zkey_path())
+            .arg(self.files.witness_path(nonce))
+            .arg(self.files.proof_path(nonce))
+            .arg(self.files.public_path(nonce));
+        run_step("rapidsnark proving", &mut command).await?;
+        self.files.load_proof(nonce)
+    }
+
+    fn load_proof(&self, nonce: &str) -> Result<CircomCalldata> {
+        self.files.load_proof(nonce)
+    }
+}
+
+/// Proves locally with snarkjs, for machines rapidsnark doesn't run on (e.g. Macs).
+pub struct SnarkjsProver {
+    files: CircuitFiles,
+    witness: Arc<WitnessCalculator>,
+}
+
+#[async_trait]
+impl Prover for SnarkjsProver {
+    fn name(&self) -> &'static str {
+        "snarkjs"
+    }
+
+    async fn prove(&self, nonce: &str, input: &CircuitInput) -> Result<CircomCalldata> {
+        fs::create_dir_all(&self.files.proof_dir)?;
+        self.files.write_input(nonce, input)?;
+        self.files.write_witness(&self.witness, nonce, input).await?;
+        let mut command = Command::new(format!("{}/node_modules/.bin/snarkjs", self.files.zk_email_path));
+        command
+            .env("NODE_OPTIONS", "--max-old-space-size=644000")
+            .arg("groth16")
+            .arg("prove")
+            .arg(self.files.zkey_path())
+            .arg(self.files.witness_path(nonce))
+            .arg(self.files.proof_path(nonce))
+            .arg(self.files.public_path(nonce));
+        run_step("snarkjs proving", &mut command).await?;
+        self.files.load_proof(nonce)
+    }
+
+    fn load_proof(&self, nonce: &str) -> Result<CircomCalldata> {
+        self.files.load_proof(nonce)
+    }
+}
+
+/// Verifies every proof another prover makes, proving again when one doesn't verify or its files are incomplete,
+/// instead of sending it to fail on chain.
+pub struct VerifiedProver {
+    inner: Arc<dyn Prover>,
+    verifier: ProofVerifier,
+}
+
+#[async_trait]
+impl Prover for VerifiedProver {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    async fn prove(&self, nonce: &str, input: &CircuitInput) -> Result<CircomCalldata> {
+        for attempt in 1..=PROOF_ATTEMPTS {
+            let calldata = match self.inner.prove(nonce, input).await {
+                Ok(calldata) => calldata,
+                Err(e) => match e.downcast_ref::<ProofFileError>() {
+                    Some(file_error) if file_error.is_retryable() => {
+                        println!("Proof {}/{} of email {} could not be loaded: {}", attempt, PROOF_ATTEMPTS, nonce, file_error);
+                        continue;
+                    }
+                    _ => return Err(e),
+                },
+            };
+            match self.verifier.verify(&calldata) {
+                Ok(()) => return Ok(calldata),
+                Err(e) => println!("Proof {}/{} of email {} is invalid: {}", attempt, PROOF_ATTEMPTS, nonce, e),
+            }
+        }
+        Err(anyhow!("No valid proof of email {} after {} attempts", nonce, PROOF_ATTEMPTS))
+    }
+
+    fn load_proof(&self, nonce: &str) -> Result<CircomCalldata> {
+        let calldata = self.inner.load_proof(nonce)?;
+        self.verifier.verify(&calldata)?;
+        Ok(calldata)
+    }
+}
+
+/// What an HTTP prover replies with: the snarkjs proof and public signals JSON.
+#[derive(Debug, Deserialize)]
+struct HttpProofResponse {
+    proof: Value,
+    public: Value,
+}
+
+/// Posts the raw email to a remote prover, which replies with the proof once it's done.
+pub struct HttpProver {
+    files: CircuitFiles,
+    url: String,
+    client: Client,
+}
+
+#[async_trait]
+impl Prover for HttpProver {
+    fn name(&self) -> &'static str {
+        "http"
+    }
+
+    async fn prove(&self, nonce: &str, _input: &CircuitInput) -> Result<CircomCalldata> {
+        let raw_email = fs::read_to_string(self.files.eml_path(nonce))?;
+        let response = self
+            .client
+            .post(&self.url)
+            .query(&[("nonce", nonce)])
+            .header("Content-Type", "application/octet-stream")
+            .body(raw_email)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(anyhow!("Prover at {} replied {}: {}", self.url, status, response.text().await?));
+        }
+        let proof: HttpProofResponse = response.json().await?;
+        // Keep the proof on disk like the local provers do, so the chain step can be re-run by hand
+        fs::create_dir_all(&self.files.proof_dir)?;
+        fs::write(self.files.proof_path(nonce), serde_json::to_vec(&proof.proof)?)?;
+        fs::write(self.files.public_path(nonce), serde_json::to_vec(&proof.public)?)?;
+        self.files.load_proof(nonce)
+    }
+
+    fn load_proof(&self, nonce: &str) -> Result<CircomCalldata> {
+        self.files.load_proof(nonce)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_circuit_file_layout() {
+        let files = CircuitFiles {
+            zk_email_path: "/zk-email-verify".to_string(),
+            circuit_name: "wallet".to_string(),
+            eml_dir: "/relayer/received_eml".to_string(),
+            proof_dir: "/relayer/proofs".to_string(),
+        };
+        assert_eq!(files.eml_path("7"), "/relayer/received_eml/wallet_7.eml");
+        assert_eq!(files.input_path("7"), "/relayer/received_eml/input_7.json");
+        assert_eq!(files.wasm_path(), "/zk-email-verify/build/wallet/wallet_js/wallet.wasm");
+        assert_eq!(files.zkey_path(), "/zk-email-verify/build/wallet/wallet.zkey");
+        assert_eq!(files.witness_path("7"), "/zk-email-verify/build/wallet/witness_7.wtns");
+        assert_eq!(files.proof_path("7"), "/relayer/proofs/rapidsnark_proof_7.json");
+        assert_eq!(files.public_path("7"), "/relayer/proofs/rapidsnark_public_7.json");
+    }
+}
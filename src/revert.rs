@@ -0,0 +1,169 @@
+use ethers::abi::{Abi, ParamType, Token};
+use ethers::core::types::Bytes;
+use ethers::providers::JsonRpcError;
+use ethers::utils::id;
+use hex::encode;
+use std::fmt;
+
+/// Why the wallet contract rejected a call, decoded from its revert data.
+/// Reverts we recognize get their own reply, anything else is passed on to the user as the contract worded it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RevertReason {
+    /// The email's nullifier was already used, i.e. this email was already executed
+    NullifierUsed,
+    /// The contract has no DKIM key on file for the email's domain and selector
+    UnknownMailserverKey,
+    /// The sender's wallet holds less of the token than the email asks to move
+    InsufficientBalance,
+    /// The proof or its public signals did not pass the on-chain verifier
+    InvalidProof,
+    /// The token isn't registered in the TokenRegistry
+    UnsupportedToken,
+    /// A `require`/`revert("...")` message we don't have a reply for
+    Message(String),
+    /// A custom error from the wallet ABI we don't have a reply for, with its decoded arguments
+    CustomError { name: String, args: Vec<Token> },
+    /// A `Panic(uint256)` from a failed assert, overflow or similar, with its code
+    Panic(u64),
+    /// Revert data that matches nothing in the ABI
+    Unknown(Bytes),
+}
+
+impl RevertReason {
+    /// Decodes revert data against the standard `Error(string)` and `Panic(uint256)` and the custom errors of `abi`.
+    pub fn decode(revert_data: &[u8], abi: &Abi) -> Self {
+        if let Some(args) = revert_data.strip_prefix(&id("Error(string)")[..]) {
+            if let Some(Token::String(message)) = decode_single(&ParamType::String, args) {
+                return Self::from_message(&message);
+            }
+        }
+        if let Some(args) = revert_data.strip_prefix(&id("Panic(uint256)")[..]) {
+            if let Some(Token::Uint(code)) = decode_single(&ParamType::Uint(256), args) {
+                return Self::Panic(code.low_u64());
+            }
+        }
+        for error in abi.errors() {
+            let types: Vec<ParamType> = error.inputs.iter().map(|param| param.kind.clone()).collect();
+            let signature = format!("{}({})", error.name, types.iter().map(|kind| kind.to_string()).collect::<Vec<_>>().join(","));
+            if let Some(args) = revert_data.strip_prefix(&id(&signature)[..]) {
+                if let Ok(args) = ethers::abi::decode(&types, args) {
+                    return Self::from_custom_error(&error.name, args);
+                }
+            }
+        }
+        Self::Unknown(Bytes::from(revert_data.to_vec()))
+    }
+
+    /// Decodes the revert in a JSON-RPC error, which nodes return either as data or, for `require` messages, only in the error message.
+    pub fn from_error_response(response: &JsonRpcError, abi: &Abi) -> Option<Self> {
+        if let Some(revert_data) = response.as_revert_data() {
+            if !revert_data.is_empty() {
+                return Some(Self::decode(&revert_data, abi));
+            }
+        }
+        response
+            .message
+            .strip_prefix("execution reverted: ")
+            .map(Self::from_message)
+    }
+
+    /// Matches the wording of the wallet contract's `require` messages.
+    fn from_message(message: &str) -> Self {
+        let lowercase = message.to_lowercase();
+        if lowercase.contains("nullifier") || lowercase.contains("already used") {
+            Self::NullifierUsed
+        } else if lowercase.contains("mailserver") || lowercase.contains("dkim") || lowercase.contains("rsa key") {
+            Self::UnknownMailserverKey
+        } else if lowercase.contains("insufficient") || lowercase.contains("exceeds balance") {
+            Self::InsufficientBalance
+        } else if lowercase.contains("invalid proof") || lowercase.contains("verif") {
+            Self::InvalidProof
+        } else if lowercase.contains("token") && (lowercase.contains("not registered") || lowercase.contains("not supported")) {
+            Self::UnsupportedToken
+        } else {
+            Self::Message(message.to_string())
+        }
+    }
+
+    fn from_custom_error(name: &str, args: Vec<Token>) -> Self {
+        match name {
+            "NullifierUsed" | "NullifierAlreadyUsed" | "EmailAlreadyUsed" => Self::NullifierUsed,
+            "MailserverKeyNotFound" | "UnknownMailserverKey" | "InvalidMailserverKey" => Self::UnknownMailserverKey,
+            "InsufficientBalance" => Self::InsufficientBalance,
+            "InvalidProof" | "VerificationFailed" => Self::InvalidProof,
+            "TokenNotRegistered" | "UnsupportedToken" => Self::UnsupportedToken,
+            _ => Self::CustomError {
+                name: name.to_string(),
+                args,
+            },
+        }
+    }
+}
+
+fn decode_single(kind: &ParamType, data: &[u8]) -> Option<Token> {
+    ethers::abi::decode(&[kind.clone()], data).ok()?.into_iter().next()
+}
+
+/// A short description of the revert, as stored with the email and logged.
+impl fmt::Display for RevertReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NullifierUsed => write!(f, "email nullifier already used"),
+            Self::UnknownMailserverKey => write!(f, "mailserver key not found"),
+            Self::InsufficientBalance => write!(f, "insufficient balance"),
+            Self::InvalidProof => write!(f, "invalid proof"),
+            Self::UnsupportedToken => write!(f, "token not registered"),
+            Self::Message(message) => write!(f, "{}", message),
+            Self::CustomError { name, args } => write!(f, "{}({:?})", name, args),
+            Self::Panic(code) => write!(f, "panic code 0x{:02x}", code),
+            Self::Unknown(revert_data) => write!(f, "0x{}", encode(revert_data)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ethers::core::types::U256;
+
+    fn with_selector(signature: &str, args: &[Token]) -> Vec<u8> {
+        let mut revert_data = id(signature).to_vec();
+        revert_data.extend(ethers::abi::encode(args));
+        revert_data
+    }
+
+    #[test]
+    fn test_decode_revert_reason() {
+        let abi: Abi = serde_json::from_str(
+            r#"[
+                {"type": "error", "name": "NullifierUsed", "inputs": [{"name": "nullifier", "type": "uint256"}]},
+                {"type": "error", "name": "Unauthorized", "inputs": [{"name": "salt", "type": "uint256"}]}
+            ]"#,
+        )
+        .unwrap();
+        let message = |message: &str| with_selector("Error(string)", &[Token::String(message.to_string())]);
+
+        assert_eq!(RevertReason::decode(&message("Nullifier already used"), &abi), RevertReason::NullifierUsed);
+        assert_eq!(RevertReason::decode(&message("ERC20: transfer amount exceeds balance"), &abi), RevertReason::InsufficientBalance);
+        assert_eq!(
+            RevertReason::decode(&message("Wallet is paused"), &abi),
+            RevertReason::Message("Wallet is paused".to_string())
+        );
+        assert_eq!(
+            RevertReason::decode(&with_selector("NullifierUsed(uint256)", &[Token::Uint(U256::one())]), &abi),
+            RevertReason::NullifierUsed
+        );
+        assert_eq!(
+            RevertReason::decode(&with_selector("Unauthorized(uint256)", &[Token::Uint(U256::one())]), &abi),
+            RevertReason::CustomError {
+                name: "Unauthorized".to_string(),
+                args: vec![Token::Uint(U256::one())],
+            }
+        );
+        assert_eq!(
+            RevertReason::decode(&with_selector("Panic(uint256)", &[Token::Uint(U256::from(0x11))]), &abi).to_string(),
+            "panic code 0x11"
+        );
+        assert_eq!(RevertReason::decode(&[0xde, 0xad, 0xbe, 0xef], &abi).to_string(), "0xdeadbeef");
+    }
+}
@@ -1,17 +1,10 @@
 use lettre::{
-    message::{
-        header::{Cc, From, Header, HeaderName, InReplyTo, ReplyTo, To},
-        Mailbox, Mailboxes, MessageBuilder,
-    },
-    transport::smtp::{
-        authentication::Credentials, client::SmtpConnection, commands::*, extension::ClientId,
-        SMTP_PORT,
-    },
+    message::Mailbox,
+    transport::smtp::authentication::Credentials,
     Address, Message, SmtpTransport, Transport,
 };
 
-// use mailparse::Mail;
-use crate::{config::{SMTP_DOMAIN_NAME_KEY, SMTP_PORT_KEY}, parse_email::{extract_from, extract_recipient_from_subject}};
+use crate::parse_email::{extract_recipient_from_subject, ParsedEmail};
 use native_tls::{Protocol, TlsConnector};
 use std::error::Error;
 
@@ -56,44 +49,14 @@ impl EmailSenderClient {
         Ok(true)
     }
 
-    /// This function replies to all recipients of the original email (raw_email).
-    /// The subject of the reply email is prefixed with "Re: " followed by the original subject.
-    /// The original subject is extracted from the raw_email parameter.
+    /// This function replies to all recipients of the original email.
+    /// The subject of the reply email is prefixed with "Re: " followed by the original (decoded) subject.
     /// If send_to_recipient, the email recipient mentioned in the subject will be added to the final confirmation
 
-    pub fn reply_all(&self, raw_email: &str, reply_body: &str, send_to_recipient: bool) -> Result<(), Box<dyn Error>> {
-        let mut original_to = vec![];
-        let mut original_cc = vec![];
-        let mut original_from = None;
-        let mut in_reply_to = String::new();
-        let mut original_subject = String::new();
-
-        // TODO: Replace this with Sora's code
-        for line in raw_email.lines() {
-            if line.starts_with("To:") {
-                let parsed: Result<To, _> = To::parse(line);
-                if let Ok(header) = parsed {
-                    original_to.push(header);
-                }
-            } else if line.starts_with("Cc:") {
-                let parsed: Result<Cc, _> = Cc::parse(line);
-                if let Ok(header) = parsed {
-                    original_cc.push(header);
-                }
-            } else if line.starts_with("From:") {
-                let parsed: Result<From, _> = From::parse(line);
-                if let Ok(header) = parsed {
-                    original_from = Some(header);
-                }
-            } else if line.starts_with("Message-ID:") {
-                in_reply_to = line.trim_start_matches("Message-ID:").trim().to_string();
-            } else if line.starts_with("Subject:") {
-                original_subject = line.trim_start_matches("Subject:").trim().to_string();
-            }
-        }
+    pub fn reply_all(&self, original: &ParsedEmail, reply_body: &str, send_to_recipient: bool) -> Result<(), Box<dyn Error>> {
         println!(
-            "Parsed email headers: {:?} {:?} {:?} {:?} {:?}",
-            original_to, original_cc, original_from, in_reply_to, original_subject
+            "Replying to email: {:?} {:?} {:?} {:?} {:?}",
+            original.to, original.cc, original.from, original.message_id, original.subject
         );
         // Create the email sender's Mailbox
         let sender = Mailbox::new(
@@ -103,60 +66,43 @@ impl EmailSenderClient {
 
         let mut email = Message::builder()
             .from(sender.clone())
-            .subject(format!("Re: {}", original_subject))
-            .in_reply_to(in_reply_to);
-        
-        // TODO: The extract_from function is a bit messy, but this is just a backup for error handling in the SMTP client and may not even reply correctly...
-        let mboxes: Mailboxes = match original_from {
-            Some(from) => from.into(),
-            None => {
-                let extracted = extract_from(raw_email)?;
-                println!("Extracted from as a backup: {:?}", extracted);
-                let parsed_mailbox: Result<Mailbox, _> = extracted.parse();
-                match parsed_mailbox {
-                    Ok(mailbox) => Mailboxes::new().with(mailbox),
-                    Err(_) => Mailboxes::new(),
-                }
-            },
-        };
+            .subject(format!("Re: {}", original.subject));
+        if let Some(message_id) = &original.message_id {
+            email = email.in_reply_to(format!("<{}>", message_id));
+        }
 
-        for mbox in mboxes {
-            if mbox.email != sender.email {
-                email = email.to(mbox);
-            }
+        let from_address = original.from.parse::<Address>()?;
+        if from_address != sender.email {
+            email = email.to(Mailbox::new(original.from_name.clone(), from_address));
         }
-        for to in original_to {
-            let mboxes: Mailboxes = to.into();
-            for mbox in mboxes {
-                if mbox.email == self.email_id.parse::<Address>()? {
-                    continue;
-                }
-                email = email.to(mbox);
+        for to in original.to.iter() {
+            let address = to.parse::<Address>()?;
+            if address == sender.email {
+                continue;
             }
-            
-            if send_to_recipient {
-                // Extract and send to any email address from the subject
-                print!("Searching for email in subject... {:?}", original_subject);
-                match extract_recipient_from_subject(original_subject.as_str()) {
-                    Ok(recipient_email) => {
-                        let recipient = Mailbox::new(None, recipient_email.parse::<Address>()?);
-                        email = email.to(recipient);
-                    },
-                    Err(e) => {
-                        println!("Error extracting recipient from subject: {:?}", e);
-                    }
+            email = email.to(Mailbox::new(None, address));
+        }
+
+        if send_to_recipient {
+            // Extract and send to any email address from the subject
+            print!("Searching for email in subject... {:?}", original.subject);
+            match extract_recipient_from_subject(original.subject.as_str()) {
+                Ok(recipient_email) => {
+                    let recipient = Mailbox::new(None, recipient_email.parse::<Address>()?);
+                    email = email.to(recipient);
+                },
+                Err(e) => {
+                    println!("Error extracting recipient from subject: {:?}", e);
                 }
             }
         }
 
-        for cc in original_cc {
-            let mboxes: Mailboxes = cc.into();
-            for mbox in mboxes {
-                if (mbox.email == self.email_id.parse::<Address>()?) {
-                    continue;
-                }
-                email = email.cc(mbox);
+        for cc in original.cc.iter() {
+            let address = cc.parse::<Address>()?;
+            if address == sender.email {
+                continue;
             }
+            email = email.cc(Mailbox::new(None, address));
         }
 
         let message = match email.body(reply_body.as_bytes().to_vec()) {
@@ -1,11 +1,20 @@
 use ethers::core::types::{Address, U256, H160, H256};
-use crate::chain::{query_balance};
-pub const CHAIN: &str = "Ethereum Goerli";
+use crate::amount::TokenAmount;
+use crate::chain_config::{ChainConfig, ChainRegistry};
+use crate::parse_email::MigrateDestination;
+use crate::revert::RevertReason;
 
-pub fn invalid_reply() -> String {
+pub fn invalid_reply(formats: &[&str], token_names: &[String]) -> String {
+    let formats_list = formats
+        .iter()
+        .map(|format| format!("\"{}\"", format))
+        .collect::<Vec<_>>()
+        .join(", ");
     format!(
-        "Subject failed formatting check! Please format your email on https://sendeth.org, or try again with this subject: \"Send _ DAI to __@__.___\". \
-        You can send DAI, USDC, or TEST tokens right now."
+        "Subject failed formatting check! Please format your email on https://sendeth.org, or try again with one of these subjects: {}. \
+        You can send {} right now.",
+        formats_list,
+        token_names.join(", ")
     )
 }
 
@@ -15,76 +24,195 @@ pub fn bad_message_id() -> String {
     )
 }
 
-pub fn reply_with_etherscan(tx_hash: H256) -> String {
-    let etherscan_url = format!("https://goerli.etherscan.io/tx/0x{:x}", tx_hash);
-    let reply = format!(
-        "Transaction sent! View Etherscan confirmation: {}.\n \n\
+pub fn dkim_failure_reply(reason: &str) -> String {
+    format!(
+        "We could not verify the DKIM signature on your email ({}), so we can't prove that it came from your address and have not executed it. \
+        Please send the email again directly from your email provider's web or mobile client, without forwarding it.",
+        reason
+    )
+}
+
+pub fn reply_with_explorer(chain: &ChainConfig, tx_hash: H256, confirmations: usize) -> String {
+    let explorer_url = chain.tx_url(tx_hash);
+    format!(
+        "Transaction confirmed on {} ({} confirmation(s))! View it here: {}.\n \n\
         If either email address is new, we've assigned them an address on-chain, controlled only by that email (your email address is not leaked on-chain).\n \n\
         Under 'ERC20 Tokens Transferred', you'll see transactions in which we give each new wallet 100 tokens, and the last line shows your tokens moving between the two accounts.",
-        etherscan_url
-    );
-    println!("Replying with confirmation...{}", reply);
-    reply
-}
-
-pub async fn pending_reply(address: &str, amount: &str, currency: &str, recipient: &str) -> String {
-    let mut enough_balance = false;
-    let balance_detected_message = match query_balance(
-        false,
-        address.clone(),
-        currency,
-    )
-    .await {
-        Ok(balance) => {
-            enough_balance = balance >= amount.parse().unwrap();
-            let remaining = balance - amount.parse::<f64>().unwrap();
-            
-            let enough_balance_str = 
-                if (currency == "TEST" && remaining == 100.0) {
-                    format!("Created new wallet for you at {}, controlled by your emails. Your email address is not leaked on-chain. It has {} {}, and the transaction will send {} {} to {} and your remaining balance will be {} {}.", address, balance, currency, amount, currency, recipient, remaining, currency)                
-                } else if enough_balance {
+        chain.name, confirmations, explorer_url
+    )
+}
+
+/// What a revert means for the user and what they can do about it.
+pub fn revert_explanation(reason: Option<&RevertReason>) -> String {
+    match reason {
+        Some(RevertReason::NullifierUsed) => {
+            "this email was already executed, and each email can only be used once. To send again, write a new email".to_string()
+        }
+        Some(RevertReason::UnknownMailserverKey) => "the wallet contract doesn't have the DKIM key your email was signed with. \
+            Most likely your email domain is not supported (must be @gmail.com, @hotmail.com, @ethereum.org, or @skiff.com)"
+            .to_string(),
+        Some(RevertReason::InsufficientBalance) => {
+            "your wallet doesn't hold enough of this token. Reply with subject \"Balance\" to check what you have".to_string()
+        }
+        Some(RevertReason::InvalidProof) => "the proof of your email failed verification on chain. \
+            This is on our side, not yours -- please send your email again, and contact us at aayushg@mit.edu if it keeps failing"
+            .to_string(),
+        Some(RevertReason::UnsupportedToken) => "this token isn't registered with the wallet contract on this chain".to_string(),
+        Some(RevertReason::Message(message)) => format!("the wallet contract rejected it with \"{}\"", message),
+        Some(reason) => format!("the wallet contract rejected it ({})", reason),
+        None => "the wallet contract rejected it without giving a reason".to_string(),
+    }
+}
+
+pub fn reverted_reply(chain: &ChainConfig, tx_hash: H256, reason: Option<&RevertReason>) -> String {
+    format!(
+        "Transaction reverted: {}. Nothing was moved, and your wallet balance is unchanged. View it here: {}.",
+        revert_explanation(reason),
+        chain.tx_url(tx_hash)
+    )
+}
+
+/// The reply when a call would revert, so it was never sent.
+pub fn rejected_reply(reason: Option<&RevertReason>) -> String {
+    format!(
+        "Error sending transaction: {}. We did not send it, so nothing was moved.",
+        revert_explanation(reason)
+    )
+}
+
+/// The reply to an email whose nullifier is already used on chain, linking its transaction if we still have it.
+pub fn already_executed_reply(chain: &ChainConfig, tx_hash: Option<H256>) -> String {
+    match tx_hash {
+        Some(tx_hash) => format!(
+            "This email was already executed on {} in transaction {}, and each email can only be executed once. \
+            To send again, write a new email.",
+            chain.name,
+            chain.tx_url(tx_hash)
+        ),
+        None => format!(
+            "This email was already executed on {}, and each email can only be executed once. To send again, write a new email.",
+            chain.name
+        ),
+    }
+}
+
+pub fn dropped_reply(chain: &ChainConfig, tx_hash: H256) -> String {
+    format!(
+        "Your transaction {} was dropped on {} before it was mined, so nothing was moved. Please send your email again.",
+        chain.tx_url(tx_hash),
+        chain.name
+    )
+}
+
+pub fn pending_reply(chain: &ChainConfig, address: &str, balance: Option<&TokenAmount>, amount: &TokenAmount, currency: &str, recipient: &str) -> String {
+    let balance_detected_message = match balance {
+        Some(balance) => {
+            // None when the balance is too low to cover the amount
+            let remaining = balance.checked_sub(amount);
+            match remaining {
+                Some(remaining) if currency == "TEST" && remaining == TokenAmount::from_whole(100, remaining.decimals) => {
+                    format!("Created new wallet for you at {}, controlled by your emails. Your email address is not leaked on-chain. It has {} {}, and the transaction will send {} {} to {} and your remaining balance will be {} {}.", address, balance, currency, amount, currency, recipient, remaining, currency)
+                }
+                Some(remaining) => {
                     format!("Your wallet {} has {} {}. The transaction will send {} {} to {} and your remaining balance will be {} {}.", address, balance, currency, amount, currency, recipient, remaining, currency)
-                } else {
+                }
+                None => {
                     format!("Created new wallet for you at {} -- in order to send this transaction, you must add at least {} {} to send. \
                     The send has been queued and will execute once enough balance is detected, then automatically send {} {} to {}.",
                     address, amount, currency, amount, currency, recipient)
-                };
-            enough_balance_str
+                }
+            }
         },
-        Err(_) => {
-            format!("Failed to detect balance in account.")
-        }
+        None => "Failed to detect balance in account.".to_string(),
     };
-    println!("Balance detected message: {}", balance_detected_message);
-       
-
     format!(
         "{} \
-        We will follow up with {} Etherscan link in about a minute when finished. \n \n\
+        We will follow up with a {} explorer link in about a minute when finished. \n \n\
         You are sending using zk email (https://prove.email) and email wallet (https://emailwallet.org). \
         The relayer will prove on-chain that you sent an email authorizing this transaction. \
         We will automatically deploy a wallets for each new user, controlled only by that new user's email address and domain (we can't steal your assets!). \
         While we're in beta, we transfer you 100 'TEST' tokens to try out free transfers.",
-        balance_detected_message, CHAIN
+        balance_detected_message, chain.name
     )
 }
 
-pub fn recipient_intro_body(sender_email: &str, amount: &str, currency: &str) -> String {
+pub fn replayed_reply(nonce: u64) -> String {
     format!(
-        "You have received a transfer from {} for {} {} on {}. \
-        We automatically created a wallet for you and sent you the money using Email Wallet's ZK technology (https://prove.email). 
-        
-        If you want to transfer these funds or cash out, you just need to send another email, which you can format on https://emailwallet.org.
-        
-        If you don't want this money or weren't expecting a transfer, you can ignore this email, and the money will automatically be returned once a month has passed.",
-        sender_email, amount, currency, CHAIN
+        "This command was not executed: your wallet already executed a newer command, and commands only execute \
+        in the order they were sent (this one was number {}). Nothing was sent. Send your email again to retry it.",
+        nonce
     )
 }
 
-// TODO: Change view > claim for uninitialized accounts
-pub fn recipient_intro_subject(sender_email: &str, amount: &str, currency: &str) -> String {
+pub fn funds_expired_reply(chain: &ChainConfig, address: &str, amount: &str, currency: &str, expiry_secs: u64) -> String {
     format!(
-        "View your transfer from {} for {} {} on {}",
-        sender_email, amount, currency, CHAIN
+        "Your queued send of {} {} expired: your wallet {} on {} did not receive enough {} within {} hours, so nothing was sent. \
+        Add funds to your wallet and send your email again.",
+        amount, currency, address, chain.name, currency, expiry_secs / 3600
     )
-}
\ No newline at end of file
+}
+
+pub fn invalid_amount_reply(amount: &str, currency: &str, decimals: u8) -> String {
+    format!(
+        "Could not send {} {}: {} amounts must be a positive number with at most {} decimal places.",
+        amount, currency, currency, decimals
+    )
+}
+
+pub fn unsupported_chain_reply(chain_name: &str, chains: &ChainRegistry) -> String {
+    format!(
+        "The relayer does not run on {} yet. You can use one of: {}, by ending your subject with \"on <chain>\", or leave it out to use {}.",
+        chain_name,
+        chains.names().join(", "),
+        chains.default_chain().name
+    )
+}
+
+pub fn unsupported_token_reply(token_name: &str, supported: &[String]) -> String {
+    format!(
+        "{} is not supported by the relayer yet. You can use one of: {}.",
+        token_name,
+        supported.join(", ")
+    )
+}
+
+pub fn balance_reply(chain: &ChainConfig, address: &str, balances: &[(String, Option<TokenAmount>)]) -> String {
+    let balance_lines = balances
+        .iter()
+        .map(|(token_name, balance)| match balance {
+            Some(balance) => format!("{} {}", balance, token_name),
+            None => format!("{}: could not be fetched, please try again later", token_name),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "Your wallet {} on {} holds:\n\n{}\n\n\
+        To send tokens, reply with a subject like \"Send _ DAI to __@__.___\".",
+        address, chain.name, balance_lines
+    )
+}
+
+pub fn migrate_reply(chain: &ChainConfig, address: &str, balance: &TokenAmount, token_name: &str, destination: &MigrateDestination) -> String {
+    let destination_str = match destination {
+        MigrateDestination::Email(recipient) => format!("the wallet of {}", recipient),
+        MigrateDestination::Wallet(wallet) => format!("the external address {}", wallet),
+    };
+    format!(
+        "Your wallet {} has {} {}. We are moving all of it to {}. \
+        We will follow up with a {} explorer link once the proof is generated and the transaction is sent.",
+        address, balance, token_name, destination_str, chain.name
+    )
+}
+
+pub fn proof_failure_reply() -> String {
+    "We were unable to generate the proof of your email -- we are likely mid-migration. Nothing was sent. \
+    Check back in tomorrow to try to send again!"
+        .to_string()
+}
+
+pub fn migrate_empty_reply(address: &str, token_name: &str) -> String {
+    format!(
+        "Your wallet {} has no {} to move, so nothing was sent. Reply with the subject \"Balance\" to see what your wallet holds.",
+        address, token_name
+    )
+}
@@ -0,0 +1,135 @@
+use crate::chain::{get_pending_tx_count, get_signer, SignerType};
+use crate::chain_config::{ChainConfig, ChainRegistry};
+use crate::db::Store;
+use crate::gas::{Fees, GasStrategy, MinedTransaction, TxReplacement};
+use anyhow::Result;
+use ethers::core::types::transaction::eip2718::TypedTransaction;
+use ethers::core::types::{BlockId, BlockNumber, H160, H256, U256};
+use ethers::providers::Middleware;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Sends the relayer's transactions on one chain. Nonces are reserved atomically in the store, so they survive restarts,
+/// and broadcasts are serialized, so no two submissions ever race for the same nonce.
+pub struct TxSubmitter {
+    chain: ChainConfig,
+    signer: SignerType,
+    address: H160,
+    strategy: GasStrategy,
+    store: Arc<Store>,
+    // Held from reserving a nonce until its transaction is broadcast
+    submit_lock: Mutex<()>,
+}
+
+impl TxSubmitter {
+    /// Builds the submitter of a chain, reconciling its stored nonce with the relayer's pending transaction count on chain.
+    pub async fn new(chain: &ChainConfig, store: Arc<Store>) -> Result<Self> {
+        let signer = get_signer(chain).await?;
+        let address = signer.address();
+        let submitter = Self {
+            chain: chain.clone(),
+            signer,
+            address,
+            strategy: GasStrategy::from_env(),
+            store,
+            submit_lock: Mutex::new(()),
+        };
+        let pending_count = submitter.pending_count().await?;
+        let nonce = submitter.store.reconcile_nonce(chain.id, &address, pending_count).await?;
+        let unmined = get_pending_tx_count(chain, address).await?;
+        println!("Submitting from {:?} on {} at nonce {} ({} unmined)", address, chain.name, nonce, unmined);
+        Ok(submitter)
+    }
+
+    pub fn chain(&self) -> &ChainConfig {
+        &self.chain
+    }
+
+    pub fn signer(&self) -> &SignerType {
+        &self.signer
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    async fn pending_count(&self) -> Result<U256> {
+        Ok(self
+            .signer
+            .get_transaction_count(self.address, Some(BlockNumber::Pending.into()))
+            .await?)
+    }
+
+    /// Runs a call with eth_call and estimates its gas at the given block, without sending anything.
+    /// Fails with the node's error if the call would revert, and otherwise returns the gas estimate.
+    pub async fn simulate(&self, tx: &TypedTransaction, block_number: u64) -> Result<U256> {
+        let block = Some(BlockId::Number(BlockNumber::Number(block_number.into())));
+        self.signer.call(tx, block).await?;
+        Ok(self.signer.estimate_gas(tx, block).await?)
+    }
+
+    /// Prices a transaction and fills in its gas limit, unless it was already set from a simulation.
+    pub async fn prepare(&self, tx: TypedTransaction) -> Result<(TypedTransaction, Fees)> {
+        self.strategy.prepare(&self.signer, tx).await
+    }
+
+    /// Broadcasts a prepared transaction at the next free nonce, returning it with its nonce pinned so replacements reuse it.
+    /// The nonce is handed back if the broadcast fails.
+    pub async fn send(&self, tx: TypedTransaction) -> Result<(TypedTransaction, H256)> {
+        let _guard = self.submit_lock.lock().await;
+        let pending_count = self.pending_count().await?;
+        let nonce = self.store.reserve_nonce(self.chain.id, &self.address, pending_count).await?;
+        let mut tx = tx;
+        tx.set_nonce(nonce);
+        match self.signer.send_transaction(tx.clone(), None).await {
+            Ok(pending) => Ok((tx, *pending)),
+            Err(e) => {
+                println!("Broadcast at nonce {} failed, releasing it: {}", nonce, e);
+                self.store.release_nonce(self.chain.id, &self.address, nonce).await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Waits until a sent transaction has the given number of confirmations, replacing it at the same nonce while it is stuck.
+    pub async fn wait<F, Fut>(
+        &self,
+        tx: TypedTransaction,
+        fees: Fees,
+        tx_hash: H256,
+        confirmations: usize,
+        on_replacement: F,
+    ) -> Result<MinedTransaction>
+    where
+        F: FnMut(TxReplacement) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        self.strategy
+            .wait_until_mined(&self.signer, tx, fees, tx_hash, confirmations, on_replacement)
+            .await
+    }
+}
+
+/// The submitter of every configured chain, built once and shared by all the emails the relayer executes.
+#[derive(Clone, Default)]
+pub struct Submitters {
+    by_chain: Arc<HashMap<u64, TxSubmitter>>,
+}
+
+impl Submitters {
+    pub async fn new(chains: &ChainRegistry, store: Arc<Store>) -> Result<Self> {
+        let mut by_chain = HashMap::new();
+        for chain in chains.chains() {
+            by_chain.insert(chain.id, TxSubmitter::new(chain, store.clone()).await?);
+        }
+        Ok(Self {
+            by_chain: Arc::new(by_chain),
+        })
+    }
+
+    pub fn get(&self, chain_id: u64) -> Option<&TxSubmitter> {
+        self.by_chain.get(&chain_id)
+    }
+}
@@ -0,0 +1,124 @@
+use crate::chain::CircomCalldata;
+use anyhow::{anyhow, Result};
+use ark_bn254::{Bn254, Fq, Fq2, Fr, G1Affine, G2Affine};
+use ark_ff::{BigInteger256, PrimeField};
+use ark_groth16::{prepare_verifying_key, verify_proof, PreparedVerifyingKey, Proof, VerifyingKey};
+use ethers::core::types::U256;
+use serde_json::Value;
+use std::fs;
+
+/// Checks Groth16 proofs against the circuit's verification key before they are sent to chain,
+/// so a malformed or mismatched proof costs a retry instead of a reverted transaction.
+pub struct ProofVerifier {
+    pvk: PreparedVerifyingKey<Bn254>,
+}
+
+impl ProofVerifier {
+    /// Loads the verification key snarkjs exports with `snarkjs zkey export verificationkey`.
+    pub fn load(path: &str) -> Result<Self> {
+        let json = fs::read_to_string(path).map_err(|e| anyhow!("Could not read verification key at {}: {}", path, e))?;
+        let verifier = Self::from_json(&serde_json::from_str(&json)?)?;
+        println!("Loaded verification key from {}", path);
+        Ok(verifier)
+    }
+
+    pub fn from_json(json: &Value) -> Result<Self> {
+        let gamma_abc_g1 = json["IC"]
+            .as_array()
+            .ok_or(anyhow!("Verification key has no IC"))?
+            .iter()
+            .map(g1_from_json)
+            .collect::<Result<Vec<_>>>()?;
+        let vk = VerifyingKey::<Bn254> {
+            alpha_g1: g1_from_json(&json["vk_alpha_1"])?,
+            beta_g2: g2_from_json(&json["vk_beta_2"])?,
+            gamma_g2: g2_from_json(&json["vk_gamma_2"])?,
+            delta_g2: g2_from_json(&json["vk_delta_2"])?,
+            gamma_abc_g1,
+        };
+        Ok(Self {
+            pvk: prepare_verifying_key(&vk),
+        })
+    }
+
+    /// Verifies the proof and public signals exactly as they will be sent to the contract,
+    /// so a wrong G2 coordinate order fails here too.
+    pub fn verify(&self, calldata: &CircomCalldata) -> Result<()> {
+        // The contract takes G2 coordinates as [c1, c0]
+        let proof = Proof::<Bn254> {
+            a: g1(calldata.pi_a[0], calldata.pi_a[1])?,
+            b: g2(
+                [calldata.pi_b[0][1], calldata.pi_b[0][0]],
+                [calldata.pi_b[1][1], calldata.pi_b[1][0]],
+            )?,
+            c: g1(calldata.pi_c[0], calldata.pi_c[1])?,
+        };
+        let signals = calldata
+            .signals
+            .iter()
+            .map(|signal| Fr::from_repr(BigInteger256(signal.0)).ok_or(anyhow!("Public signal {} is not in the scalar field", signal)))
+            .collect::<Result<Vec<_>>>()?;
+        match verify_proof(&self.pvk, &proof, &signals) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(anyhow!("Proof does not verify against its public signals")),
+            Err(e) => Err(anyhow!("Proof could not be verified: {:?}", e)),
+        }
+    }
+}
+
+fn fq(value: U256) -> Result<Fq> {
+    Fq::from_repr(BigInteger256(value.0)).ok_or(anyhow!("Coordinate {} is not in the base field", value))
+}
+
+fn g1(x: U256, y: U256) -> Result<G1Affine> {
+    let point = G1Affine::new(fq(x)?, fq(y)?, false);
+    if !point.is_on_curve() || !point.is_in_correct_subgroup_assuming_on_curve() {
+        return Err(anyhow!("({}, {}) is not a G1 point", x, y));
+    }
+    Ok(point)
+}
+
+/// Builds a G2 point from its coordinates, each given as [c0, c1] like snarkjs writes them.
+fn g2(x: [U256; 2], y: [U256; 2]) -> Result<G2Affine> {
+    let point = G2Affine::new(Fq2::new(fq(x[0])?, fq(x[1])?), Fq2::new(fq(y[0])?, fq(y[1])?), false);
+    if !point.is_on_curve() || !point.is_in_correct_subgroup_assuming_on_curve() {
+        return Err(anyhow!("({:?}, {:?}) is not a G2 point", x, y));
+    }
+    Ok(point)
+}
+
+fn u256_from_json(value: &Value) -> Result<U256> {
+    let decimal = value.as_str().ok_or(anyhow!("Expected a decimal string, got {}", value))?;
+    Ok(U256::from_dec_str(decimal)?)
+}
+
+fn g1_from_json(point: &Value) -> Result<G1Affine> {
+    g1(u256_from_json(&point[0])?, u256_from_json(&point[1])?)
+}
+
+fn g2_from_json(point: &Value) -> Result<G2Affine> {
+    let coordinate = |i: usize| -> Result<[U256; 2]> { Ok([u256_from_json(&point[i][0])?, u256_from_json(&point[i][1])?]) };
+    g2(coordinate(0)?, coordinate(1)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chain::get_calldata;
+
+    #[test]
+    fn test_verify_fixture_proof() {
+        let verifier = ProofVerifier::load("fixtures/groth16/verification_key.json").unwrap();
+        let calldata = get_calldata("fixtures/groth16", "1").unwrap();
+        verifier.verify(&calldata).unwrap();
+
+        let mut tampered = calldata.clone();
+        tampered.signals[0] = tampered.signals[0] + 1;
+        assert!(verifier.verify(&tampered).is_err());
+
+        // Unswapped G2 coordinates, as rapidsnark writes them, aren't a valid point
+        let mut unswapped = calldata;
+        unswapped.pi_b = unswapped.pi_b.map(|[c1, c0]| [c0, c1]);
+        assert!(verifier.verify(&unswapped).is_err());
+    }
+}
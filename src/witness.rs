@@ -0,0 +1,273 @@
+use anyhow::{anyhow, Result};
+use num_bigint::BigUint;
+use num_traits::Num;
+use serde_json::Value;
+use std::fs;
+use wasmi::core::Trap;
+use wasmi::{Caller, Engine, Extern, Instance, Linker, Module, Store, TypedFunc};
+
+/// Computes circuit witnesses in-process with the wasm circom compiles the circuit to, instead of running
+/// `node generate_witness.js` per proof. The wasm is compiled once, and each witness gets a fresh instance of it.
+pub struct WitnessCalculator {
+    engine: Engine,
+    module: Module,
+}
+
+/// The full witness of a circuit input, with the field it was computed in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Witness {
+    pub prime: BigUint,
+    /// Size of a field element in 32 bit words, as the wasm reports it
+    pub n32: usize,
+    pub values: Vec<BigUint>,
+}
+
+/// What the wasm reported through its runtime imports before trapping.
+#[derive(Default)]
+struct Runtime {
+    error_code: Option<i32>,
+    message: String,
+}
+
+impl WitnessCalculator {
+    pub fn load(wasm_path: &str) -> Result<Self> {
+        let wasm = fs::read(wasm_path).map_err(|e| anyhow!("Could not read circuit wasm at {}: {}", wasm_path, e))?;
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm[..])?;
+        println!("Loaded circuit wasm from {}", wasm_path);
+        Ok(Self { engine, module })
+    }
+
+    /// Computes the witness of a circuit input, given as the JSON object of signal names to (arrays of) decimal strings.
+    pub fn calculate(&self, input: &Value) -> Result<Witness> {
+        let mut store = Store::new(&self.engine, Runtime::default());
+        let instance = self.instantiate(&mut store)?;
+        let wasm = WasmCircuit::new(&store, &instance)?;
+
+        let n32 = wasm.get_field_num_len32.call(&mut store, ())? as usize;
+        wasm.get_raw_prime.call(&mut store, ())?;
+        let prime = wasm.read_field_element(&mut store, n32)?;
+
+        wasm.init.call(&mut store, 1).map_err(|e| runtime_error(&store, e))?;
+        let signals = input.as_object().ok_or(anyhow!("Circuit input must be a JSON object"))?;
+        for (name, value) in signals {
+            let (hash_msb, hash_lsb) = fnv_hash(name);
+            let mut values = vec![];
+            flatten_signal(value, &mut values)?;
+            let expected = wasm.get_input_signal_size.call(&mut store, (hash_msb, hash_lsb))?;
+            if expected < 0 {
+                return Err(anyhow!("Circuit has no input signal {}", name));
+            }
+            if values.len() != expected as usize {
+                return Err(anyhow!("Input signal {} takes {} values, got {}", name, expected, values.len()));
+            }
+            for (i, value) in values.iter().enumerate() {
+                wasm.write_field_element(&mut store, &(value % &prime), n32)?;
+                wasm.set_input_signal
+                    .call(&mut store, (hash_msb, hash_lsb, i as i32))
+                    .map_err(|e| runtime_error(&store, e))?;
+            }
+        }
+
+        let witness_size = wasm.get_witness_size.call(&mut store, ())? as usize;
+        let mut values = Vec::with_capacity(witness_size);
+        for i in 0..witness_size {
+            wasm.get_witness.call(&mut store, i as i32)?;
+            values.push(wasm.read_field_element(&mut store, n32)?);
+        }
+        Ok(Witness { prime, n32, values })
+    }
+
+    fn instantiate(&self, store: &mut Store<Runtime>) -> Result<Instance> {
+        let mut linker = Linker::<Runtime>::new(&self.engine);
+        // Like witness_calculator.js, abort the call the exception was raised in
+        linker.func_wrap("runtime", "exceptionHandler", |mut caller: Caller<'_, Runtime>, code: i32| {
+            caller.data_mut().error_code = Some(code);
+            Err::<(), _>(Trap::new(format!("circuit exception {}", code)))
+        })?;
+        linker.func_wrap("runtime", "printErrorMessage", |mut caller: Caller<'_, Runtime>| {
+            let message = read_message(&mut caller);
+            caller.data_mut().message.push_str(&message);
+        })?;
+        linker.func_wrap("runtime", "writeBufferMessage", |mut caller: Caller<'_, Runtime>| {
+            let message = read_message(&mut caller);
+            if !message.is_empty() {
+                println!("Circuit log: {}", message);
+            }
+        })?;
+        linker.func_wrap("runtime", "showSharedRWMemory", |_: Caller<'_, Runtime>| {})?;
+        Ok(linker.instantiate(&mut *store, &self.module)?.start(&mut *store)?)
+    }
+}
+
+/// The functions a circom wasm exports. Field elements go in and out through its shared memory, one 32 bit word at a time.
+struct WasmCircuit {
+    init: TypedFunc<i32, ()>,
+    get_field_num_len32: TypedFunc<(), i32>,
+    get_raw_prime: TypedFunc<(), ()>,
+    read_shared_rw_memory: TypedFunc<i32, i32>,
+    write_shared_rw_memory: TypedFunc<(i32, i32), ()>,
+    get_input_signal_size: TypedFunc<(i32, i32), i32>,
+    set_input_signal: TypedFunc<(i32, i32, i32), ()>,
+    get_witness_size: TypedFunc<(), i32>,
+    get_witness: TypedFunc<i32, ()>,
+}
+
+impl WasmCircuit {
+    fn new(store: &Store<Runtime>, instance: &Instance) -> Result<Self> {
+        Ok(Self {
+            init: instance.get_typed_func(store, "init")?,
+            get_field_num_len32: instance.get_typed_func(store, "getFieldNumLen32")?,
+            get_raw_prime: instance.get_typed_func(store, "getRawPrime")?,
+            read_shared_rw_memory: instance.get_typed_func(store, "readSharedRWMemory")?,
+            write_shared_rw_memory: instance.get_typed_func(store, "writeSharedRWMemory")?,
+            get_input_signal_size: instance.get_typed_func(store, "getInputSignalSize")?,
+            set_input_signal: instance.get_typed_func(store, "setInputSignal")?,
+            get_witness_size: instance.get_typed_func(store, "getWitnessSize")?,
+            get_witness: instance.get_typed_func(store, "getWitness")?,
+        })
+    }
+
+    fn read_field_element(&self, store: &mut Store<Runtime>, n32: usize) -> Result<BigUint> {
+        let mut words = Vec::with_capacity(n32);
+        for j in 0..n32 {
+            words.push(self.read_shared_rw_memory.call(&mut *store, j as i32)? as u32);
+        }
+        Ok(BigUint::from_slice(&words))
+    }
+
+    fn write_field_element(&self, store: &mut Store<Runtime>, value: &BigUint, n32: usize) -> Result<()> {
+        let mut words = value.to_u32_digits();
+        words.resize(n32, 0);
+        for (j, word) in words.iter().enumerate() {
+            self.write_shared_rw_memory.call(&mut *store, (j as i32, *word as i32))?;
+        }
+        Ok(())
+    }
+}
+
+impl Witness {
+    /// Encodes the witness in the binary .wtns format (version 2) that snarkjs and rapidsnark read.
+    pub fn to_wtns(&self) -> Vec<u8> {
+        let n8 = self.n32 * 4;
+        let mut wtns = Vec::with_capacity(44 + n8 * (self.values.len() + 1));
+        wtns.extend_from_slice(b"wtns");
+        wtns.extend_from_slice(&2u32.to_le_bytes());
+        wtns.extend_from_slice(&2u32.to_le_bytes());
+        // Header section: field element size, prime and witness size
+        wtns.extend_from_slice(&1u32.to_le_bytes());
+        wtns.extend_from_slice(&(4 + n8 as u64 + 4).to_le_bytes());
+        wtns.extend_from_slice(&(n8 as u32).to_le_bytes());
+        push_field_element(&mut wtns, &self.prime, n8);
+        wtns.extend_from_slice(&(self.values.len() as u32).to_le_bytes());
+        // Witness section
+        wtns.extend_from_slice(&2u32.to_le_bytes());
+        wtns.extend_from_slice(&((n8 * self.values.len()) as u64).to_le_bytes());
+        for value in &self.values {
+            push_field_element(&mut wtns, value, n8);
+        }
+        wtns
+    }
+}
+
+fn push_field_element(buffer: &mut Vec<u8>, value: &BigUint, n8: usize) {
+    let mut bytes = value.to_bytes_le();
+    bytes.resize(n8, 0);
+    buffer.extend_from_slice(&bytes);
+}
+
+/// The 64 bit FNV-1a hash of a signal name, split into the high and low words the wasm looks signals up by.
+fn fnv_hash(name: &str) -> (i32, i32) {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in name.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    ((hash >> 32) as u32 as i32, hash as u32 as i32)
+}
+
+/// Flattens a signal's (possibly nested) array of values in the row-major order circom lays them out in.
+fn flatten_signal(value: &Value, values: &mut Vec<BigUint>) -> Result<()> {
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                flatten_signal(item, values)?;
+            }
+        }
+        Value::String(decimal) => values.push(BigUint::from_str_radix(decimal, 10)?),
+        Value::Number(number) => values.push(
+            number
+                .as_u64()
+                .map(BigUint::from)
+                .ok_or(anyhow!("Signal value {} is not a non-negative integer", number))?,
+        ),
+        other => return Err(anyhow!("Unexpected signal value {}", other)),
+    }
+    Ok(())
+}
+
+/// Reads the message the wasm is printing, one getMessageChar call per character until a zero.
+fn read_message(caller: &mut Caller<'_, Runtime>) -> String {
+    let get_message_char = match caller.get_export("getMessageChar").and_then(Extern::into_func) {
+        Some(func) => func,
+        None => return String::new(),
+    };
+    let get_message_char = match get_message_char.typed::<(), i32>(&*caller) {
+        Ok(func) => func,
+        Err(_) => return String::new(),
+    };
+    let mut message = String::new();
+    while let Ok(c) = get_message_char.call(&mut *caller, ()) {
+        if c == 0 {
+            break;
+        }
+        message.push(c as u8 as char);
+    }
+    message
+}
+
+/// Explains a trap with what the circuit reported before it, e.g. the template whose assert failed.
+fn runtime_error(store: &Store<Runtime>, error: Trap) -> anyhow::Error {
+    let runtime = store.data();
+    let reason = match runtime.error_code {
+        Some(1) => "signal not found",
+        Some(2) => "too many signals set",
+        Some(3) => "signal already set",
+        Some(4) => "assert failed",
+        Some(5) => "not enough memory",
+        Some(6) => "input signal array access exceeds the size",
+        _ => "trapped",
+    };
+    anyhow!("Witness generation {}: {} {}", reason, runtime.message.trim(), error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fnv_hash_and_flatten() {
+        // FNV-1a of "a" is 0xaf63dc4c8601ec8c
+        assert_eq!(fnv_hash("a"), (0xaf63dc4cu32 as i32, 0x8601ec8cu32 as i32));
+        let mut values = vec![];
+        flatten_signal(&serde_json::json!([["1", "2"], ["3", 4]]), &mut values).unwrap();
+        assert_eq!(values, (1u32..=4).map(BigUint::from).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_wtns_layout() {
+        let witness = Witness {
+            prime: BigUint::from(0x1_0000_0001u64),
+            n32: 2,
+            values: vec![BigUint::from(1u32), BigUint::from(0x1_0000_0000u64)],
+        };
+        let wtns = witness.to_wtns();
+        assert_eq!(&wtns[0..12], b"wtns\x02\x00\x00\x00\x02\x00\x00\x00");
+        // Header section of 4 + 8 + 4 bytes, with n8 = 8
+        assert_eq!(&wtns[12..28], &[1, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 8, 0, 0, 0]);
+        assert_eq!(&wtns[28..36], &[1, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(&wtns[36..40], &[2, 0, 0, 0]);
+        assert_eq!(&wtns[40..52], &[2, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&wtns[52..], &[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0]);
+    }
+}